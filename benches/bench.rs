@@ -162,6 +162,23 @@ fn skip(b: &mut Bencher) {
     bench_assert_non_match(b, re, &text);
 }
 
+#[bench]
+fn long_scan_anchored(b: &mut Bencher) {
+    // Almost all the time is spent moving between non-accepting states.
+    let re = regex!("^[a-z ]*Q");
+    let text: String = repeat("the quick brown fox jumps over the lazy dog ").take(100).collect();
+    b.bytes = text.len() as u64;
+    bench_assert_non_match(b, re, &text);
+}
+
+#[bench]
+fn long_scan_unanchored(b: &mut Bencher) {
+    let re = regex!("[a-z]{12}[0-9]");
+    let text: String = repeat("the quick brown fox jumps over the lazy dog ").take(100).collect();
+    b.bytes = text.len() as u64;
+    bench_assert_non_match(b, re, &text);
+}
+
 macro_rules! throughput(
     ($name:ident, $regex:expr, $size:expr) => (
         #[bench]
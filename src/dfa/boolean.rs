@@ -0,0 +1,372 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Boolean operations on `Dfa`s.
+//!
+//! These are all implemented using the product construction: the states of the new `Dfa`
+//! correspond to pairs of states, one from each of the original `Dfa`s. Since we want to be able
+//! to keep going after one of the `Dfa`s has failed (for example, when taking a union), a pair
+//! may also contain a missing state.
+//!
+//! The same idea also lets us compare the languages of two `Dfa`s, by searching the product for a
+//! pair of states where one accepts and the other doesn't. Searching the product with a `Dfa`
+//! that recognizes valid UTF-8 lets us find example strings that are valid UTF-8.
+
+use dfa::{Dfa, RetTrait, State};
+use look::Look;
+use nfa::{Accept, Encoding, StateIdx};
+use range_map::{Range, RangeMap};
+use std::collections::{HashMap, VecDeque};
+
+type Pair = (Option<StateIdx>, Option<StateIdx>);
+
+// Returns a vec of length 256 containing the transitions out of a state.
+fn byte_table<Ret>(st: &State<Ret>) -> Vec<Option<StateIdx>> {
+    let mut ret = vec![None; 256];
+    for (ch, &tgt) in st.transitions.keys_values() {
+        ret[ch as usize] = Some(tgt);
+    }
+    ret
+}
+
+// Returns all the bytes, in the order that we prefer to see them in examples: printable ASCII
+// first, then everything that could be part of a non-ASCII char, and the ASCII control characters
+// last.
+fn preferred_bytes() -> Vec<u8> {
+    (0x20..0x7F).chain(0x80..0x100).chain(0x00..0x20).chain(0x7F..0x80)
+        .map(|b: u32| b as u8)
+        .collect()
+}
+
+// Combines the acceptance conditions of two states, by combining separately the conditions for
+// accepting before the end of the input and at the end of the input.
+fn combine_accept<F: Fn(bool, bool) -> bool>(a: Accept, b: Accept, op: F) -> Accept {
+    Accept::from_conditions(op(a.accepts_before_eoi(), b.accepts_before_eoi()),
+                            op(a.accepts_at_eoi(), b.accepts_at_eoi()))
+}
+
+impl<Ret: RetTrait> Dfa<Ret> {
+    // Builds the product of `self` and `other`.
+    //
+    // The function `accept` determines the acceptance condition and return value of a pair of
+    // states (where `None` represents a state that has already failed).
+    fn product<F>(&self, other: &Dfa<Ret>, accept: F) -> Dfa<Ret>
+    where F: Fn(Option<&State<Ret>>, Option<&State<Ret>>) -> (Accept, Option<Ret>) {
+        fn intern(pair: Pair, pairs: &mut Vec<Pair>, map: &mut HashMap<Pair, StateIdx>)
+        -> StateIdx {
+            if let Some(&idx) = map.get(&pair) {
+                return idx;
+            }
+            pairs.push(pair);
+            map.insert(pair, pairs.len() - 1);
+            pairs.len() - 1
+        }
+
+        let mut pairs = Vec::new();
+        let mut map = HashMap::new();
+        let mut ret = Dfa::new();
+
+        for look in Look::all() {
+            let pair = (self.init_state(*look), other.init_state(*look));
+            if pair != (None, None) {
+                ret.init[look.as_usize()] = Some(intern(pair, &mut pairs, &mut map));
+            }
+        }
+
+        // Note that `pairs` grows as we go, so this visits every reachable pair.
+        let mut idx = 0;
+        while idx < pairs.len() {
+            let (a, b) = pairs[idx];
+            let a_trans = a.map(|s| byte_table(&self.states[s]));
+            let b_trans = b.map(|s| byte_table(&other.states[s]));
+
+            let mut trans = Vec::new();
+            for byte in 0..256 {
+                let next = (a_trans.as_ref().and_then(|t| t[byte]),
+                            b_trans.as_ref().and_then(|t| t[byte]));
+                if next != (None, None) {
+                    trans.push((Range::single(byte as u8), intern(next, &mut pairs, &mut map)));
+                }
+            }
+
+            let (acc, acc_ret) = accept(a.map(|s| &self.states[s]), b.map(|s| &other.states[s]));
+            ret.add_state(acc, acc_ret);
+            ret.set_transitions(idx, trans.into_iter().collect::<RangeMap<u8, StateIdx>>());
+            idx += 1;
+        }
+
+        ret.optimize()
+    }
+
+    /// Returns a `Dfa` that accepts the strings that are accepted by both `self` and `other`.
+    ///
+    /// The return values are taken from `self`.
+    pub fn intersect(&self, other: &Dfa<Ret>) -> Dfa<Ret> {
+        self.product(other, |a, b| match (a, b) {
+            (Some(a), Some(b)) => (combine_accept(a.accept, b.accept, |x, y| x && y), a.ret),
+            _ => (Accept::Never, None),
+        })
+    }
+
+    /// Returns a `Dfa` that accepts the strings that are accepted by either `self` or `other`.
+    ///
+    /// If both of them accept, the return value is taken from `self`.
+    pub fn union(&self, other: &Dfa<Ret>) -> Dfa<Ret> {
+        self.product(other, |a, b| {
+            let a_accept = a.map_or(Accept::Never, |a| a.accept);
+            let b_accept = b.map_or(Accept::Never, |b| b.accept);
+            let acc = combine_accept(a_accept, b_accept, |x, y| x || y);
+            let ret = if a_accept != Accept::Never {
+                a.and_then(|a| a.ret)
+            } else {
+                b.and_then(|b| b.ret)
+            };
+            (acc, ret)
+        })
+    }
+
+    /// Returns a `Dfa` that accepts the strings that are accepted by `self` but not by `other`.
+    ///
+    /// The return values are taken from `self`.
+    pub fn difference(&self, other: &Dfa<Ret>) -> Dfa<Ret> {
+        self.product(other, |a, b| {
+            let b_accept = b.map_or(Accept::Never, |b| b.accept);
+            match a {
+                Some(a) => (combine_accept(a.accept, b_accept, |x, y| x && !y), a.ret),
+                None => (Accept::Never, None),
+            }
+        })
+    }
+
+    /// Returns a `Dfa` that accepts all the valid UTF-8 strings that `self` doesn't accept.
+    ///
+    /// The new `Dfa` has an initial state for every kind of look-behind, and every accepting state
+    /// returns `ret`.
+    pub fn complement(&self, ret: Ret) -> Dfa<Ret> {
+        self.complement_with(Encoding::Utf8, ret)
+    }
+
+    /// Like `complement`, but for strings in the given encoding. In Latin-1, every string of
+    /// bytes is valid.
+    pub fn complement_with(&self, encoding: Encoding, ret: Ret) -> Dfa<Ret> {
+        let all = match encoding {
+            Encoding::Utf8 => Dfa::valid_utf8(Some(ret)),
+            Encoding::Latin1 => Dfa::any_bytes(Some(ret)),
+        };
+        all.difference(self)
+    }
+
+    // Searches for a shortest string that takes `self` to a state `a` and `other` to a state `b`
+    // such that `pred(a, b)` is true, starting from their `Look::Boundary` initial states. A
+    // missing state is represented by `Accept::Never`.
+    //
+    // Among the shortest strings, we prefer ones made of printable characters.
+    fn find_in_product<F>(&self, other: &Dfa<Ret>, pred: F) -> Option<Vec<u8>>
+    where F: Fn(Accept, Accept) -> bool {
+        let accept = |dfa: &Dfa<Ret>, s: Option<StateIdx>| {
+            s.map_or(Accept::Never, |s| dfa.states[s].accept)
+        };
+
+        // For each pair that we have visited, the pair we came from and the byte that took us
+        // here.
+        let mut parent: HashMap<Pair, Option<(Pair, u8)>> = HashMap::new();
+        let mut queue = VecDeque::new();
+        let bytes = preferred_bytes();
+        let start = (self.init_at_start(), other.init_at_start());
+        parent.insert(start, None);
+        queue.push_back(start);
+
+        while let Some(pair) = queue.pop_front() {
+            let (a, b) = pair;
+            if pred(accept(self, a), accept(other, b)) {
+                let mut ret = Vec::new();
+                let mut cur = pair;
+                while let Some((prev, byte)) = parent[&cur] {
+                    ret.push(byte);
+                    cur = prev;
+                }
+                ret.reverse();
+                return Some(ret);
+            }
+
+            let a_trans = a.map(|s| byte_table(&self.states[s]));
+            let b_trans = b.map(|s| byte_table(&other.states[s]));
+            for &byte in &bytes {
+                let next = (a_trans.as_ref().and_then(|t| t[byte as usize]),
+                            b_trans.as_ref().and_then(|t| t[byte as usize]));
+                if next != (None, None) && !parent.contains_key(&next) {
+                    parent.insert(next, Some((pair, byte)));
+                    queue.push_back(next);
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks whether every string in the language of `self` is also in the language of `other`.
+    ///
+    /// Here, the language of a `Dfa` is the set of strings that it accepts at the end of the input,
+    /// when starting from its `Look::Boundary` initial state. If the answer is no, returns a
+    /// shortest string that `self` accepts but `other` doesn't.
+    pub fn is_subset_of(&self, other: &Dfa<Ret>) -> Result<(), Vec<u8>> {
+        match self.find_in_product(other, |a, b| a.accepts_at_eoi() && !b.accepts_at_eoi()) {
+            Some(s) => Err(s),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks whether `self` and `other` have the same language (in the sense of `is_subset_of`).
+    ///
+    /// If the answer is no, returns a shortest string that is accepted by one of them but not by
+    /// the other.
+    pub fn is_equivalent(&self, other: &Dfa<Ret>) -> Result<(), Vec<u8>> {
+        match self.find_in_product(other, |a, b| a.accepts_at_eoi() != b.accepts_at_eoi()) {
+            Some(s) => Err(s),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a shortest string that `self` accepts at the end of the input (starting from its
+    /// `Look::Boundary` initial state), or `None` if there are no such strings.
+    ///
+    /// If possible, the returned string is valid UTF-8. Among the shortest valid strings, we
+    /// prefer ones made of printable characters.
+    pub fn shortest_match_example(&self) -> Option<Vec<u8>> {
+        // The valid UTF-8 `Dfa` accepts (unconditionally) exactly at the char boundaries.
+        let utf8 = Dfa::valid_utf8(None);
+        self.find_in_product(&utf8, |a, b| a.accepts_at_eoi() && b == Accept::Always)
+            .or_else(|| self.find_in_product(&Dfa::new(), |a, _| a.accepts_at_eoi()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa::{Dfa, RetTrait};
+    use look::Look;
+    use nfa::{Accept, Encoding};
+    use nfa::tests::re_nfa;
+    use range_map::Range;
+    use std::usize;
+
+    // Runs the dfa on the input (starting at the beginning), and returns its acceptance condition
+    // at the end.
+    fn run<Ret: RetTrait, I: AsRef<[u8]>>(dfa: &Dfa<Ret>, input: I) -> Accept {
+        let mut state = dfa.init_at_start();
+        for &b in input.as_ref() {
+            state = state.and_then(|s| {
+                dfa.transitions(s).ranges_values()
+                    .find(|x| x.0.start <= b && b <= x.0.end)
+                    .map(|x| x.1)
+            });
+        }
+        state.map_or(Accept::Never, |s| *dfa.accept(s))
+    }
+
+    fn whole(re: &str) -> Dfa<(Look, u8)> {
+        re_nfa(&format!("^(?:{})", re))
+            .byte_me(usize::MAX).unwrap()
+            .anchor(usize::MAX).unwrap()
+            .determinize(usize::MAX).unwrap()
+            .optimize()
+    }
+
+    fn lang(re: &str) -> Dfa<()> {
+        re_nfa(re).byte_me(usize::MAX).unwrap().whole_match_dfa(usize::MAX).unwrap()
+    }
+
+    #[test]
+    fn intersect() {
+        let dfa = whole("[a-c]+").intersect(&whole("[b-d]+"));
+        assert_eq!(run(&dfa, "bcb"), Accept::Always);
+        assert_eq!(run(&dfa, "abc"), Accept::Never);
+        assert_eq!(run(&dfa, "d"), Accept::Never);
+
+        let dfa = whole("a+$").intersect(&whole("a*"));
+        assert_eq!(run(&dfa, "aa"), Accept::AtEoi);
+    }
+
+    #[test]
+    fn union() {
+        let dfa = whole("abc").union(&whole("xyz$"));
+        assert_eq!(run(&dfa, "abc"), Accept::Always);
+        assert_eq!(run(&dfa, "xyz"), Accept::AtEoi);
+        assert_eq!(run(&dfa, "ab"), Accept::Never);
+    }
+
+    #[test]
+    fn difference() {
+        let dfa = whole("[a-z]+").difference(&whole("foo"));
+        assert_eq!(run(&dfa, "fo"), Accept::Always);
+        assert_eq!(run(&dfa, "foo"), Accept::Never);
+        assert_eq!(run(&dfa, "fooo"), Accept::Always);
+
+        // Accepts "ab" only if there is something after it.
+        let dfa = whole("ab").difference(&whole("ab$"));
+        assert_eq!(run(&dfa, "ab"), Accept::NotAtEoi);
+    }
+
+    #[test]
+    fn complement() {
+        let dfa = whole("a+").complement((Look::Full, 0));
+        assert_eq!(run(&dfa, ""), Accept::Always);
+        assert_eq!(run(&dfa, "aa"), Accept::Never);
+        assert_eq!(run(&dfa, "aé"), Accept::Always);
+        assert_eq!(run(&dfa.complement((Look::Full, 0)), "aa"), Accept::Always);
+
+        // Invalid UTF-8 isn't accepted.
+        assert_eq!(run(&dfa, b"a\xC3"), Accept::Never);
+        assert_eq!(run(&dfa, b"\xED\xA0\x80"), Accept::Never);
+        let dfa = Dfa::<(Look, u8)>::new().complement((Look::Full, 0));
+        assert_eq!(run(&dfa, "\u{10FFFF}"), Accept::Always);
+
+        // In Latin-1, any bytes will do.
+        let dfa = whole("a+").complement_with(Encoding::Latin1, (Look::Full, 0));
+        assert_eq!(run(&dfa, b"a\xC3"), Accept::Always);
+        assert_eq!(run(&dfa, "aa"), Accept::Never);
+    }
+
+    #[test]
+    fn subset() {
+        assert_eq!(lang("ab").is_subset_of(&lang("a[a-z]")), Ok(()));
+        assert_eq!(lang("a[a-z]").is_subset_of(&lang("ab")), Err(b"aa".to_vec()));
+        assert_eq!(lang("a+").is_subset_of(&lang("a|aaa")), Err(b"aa".to_vec()));
+        assert_eq!(lang("a$").is_subset_of(&lang("a")), Ok(()));
+        assert_eq!(Dfa::new().is_subset_of(&lang("a")), Ok(()));
+    }
+
+    #[test]
+    fn equivalent() {
+        assert_eq!(lang("a|ab").is_equivalent(&lang("ab?")), Ok(()));
+        assert_eq!(lang("(ab)*").is_equivalent(&lang("(ab)*|a(ba)*b")), Ok(()));
+        assert_eq!(lang("a*").is_equivalent(&lang("a+")), Err(vec![]));
+        assert_eq!(lang("é").is_equivalent(&lang("e")), Err(b"e".to_vec()));
+    }
+
+    #[test]
+    fn shortest_match_example() {
+        assert_eq!(lang("a|b+c").shortest_match_example(), Some(b"a".to_vec()));
+        assert_eq!(lang("[\\x00-\\x7F]{2}").shortest_match_example(), Some(b"  ".to_vec()));
+        let e_acute = "é".as_bytes().to_vec();
+        assert_eq!(lang("é|[\\x01-\\x02]x").shortest_match_example(), Some(e_acute.clone()));
+        assert_eq!(lang(r"\btest\B\b").shortest_match_example(), None);
+
+        // Prefer valid UTF-8, even if it's longer.
+        let mut dfa = Dfa::<()>::new();
+        let s0 = dfa.add_state(Accept::Never, None);
+        let s1 = dfa.add_state(Accept::AtEoi, Some(()));
+        let s2 = dfa.add_state(Accept::AtEoi, Some(()));
+        dfa.set_transitions(s0, vec![(Range::single(0xC3), s1)].into_iter().collect());
+        dfa.set_transitions(s1, vec![(Range::single(0xA9), s2)].into_iter().collect());
+        dfa.init[Look::Boundary.as_usize()] = Some(s0);
+        assert_eq!(dfa.shortest_match_example(), Some(e_acute));
+
+        // But if there's nothing valid, take what we can get.
+        dfa.states[s2].accept = Accept::Never;
+        assert_eq!(dfa.shortest_match_example(), Some(vec![0xC3]));
+    }
+}
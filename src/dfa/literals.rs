@@ -0,0 +1,203 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building a `Dfa` directly from a list of literal strings.
+//!
+//! For large lists of keywords, going through a regex string (and then an `Nfa`) is slow and uses
+//! a lot of memory. Instead, we sort the literals and add them one at a time, as in the
+//! incremental construction of Daciuk et al. ("Incremental construction of minimal acyclic
+//! finite-state automata", 2000). Whenever we add a literal, the states belonging to the previous
+//! literal that aren't shared with the new one will never change again, so we can write them
+//! straight into the `Dfa`.
+//!
+//! Daciuk's algorithm also merges each finished state with any equivalent state that was already
+//! finished. We skip that step, because every literal returns its own index: two different
+//! states always lead to different sets of return values, so they are never equivalent, and the
+//! trie that we build is already minimal.
+
+use dfa::Dfa;
+use look::Look;
+use nfa::{Accept, StateIdx};
+use range_map::Range;
+use std::cmp::min;
+
+/// How to choose between several literals that match at the same position.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MatchKind {
+    /// Prefer the literal that came first in the list, like the regex `lit1|lit2|...` does.
+    LeftmostFirst,
+    /// Prefer the longest literal.
+    LeftmostLongest,
+}
+
+struct Builder {
+    dfa: Dfa<usize>,
+    // The states that we visit when reading the most recently added literal, together with their
+    // transitions (sorted by byte). Since these states aren't finished, their transitions aren't
+    // in `dfa` yet.
+    path: Vec<(StateIdx, Vec<(u8, StateIdx)>)>,
+    prev: Vec<u8>,
+}
+
+impl Builder {
+    fn new() -> Builder {
+        let mut dfa = Dfa::new();
+        let init = dfa.add_state(Accept::Never, None);
+        dfa.init[Look::Boundary.as_usize()] = Some(init);
+        Builder {
+            dfa: dfa,
+            path: vec![(init, Vec::new())],
+            prev: Vec::new(),
+        }
+    }
+
+    // Adds a literal, which must come after all the previous ones in lexicographic order.
+    fn add(&mut self, lit: &[u8], ret: usize) {
+        let common = lit.iter().zip(self.prev.iter()).take_while(|&(a, b)| a == b).count();
+        // The first `common + 1` states on the path are shared with the new literal.
+        self.finish_after(common + 1);
+
+        for &b in &lit[common..] {
+            let new_idx = self.dfa.add_state(Accept::Never, None);
+            self.path.last_mut().unwrap().1.push((b, new_idx));
+            self.path.push((new_idx, Vec::new()));
+        }
+        let last = self.path.last().unwrap().0;
+        self.dfa.states[last].accept = Accept::Always;
+        self.dfa.states[last].ret = Some(ret);
+        self.prev.clear();
+        self.prev.extend_from_slice(lit);
+    }
+
+    // Finishes all the states on the path except for the first `len` of them.
+    fn finish_after(&mut self, len: usize) {
+        while self.path.len() > len {
+            let (idx, transitions) = self.path.pop().unwrap();
+            let transitions = transitions.into_iter()
+                .map(|(b, tgt)| (Range::single(b), tgt))
+                .collect();
+            self.dfa.set_transitions(idx, transitions);
+        }
+    }
+
+    fn into_dfa(mut self) -> Dfa<usize> {
+        self.finish_after(0);
+        self.dfa
+    }
+}
+
+impl Dfa<usize> {
+    /// Builds a minimal `Dfa` that matches any of the given literals.
+    ///
+    /// The `Dfa` is anchored: it only matches literals that start where it starts. When it
+    /// accepts, it returns the index (in `literals`) of the literal that matched; if the same
+    /// literal appears more than once, the first index is returned. The literals don't need to be
+    /// valid UTF-8.
+    ///
+    /// Like any other `Dfa`, this one reports the longest match that it finds. If `kind` is
+    /// `MatchKind::LeftmostFirst`, we drop every literal that has a prefix coming earlier in the
+    /// list (since that literal could never win), so that the longest match is the one that
+    /// comes first in the list.
+    pub fn from_literals<I>(literals: I, kind: MatchKind) -> Dfa<usize>
+    where I: IntoIterator, I::Item: AsRef<[u8]> {
+        let mut lits: Vec<(Vec<u8>, usize)> = literals.into_iter()
+            .enumerate()
+            .map(|(i, lit)| (lit.as_ref().to_vec(), i))
+            .collect();
+        lits.sort();
+
+        let mut builder = Builder::new();
+        // The literals that are prefixes of the current one, together with the smallest index of
+        // any of them.
+        let mut prefixes: Vec<(&[u8], usize)> = Vec::new();
+        for (i, &(ref lit, idx)) in lits.iter().enumerate() {
+            // Since we sorted by index within each literal, the first copy has the smallest index.
+            if i > 0 && lits[i - 1].0 == *lit {
+                continue;
+            }
+
+            while prefixes.last().map_or(false, |p| !lit.starts_with(p.0)) {
+                prefixes.pop();
+            }
+            let min_idx = prefixes.last().map_or(idx, |p| p.1);
+            if kind == MatchKind::LeftmostLongest || min_idx >= idx {
+                builder.add(lit, idx);
+            }
+            prefixes.push((&lit[..], min(min_idx, idx)));
+        }
+        builder.into_dfa()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa::{Dfa, MatchKind};
+    use runner::Engine;
+    use runner::anchored::AnchoredEngine;
+    use runner::program::Program;
+
+    fn find(lits: &[&str], kind: MatchKind, text: &str) -> Option<(usize, usize)> {
+        let dfa = Dfa::from_literals(lits, kind);
+        AnchoredEngine::new(dfa.compile()).find(text.as_bytes()).map(|(_, end, idx)| (end, idx))
+    }
+
+    #[test]
+    fn leftmost_first() {
+        let first = MatchKind::LeftmostFirst;
+        assert_eq!(find(&["a", "ab"], first, "abc"), Some((1, 0)));
+        assert_eq!(find(&["ab", "a"], first, "abc"), Some((2, 0)));
+        assert_eq!(find(&["ab", "a"], first, "ac"), Some((1, 1)));
+        assert_eq!(find(&["abc", "a", "ab"], first, "abc"), Some((3, 0)));
+        assert_eq!(find(&["abc", "a", "ab"], first, "abx"), Some((1, 1)));
+        assert_eq!(find(&["x", "y"], first, "abc"), None);
+    }
+
+    #[test]
+    fn leftmost_longest() {
+        let longest = MatchKind::LeftmostLongest;
+        assert_eq!(find(&["a", "ab"], longest, "abc"), Some((2, 1)));
+        assert_eq!(find(&["a", "abc", "ab"], longest, "abx"), Some((2, 2)));
+        assert_eq!(find(&["", "b"], longest, "abc"), Some((0, 0)));
+    }
+
+    #[test]
+    fn duplicates() {
+        assert_eq!(find(&["b", "a", "a"], MatchKind::LeftmostFirst, "a"), Some((1, 1)));
+        assert_eq!(find(&["b", "a", "a"], MatchKind::LeftmostLongest, "a"), Some((1, 1)));
+    }
+
+    #[test]
+    fn minimal() {
+        let longest = MatchKind::LeftmostLongest;
+        assert_eq!(Dfa::from_literals(&["ab", "cb", "ab"], longest).num_states(), 5);
+        assert_eq!(Dfa::from_literals(&["ab", "ac"], longest).num_states(), 4);
+        assert_eq!(Dfa::from_literals(&["abc", "ab"], MatchKind::LeftmostFirst).num_states(), 4);
+        assert_eq!(Dfa::from_literals(&["ab", "abc"], MatchKind::LeftmostFirst).num_states(), 3);
+        assert_eq!(Dfa::from_literals(&[] as &[&str], longest).num_states(), 1);
+
+        // Minimizing shouldn't do anything.
+        let dfa = Dfa::from_literals(&["foo", "bar", "baz", "fob", "ba"], longest);
+        assert_eq!(dfa.clone().optimize().num_states(), dfa.num_states());
+    }
+
+    #[test]
+    fn prefixes() {
+        let dfa = Dfa::from_literals(&["foo", "bar"], MatchKind::LeftmostFirst);
+        let prefs: Vec<Vec<u8>> = dfa.prefix_strings().into_iter().map(|p| p.0).collect();
+        assert!(prefs.contains(&b"foo".to_vec()));
+        assert!(prefs.contains(&b"bar".to_vec()));
+    }
+
+    #[test]
+    fn non_utf8() {
+        let lits: &[&[u8]] = &[b"\xFF\xFE", b"\xFF"];
+        let dfa = Dfa::from_literals(lits, MatchKind::LeftmostLongest);
+        let prog = dfa.compile();
+        assert_eq!(prog.find_from(b"\xFF\xFE\xFD", 0, 0), Ok((2, 0)));
+    }
+}
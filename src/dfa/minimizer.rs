@@ -6,26 +6,27 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use dfa::{Dfa, RetTrait};
+use dfa::{Dfa, RetTrait, TokTrait};
 use nfa::{Accept, StateIdx, StateSet};
 use range_map::{RangeMultiMap, RangeSet};
 use refinery::Partition;
 use std::collections::{HashSet, HashMap};
 
-pub struct Minimizer {
+pub struct Minimizer<Tok> {
     partition: Partition,
     distinguishers: HashSet<usize>,
     // The reversed transitions of the dfa.
-    rev: Vec<RangeMultiMap<u8, StateIdx>>,
+    rev: Vec<RangeMultiMap<Tok, StateIdx>>,
 }
 
-impl Minimizer {
+impl<Tok: TokTrait> Minimizer<Tok> {
     // Partition the states according to
     // - when they accept,
     // - what they return if they do sometimes accept, and
-    // - what set of bytes do we expect to see next.
-    fn initial_partition<Ret: RetTrait>(dfa: &Dfa<Ret>) -> Vec<Vec<StateIdx>> {
-        let mut part: HashMap<(Accept, Option<&Ret>, RangeSet<u8>), Vec<StateIdx>> = HashMap::new();
+    // - what set of tokens do we expect to see next.
+    fn initial_partition<Ret: RetTrait>(dfa: &Dfa<Ret, Tok>) -> Vec<Vec<StateIdx>> {
+        let mut part: HashMap<(Accept, Option<&Ret>, RangeSet<Tok>), Vec<StateIdx>> =
+            HashMap::new();
         for (idx, st) in dfa.states.iter().enumerate() {
             let chars = st.transitions.to_range_set();
             part.entry((st.accept, dfa.ret(idx), chars)).or_insert_with(Vec::new).push(idx);
@@ -87,7 +88,7 @@ impl Minimizer {
         }
     }
 
-    pub fn minimize<Ret: RetTrait>(dfa: &Dfa<Ret>) -> Dfa<Ret> {
+    pub fn minimize<Ret: RetTrait>(dfa: &Dfa<Ret, Tok>) -> Dfa<Ret, Tok> {
         let mut min = Minimizer::new(dfa);
 
         min.compute_partition();
@@ -114,7 +115,7 @@ impl Minimizer {
         ret
     }
 
-    fn new<Ret: RetTrait>(dfa: &Dfa<Ret>) -> Minimizer {
+    fn new<Ret: RetTrait>(dfa: &Dfa<Ret, Tok>) -> Minimizer<Tok> {
         let init = Minimizer::initial_partition(dfa);
         let part = Partition::new(init.into_iter().map(|set| set.into_iter()), dfa.num_states());
 
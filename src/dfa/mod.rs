@@ -9,33 +9,46 @@
 mod trie;
 mod prefix_searcher;
 mod minimizer;
+mod boolean;
+mod literals;
+mod strings;
+mod to_regex;
 
 use dfa::minimizer::Minimizer;
 use dfa::prefix_searcher::PrefixSearcher;
 use graph::Graph;
 use look::Look;
 use itertools::Itertools;
-use nfa::{Accept, StateIdx};
-use range_map::{RangeMap, RangeMultiMap};
+use nfa::{Accept, Encoding, Nfa, NfaBuilder, NoLooks, StateIdx};
+use num_traits::{NumCast, PrimInt};
+use range_map::{Range, RangeMap, RangeMultiMap};
 use refinery::Partition;
-use runner::program::TableInsts;
+use runner::compact::{CompactIdx, CompactInsts};
+use runner::program::{Accel, DEAD_STATE, TableInsts, TableStateIdx};
 use std;
+use std::cmp::max;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::{Debug, Formatter};
 use std::hash::Hash;
 use std::mem;
-use std::u32;
+use utf8_ranges::Utf8Sequences;
 
+pub use dfa::literals::MatchKind;
 pub use dfa::prefix_searcher::PrefixPart;
+#[cfg(feature = "quickcheck")]
+pub use dfa::strings::MatchGen;
+pub use dfa::strings::StringIter;
+pub use runner::tokens::{TokenInsts, TokenSearcher};
 
 #[derive(Clone, PartialEq, Debug)]
-pub struct State<Ret> {
-    pub transitions: RangeMap<u8, StateIdx>,
+pub struct State<Ret, Tok = u8> {
+    pub transitions: RangeMap<Tok, StateIdx>,
     pub accept: Accept,
     pub ret: Option<Ret>,
 }
 
-impl<Ret> State<Ret> {
-    pub fn new(accept: Accept, ret: Option<Ret>) -> State<Ret> {
+impl<Ret, Tok: TokTrait> State<Ret, Tok> {
+    pub fn new(accept: Accept, ret: Option<Ret>) -> State<Ret, Tok> {
         State {
             transitions: RangeMap::new(),
             accept: accept,
@@ -47,9 +60,14 @@ impl<Ret> State<Ret> {
 pub trait RetTrait: Clone + Copy + Debug + Eq + Hash {}
 impl<T: Clone + Copy + Debug + Eq + Hash> RetTrait for T {}
 
+/// The tokens that a `Dfa` consumes. These are usually bytes, but any integer type will do; for
+/// example, a `Dfa<Ret, u16>` could run on UTF-16 code units.
+pub trait TokTrait: Debug + Hash + PrimInt {}
+impl<T: Debug + Hash + PrimInt> TokTrait for T {}
+
 #[derive(Clone, PartialEq)]
-pub struct Dfa<Ret: 'static> {
-    pub states: Vec<State<Ret>>,
+pub struct Dfa<Ret: 'static, Tok = u8> {
+    pub states: Vec<State<Ret, Tok>>,
 
     /// This is a vector of length `Look::num()` containing all possible starting positions.
     ///
@@ -63,9 +81,9 @@ pub struct Dfa<Ret: 'static> {
     pub init: Vec<Option<StateIdx>>,
 }
 
-impl<Ret: RetTrait> Dfa<Ret> {
+impl<Ret: RetTrait, Tok: TokTrait> Dfa<Ret, Tok> {
     /// Returns a `Dfa` with no states.
-    pub fn new() -> Dfa<Ret> {
+    pub fn new() -> Dfa<Ret, Tok> {
         Dfa {
             states: Vec::new(),
             init: vec![None; Look::num()],
@@ -82,7 +100,7 @@ impl<Ret: RetTrait> Dfa<Ret> {
         self.states.len() - 1
     }
 
-    pub fn set_transitions(&mut self, from: StateIdx, transitions: RangeMap<u8, StateIdx>) {
+    pub fn set_transitions(&mut self, from: StateIdx, transitions: RangeMap<Tok, StateIdx>) {
         self.states[from].transitions = transitions;
     }
 
@@ -104,7 +122,7 @@ impl<Ret: RetTrait> Dfa<Ret> {
     }
 
     /// Get transitions from a given state.
-    pub fn transitions(&self, state: StateIdx) -> &RangeMap<u8, StateIdx> {
+    pub fn transitions(&self, state: StateIdx) -> &RangeMap<Tok, StateIdx> {
         &self.states[state].transitions
     }
 
@@ -119,8 +137,8 @@ impl<Ret: RetTrait> Dfa<Ret> {
     }
 
     /// Changes the return value.
-    pub fn map_ret<T: RetTrait, F: FnMut(Ret) -> T>(self, mut f: F) -> Dfa<T> {
-        let mut ret: Dfa<T> = Dfa::new();
+    pub fn map_ret<T: RetTrait, F: FnMut(Ret) -> T>(self, mut f: F) -> Dfa<T, Tok> {
+        let mut ret: Dfa<T, Tok> = Dfa::new();
         ret.init = self.init;
 
         for st in self.states {
@@ -137,12 +155,12 @@ impl<Ret: RetTrait> Dfa<Ret> {
     /// Returns an equivalent DFA with a minimal number of states.
     ///
     /// Uses Hopcroft's algorithm.
-    fn minimize(&self) -> Dfa<Ret> {
+    fn minimize(&self) -> Dfa<Ret, Tok> {
         Minimizer::minimize(self)
     }
 
     /// Returns the transitions of this automaton, reversed.
-    fn reversed_transitions(&self) -> Vec<RangeMultiMap<u8, StateIdx>> {
+    fn reversed_transitions(&self) -> Vec<RangeMultiMap<Tok, StateIdx>> {
         let mut ret = vec![RangeMultiMap::new(); self.states.len()];
 
         for (source, st) in self.states.iter().enumerate() {
@@ -154,95 +172,272 @@ impl<Ret: RetTrait> Dfa<Ret> {
         ret
     }
 
-    /// Returns a set of strings that match the beginning of this `Dfa`.
+    // Returns the values that the compiled program should return in each state, first for accepting
+    // anywhere and then for accepting at the end of the input.
+    fn accept_tables(&self) -> (Vec<Option<Ret>>, Vec<Option<Ret>>) {
+        let accept: Vec<Option<Ret>> = self.states.iter()
+            .map(|st| if st.accept.accepts_before_eoi() { st.ret } else { None })
+            .collect();
+        let accept_at_eoi: Vec<Option<Ret>> = self.states.iter()
+            .map(|st| if st.accept.accepts_at_eoi() { st.ret } else { None })
+            .collect();
+        (accept, accept_at_eoi)
+    }
+
+    // Finds the tokens that are treated equivalently by this Dfa.
+    //
+    // First, we split the tokens into intervals so that every interval is either contained in or
+    // disjoint from every range of every transition. Then we group the intervals into classes.
+    // Returns the first token of each interval, the class of each interval, and the number of
+    // classes.
+    fn token_equivalence_classes(&self) -> (Vec<Tok>, Vec<u32>, usize) {
+        let mut starts = vec![Tok::min_value()];
+        for st in &self.states {
+            for &(range, _) in st.transitions.ranges_values() {
+                starts.push(range.start);
+                if range.end < Tok::max_value() {
+                    starts.push(range.end + Tok::one());
+                }
+            }
+        }
+        starts.sort();
+        starts.dedup();
+
+        let mut part = Partition::new(Some(0..starts.len()).into_iter(), starts.len());
+        {
+            let interval = |tok: Tok| match starts.binary_search(&tok) {
+                Ok(i) => i,
+                Err(i) => i - 1,
+            };
+            let mut buf = Vec::new();
+            for st in &self.states {
+                let group = st.transitions.ranges_values().group_by_lazy(|x| x.1);
+                for (_, ranges_values) in &group {
+                    buf.clear();
+                    for &(range, _) in ranges_values {
+                        buf.extend(interval(range.start)..(interval(range.end) + 1));
+                    }
+                    part.refine(&buf);
+                }
+            }
+        }
+
+        let mut classes = vec![0; starts.len()];
+        for (i, p) in part.iter().enumerate() {
+            for &x in p {
+                classes[x] = i as u32;
+            }
+        }
+        (starts, classes, part.num_parts())
+    }
+
+    /// Compiles this `Dfa` into instructions that can run on a slice of tokens.
     ///
-    /// If the set is non-empty, every match of this `Dfa` is guaranteed to start with one of these
-    /// strings.
-    pub fn prefix_strings(&self) -> Vec<PrefixPart> {
-        // It might seem silly to look for prefixes starting at the anchored state, but it's useful
-        // for forward-backward matching. In cases where the regex is honestly anchored, we won't
-        // ask to make a prefix anyway.
-        if let Some(state) = self.init_state(Look::Boundary) {
-            PrefixSearcher::extract(self, state)
-        } else {
-            Vec::new()
+    /// For `Dfa`s that consume bytes, `compile` makes something much faster.
+    pub fn compile_tokens(&self) -> TokenInsts<Tok, Ret> {
+        let (interval_starts, interval_class, num_classes) = self.token_equivalence_classes();
+        let (accept, accept_at_eoi) = self.accept_tables();
+
+        let mut table = vec![DEAD_STATE; self.num_states() * num_classes];
+        for (idx, st) in self.states.iter().enumerate() {
+            for &(range, tgt_state) in st.transitions.ranges_values() {
+                let first = interval_starts.binary_search(&range.start)
+                    .expect("BUG: every range should start an interval");
+                for i in first..interval_starts.len() {
+                    if interval_starts[i] > range.end {
+                        break;
+                    }
+                    let class = interval_class[i] as usize;
+                    table[idx * num_classes + class] = tgt_state as TableStateIdx;
+                }
+            }
+        }
+
+        TokenInsts {
+            interval_starts: interval_starts,
+            interval_class: interval_class,
+            num_classes: num_classes,
+            table: table,
+            accept: accept,
+            accept_at_eoi: accept_at_eoi,
+            init_at_start: self.init_at_start(),
+            init_otherwise: self.init_otherwise(),
         }
     }
 
-    /*
-    pub fn critical_strings(&self) -> Vec<(Vec<u8>, StateIdx)> {
-        unimplemented!();
+    /// Compiles this `Dfa` into a `TokenSearcher`, which finds matches in a slice of tokens in
+    /// linear time.
+    ///
+    /// As well as the program from `compile_tokens`, this needs a program that runs backward to
+    /// find where matches start. It comes from determinizing the reversal of this `Dfa`, which
+    /// fails if that needs more than `max_states` states.
+    pub fn compile_token_searcher(&self, max_states: usize) -> ::Result<TokenSearcher<Tok, Ret>> {
+        let backward = match self.init_otherwise() {
+            Some(init) => {
+                let nfa = try!(self.match_starts_nfa(init));
+                let dfa = try!(nfa.determinize_longest(max_states)).map_ret(|_| ());
+                Some(dfa.optimize().compile_tokens())
+            },
+            None => None,
+        };
+        Ok(TokenSearcher::new(self.compile_tokens(), backward))
     }
-    */
 
-    // Finds the bytes that are treated equivalently by this Dfa.
-    //
-    // Returns a Vec of length 256 such that vec[i] == vec[j] when i and j are two equivalent
-    // bytes. Also returns the log of the number of classes, rounded up.
-    fn byte_equivalence_classes(&self) -> (Vec<u8>, u32) {
-        let mut part = Partition::new(Some(0..256).into_iter(), 256);
-        let mut buf = Vec::with_capacity(256);
+    // Returns an `Nfa` that reads the input backward from the end, and accepts wherever a match
+    // that starts from `init` could start.
+    fn match_starts_nfa(&self, init: StateIdx) -> ::Result<Nfa<Tok, NoLooks>> {
+        let (accept, accept_at_eoi) = self.accept_tables();
+        let mut b = NfaBuilder::<Tok, NoLooks>::new();
+        for idx in 0..self.num_states() {
+            b.add_state(if idx == init { Accept::Always } else { Accept::Never });
+        }
 
-        for st in &self.states {
-            let group = st.transitions.keys_values().group_by_lazy(|x| x.1);
-            for (_, keys_values) in &group {
-                buf.clear();
-                for (key, _) in keys_values {
-                    buf.push(key as usize);
+        // We start in `end`, along with the states that accept at the end of the input. Once we
+        // have read a token, we are in `any`, which stands for all the states that accept before
+        // the end of the input.
+        let end = b.add_state(Accept::Never);
+        let any = b.add_state(if accept[init].is_some() { Accept::Always } else { Accept::Never });
+        b.add_init(Look::Boundary, end);
+        b.add_transition(end, any, Range::full());
+        b.add_transition(any, any, Range::full());
+        for (idx, st) in self.states.iter().enumerate() {
+            if accept_at_eoi[idx].is_some() {
+                b.add_init(Look::Boundary, idx);
+            }
+            for &(range, tgt) in st.transitions.ranges_values() {
+                b.add_transition(tgt, idx, range);
+                if accept[tgt].is_some() {
+                    b.add_transition(any, idx, range);
                 }
-                part.refine(&buf);
             }
         }
+        b.build()
+    }
 
-        let mut ret = vec![0; 256];
-        for (i, p) in part.iter().enumerate() {
-            for &x in p {
-                ret[x] = i as u8;
+    // Returns the initial states, without duplicates.
+    fn init_states(&self) -> Vec<StateIdx> {
+        let mut ret: Vec<StateIdx> = self.init.iter().filter_map(|x| *x).collect();
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+
+    // For each state, checks whether there is some path from it to an accepting state.
+    fn coreachable_states(&self) -> Vec<bool> {
+        let rev = self.reversed_transitions();
+        let mut ret = vec![false; self.num_states()];
+        let mut stack = Vec::new();
+
+        for (idx, st) in self.states.iter().enumerate() {
+            if st.accept != Accept::Never {
+                ret[idx] = true;
+                stack.push(idx);
             }
         }
-        let size = (part.num_parts() - 1) as u32;
+        while let Some(idx) = stack.pop() {
+            for &(_, source) in rev[idx].ranges_values() {
+                if !ret[source] {
+                    ret[source] = true;
+                    stack.push(source);
+                }
+            }
+        }
+        ret
+    }
 
-        (ret, 32 - size.leading_zeros())
+    /// Returns the length (in bytes) of the shortest string that this `Dfa` accepts, or `None` if
+    /// it doesn't accept anything.
+    ///
+    /// The length counts every byte that the `Dfa` consumes, including any bytes that are only
+    /// there to check look-ahead or look-behind conditions.
+    pub fn min_match_len(&self) -> Option<usize> {
+        let mut dist: Vec<Option<usize>> = vec![None; self.num_states()];
+        let mut queue = VecDeque::new();
+        for idx in self.init_states() {
+            dist[idx] = Some(0);
+            queue.push_back(idx);
+        }
+
+        // Since every transition has length 1, the states come out of the queue in order of
+        // increasing distance. So the first accepting state we find is the closest one.
+        while let Some(idx) = queue.pop_front() {
+            let d = dist[idx].unwrap();
+            if self.states[idx].accept != Accept::Never {
+                return Some(d);
+            }
+            for tgt in self.neighbors(idx) {
+                if dist[tgt].is_none() {
+                    dist[tgt] = Some(d + 1);
+                    queue.push_back(tgt);
+                }
+            }
+        }
+        None
     }
 
-    /// Compiles this `Dfa` into instructions for execution.
-    pub fn compile(&self) -> TableInsts<Ret> {
-        let (byte_class, log_num_classes) = self.byte_equivalence_classes();
+    /// Returns the length (in bytes) of the longest string that this `Dfa` accepts, or `None` if
+    /// there is no bound on the length.
+    ///
+    /// If the `Dfa` doesn't accept anything, returns `Some(0)`. As with `min_match_len`, the
+    /// length includes any bytes that are consumed by look-around conditions.
+    pub fn max_match_len(&self) -> Option<usize> {
+        use graph::DfsInstruction::*;
+
+        // The length is unbounded if and only if there is a cycle that can be reached from an
+        // initial state and that can reach an accepting state.
+        let coreachable = self.coreachable_states();
+        let mut unbounded = false;
+        self.dfs(self.init_states().into_iter(),
+                 |_| Continue,
+                 |cycle| if coreachable[cycle[0]] { unbounded = true; Stop } else { Continue });
+        if unbounded {
+            return None;
+        }
 
-        let mut table = vec![u32::MAX; self.num_states() << log_num_classes];
-        let accept: Vec<Option<Ret>> = self.states.iter()
-            .map(|st| if st.accept == Accept::Always { st.ret } else { None })
+        // Now the states that can reach an accepting state form an acyclic graph, and we find the
+        // longest paths by processing it in reverse topological order.
+        let rev = self.reversed_transitions();
+        let mut out_degree: Vec<usize> = self.states.iter()
+            .map(|st| st.transitions.ranges_values().filter(|x| coreachable[x.1]).count())
             .collect();
-        let accept_at_eoi: Vec<Option<Ret>> = self.states.iter()
-            .map(|st| if st.accept != Accept::Never { st.ret } else { None })
+        let mut longest: Vec<Option<usize>> = vec![None; self.num_states()];
+        let mut stack: Vec<StateIdx> = (0..self.num_states())
+            .filter(|&idx| coreachable[idx] && out_degree[idx] == 0)
             .collect();
+        for &idx in &stack {
+            // A state that can reach an accepting state but has no transitions must be accepting.
+            longest[idx] = Some(0);
+        }
 
-        for (idx, st) in self.states.iter().enumerate() {
-            for (ch, &tgt_state) in st.transitions.keys_values() {
-                let class = byte_class[ch as usize];
-                table[(idx << log_num_classes) + class as usize] = tgt_state as u32;
+        while let Some(idx) = stack.pop() {
+            let len = longest[idx].unwrap();
+            for &(_, source) in rev[idx].ranges_values() {
+                let source_len = longest[source].map_or(len + 1, |x| max(x, len + 1));
+                longest[source] = Some(source_len);
+                out_degree[source] -= 1;
+                if out_degree[source] == 0 {
+                    stack.push(source);
+                }
             }
         }
 
-        TableInsts {
-            log_num_classes: log_num_classes,
-            byte_class: byte_class,
-            accept: accept,
-            accept_at_eoi: accept_at_eoi,
-            table: table,
-        }
+        Some(self.init_states().into_iter()
+            .filter_map(|idx| longest[idx])
+            .max()
+            .unwrap_or(0))
     }
 
     /// Finds an equivalent DFA with the minimal number of states.
-    pub fn optimize(self) -> Dfa<Ret> {
+    pub fn optimize(self) -> Dfa<Ret, Tok> {
         let mut ret = self.minimize();
         ret.sort_states();
         ret
     }
 
-    /// Deletes any transitions that return to the initial state.
+    /// Deletes any transitions that return to the initial state for the beginning of the input.
     ///
-    /// This results in a new Dfa with the following properties:
+    /// This results in a new Dfa with the following properties (when started from that initial
+    /// state):
     /// - if the original Dfa has a match then the new Dfa also has a match that ends in the same
     ///   position (and vice versa), and
     /// - the new Dfa doesn't need to backtrack to find matches: if it fails then it can be
@@ -253,16 +448,31 @@ impl<Ret: RetTrait> Dfa<Ret> {
     /// a `Prefix` to scan ahead for a good place to resume matching.
     ///
     /// # Panics
-    /// - if `self` is not anchored.
-    pub fn cut_loop_to_init(mut self) -> Dfa<Ret> {
-        if !self.is_anchored() {
-            panic!("only anchored Dfas can be cut");
+    /// - if `self` has no initial state for the beginning of the input.
+    pub fn cut_loop_to_init(mut self) -> Dfa<Ret, Tok> {
+        let init = match self.init_at_start() {
+            Some(init) => init,
+            None => panic!("only Dfas that can start at the beginning can be cut"),
+        };
+        for st in &mut self.states {
+            st.transitions.retain_values(|x| *x != init);
         }
+        self
+    }
 
-        // The unwrap is safe because we just checked that we are anchored.
-        let init = self.init_at_start().unwrap();
+    /// Deletes any transitions (and initial states) that lead to states that can never accept.
+    ///
+    /// This doesn't change what the `Dfa` accepts, but it can make `optimize` more effective: it
+    /// never merges states that have transitions on different bytes, even if they can't accept.
+    pub fn cut_dead_ends(mut self) -> Dfa<Ret, Tok> {
+        let live = self.coreachable_states();
         for st in &mut self.states {
-            st.transitions.retain_values(|x| *x != init);
+            st.transitions.retain_values(|x| live[*x]);
+        }
+        for init in &mut self.init {
+            if init.map_or(false, |s| !live[s]) {
+                *init = None;
+            }
         }
         self
     }
@@ -290,10 +500,12 @@ impl<Ret: RetTrait> Dfa<Ret> {
         let mut old_states = vec![State::new(Accept::Never, None); self.states.len()];
         mem::swap(&mut old_states, &mut self.states);
 
+        let num_reachable = sorted.len();
         for (new_idx, old_idx) in sorted.into_iter().enumerate() {
             state_map[old_idx] = Some(new_idx);
             mem::swap(&mut old_states[old_idx], &mut self.states[new_idx]);
         }
+        self.states.truncate(num_reachable);
 
         // Fix the transitions and initialization to point to the new states. The `unwrap` here is
         // basically the assertion that all reachable states should be mapped to new states.
@@ -387,7 +599,290 @@ impl<Ret: RetTrait> Dfa<Ret> {
     */
 }
 
-impl<Ret: Debug> Debug for Dfa<Ret> {
+impl<Ret: RetTrait> Dfa<Ret> {
+    /// Returns a set of strings that match the beginning of this `Dfa`.
+    ///
+    /// If the set is non-empty, every match of this `Dfa` is guaranteed to start with one of these
+    /// strings.
+    pub fn prefix_strings(&self) -> Vec<PrefixPart> {
+        // It might seem silly to look for prefixes starting at the anchored state, but it's useful
+        // for forward-backward matching. In cases where the regex is honestly anchored, we won't
+        // ask to make a prefix anyway.
+        if let Some(state) = self.init_state(Look::Boundary) {
+            PrefixSearcher::extract(self, state)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /*
+    pub fn critical_strings(&self) -> Vec<(Vec<u8>, StateIdx)> {
+        unimplemented!();
+    }
+    */
+
+    // Finds the bytes that are treated equivalently by this Dfa.
+    //
+    // Returns a Vec of length 256 such that vec[i] == vec[j] when i and j are two equivalent
+    // bytes. Also returns the log of the number of classes, rounded up.
+    fn byte_equivalence_classes(&self) -> (Vec<u8>, u32) {
+        let mut part = Partition::new(Some(0..256).into_iter(), 256);
+        let mut buf = Vec::with_capacity(256);
+
+        for st in &self.states {
+            let group = st.transitions.keys_values().group_by_lazy(|x| x.1);
+            for (_, keys_values) in &group {
+                buf.clear();
+                for (key, _) in keys_values {
+                    buf.push(key as usize);
+                }
+                part.refine(&buf);
+            }
+        }
+
+        let mut ret = vec![0; 256];
+        for (i, p) in part.iter().enumerate() {
+            for &x in p {
+                ret[x] = i as u8;
+            }
+        }
+        let size = (part.num_parts() - 1) as u32;
+
+        (ret, 32 - size.leading_zeros())
+    }
+
+    // Returns the state that we go to from `state` on reading `byte`.
+    fn next_state(&self, state: StateIdx, byte: u8) -> Option<StateIdx> {
+        self.states[state].transitions.ranges_values()
+            .find(|x| x.0.start <= byte && byte <= x.0.end)
+            .map(|x| x.1)
+    }
+
+    // Returns true if reading any non-ASCII codepoint (encoded as UTF-8) starting at `state`
+    // brings us back to `state`.
+    fn loops_on_non_ascii(&self, state: StateIdx) -> bool {
+        for seq in Utf8Sequences::new('\u{80}', '\u{10FFFF}') {
+            let mut cur = vec![state];
+            for range in seq.as_slice() {
+                let mut next = Vec::new();
+                for &s in &cur {
+                    for b in (range.start as usize)..(range.end as usize + 1) {
+                        match self.next_state(s, b as u8) {
+                            Some(t) => next.push(t),
+                            None => return false,
+                        }
+                    }
+                }
+                next.sort();
+                next.dedup();
+                cur = next;
+            }
+            if cur != vec![state] {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Checks whether the given state loops back to itself on all but a few bytes, and if so
+    // returns the bytes that leave it.
+    //
+    // If the input is valid UTF-8, a state that loops back to itself on every non-ASCII codepoint
+    // only needs to search for its ASCII escape bytes: those can't appear in the middle of a
+    // multi-byte codepoint. In Latin-1, every byte is a char, so all of them count.
+    //
+    // States that only accept before the end of the input aren't accelerated, because skipping
+    // over the input would lose track of the last position at which they accepted.
+    fn accel(&self, state: StateIdx, encoding: Encoding) -> Accel {
+        if self.states[state].accept == Accept::NotAtEoi {
+            return Accel::None;
+        }
+
+        let mut loops = [false; 256];
+        for (ch, &tgt_state) in self.states[state].transitions.keys_values() {
+            if tgt_state == state {
+                loops[ch as usize] = true;
+            }
+        }
+
+        let num_ascii_escapes = (0..128).filter(|&b| !loops[b]).count();
+        if num_ascii_escapes > 3 {
+            return Accel::None;
+        }
+        let max_byte = match encoding {
+            Encoding::Utf8 if self.loops_on_non_ascii(state) => 128,
+            _ => 256,
+        };
+        let escapes: Vec<u8> = (0..max_byte)
+            .filter(|&b| !loops[b])
+            .take(4)
+            .map(|b| b as u8)
+            .collect();
+        Accel::from_escapes(&escapes)
+    }
+
+    /// Compiles this `Dfa` into instructions for execution on valid UTF-8.
+    pub fn compile(&self) -> TableInsts<Ret> {
+        self.compile_with(Encoding::Utf8)
+    }
+
+    /// Compiles this `Dfa` into instructions for execution on input in the given encoding.
+    pub fn compile_with(&self, encoding: Encoding) -> TableInsts<Ret> {
+        let (byte_class, log_num_classes) = self.byte_equivalence_classes();
+        let (accept, accept_at_eoi) = self.accept_tables();
+        let accel: Vec<Accel> = (0..self.num_states())
+            .map(|idx| self.accel(idx, encoding))
+            .collect();
+
+        // Put the special states (the ones that need more than just a table lookup) at the end, so
+        // that we can recognize them with a single comparison.
+        let special: Vec<bool> = (0..self.num_states())
+            .map(|idx| accept[idx].is_some() || accel[idx] != Accel::None)
+            .collect();
+        let order: Vec<StateIdx> = (0..self.num_states()).filter(|&idx| !special[idx])
+            .chain((0..self.num_states()).filter(|&idx| special[idx]))
+            .collect();
+        let num_boring = special.iter().filter(|x| !**x).count();
+
+        let mut state_map = vec![0; self.num_states()];
+        for (new_idx, &old_idx) in order.iter().enumerate() {
+            state_map[old_idx] = (new_idx << log_num_classes) as u32;
+        }
+
+        let mut table = vec![DEAD_STATE; self.num_states() << log_num_classes];
+        for (new_idx, &old_idx) in order.iter().enumerate() {
+            for (ch, &tgt_state) in self.states[old_idx].transitions.keys_values() {
+                let class = byte_class[ch as usize];
+                table[(new_idx << log_num_classes) + class as usize] = state_map[tgt_state];
+            }
+        }
+
+        TableInsts {
+            log_num_classes: log_num_classes,
+            byte_class: byte_class,
+            special_min: (num_boring << log_num_classes) as u32,
+            state_map: state_map,
+            accept: order.iter().map(|&idx| accept[idx]).collect(),
+            accept_at_eoi: order.iter().map(|&idx| accept_at_eoi[idx]).collect(),
+            accel: order.iter().map(|&idx| accel[idx]).collect(),
+            utf8: encoding == Encoding::Utf8,
+            table: table,
+        }
+    }
+
+    /// Compiles this `Dfa` into instructions that use less memory than `compile`, but are a bit
+    /// slower to execute on non-ASCII input.
+    ///
+    /// Returns `None` if there are too many states to fit in `Idx`.
+    pub fn compile_compact<Idx: CompactIdx>(&self) -> Option<CompactInsts<Ret, Idx>> {
+        self.compile_compact_with(Encoding::Utf8)
+    }
+
+    /// Like `compile_compact`, but for input in the given encoding.
+    pub fn compile_compact_with<Idx: CompactIdx>(&self, encoding: Encoding)
+    -> Option<CompactInsts<Ret, Idx>> {
+        let dead = Idx::max_value();
+        if Idx::max_value().to_usize().map_or(false, |max| self.num_states() >= max) {
+            return None;
+        }
+
+        // Find the equivalence classes of the non-ASCII bytes, and a representative of each class.
+        let (byte_class, _) = self.byte_equivalence_classes();
+        let mut class_map: Vec<Option<u8>> = vec![None; 256];
+        let mut class_reps = Vec::new();
+        let mut high_class = Vec::with_capacity(128);
+        for b in 0x80..0x100 {
+            let class = byte_class[b] as usize;
+            if class_map[class].is_none() {
+                class_map[class] = Some(class_reps.len() as u8);
+                class_reps.push(b);
+            }
+            high_class.push(class_map[class].unwrap());
+        }
+
+        let mut ascii = Vec::with_capacity(self.num_states() * 128);
+        let mut high = Vec::new();
+        let mut high_row = Vec::with_capacity(self.num_states());
+        let mut rows = HashMap::<Vec<Idx>, u32>::new();
+        for st in &self.states {
+            let mut trans = vec![dead; 256];
+            for (ch, &tgt_state) in st.transitions.keys_values() {
+                trans[ch as usize] = <Idx as NumCast>::from(tgt_state).unwrap();
+            }
+
+            ascii.extend_from_slice(&trans[..128]);
+            let row: Vec<Idx> = class_reps.iter().map(|&b| trans[b]).collect();
+            let offset = *rows.entry(row.clone()).or_insert_with(|| {
+                let offset = high.len() as u32;
+                high.extend_from_slice(&row);
+                offset
+            });
+            high_row.push(offset);
+        }
+
+        let (accept, accept_at_eoi) = self.accept_tables();
+        Some(CompactInsts {
+            ascii: ascii,
+            high_class: high_class,
+            high: high,
+            high_row: high_row,
+            accept: accept,
+            accept_at_eoi: accept_at_eoi,
+            accel: (0..self.num_states()).map(|idx| self.accel(idx, encoding)).collect(),
+            utf8: encoding == Encoding::Utf8,
+        })
+    }
+
+    /// Returns a `Dfa` that accepts every valid UTF-8 string, returning `ret`.
+    pub fn valid_utf8(ret: Option<Ret>) -> Dfa<Ret> {
+        let mut dfa = Dfa::new();
+        // The state at a character boundary.
+        let start = dfa.add_state(Accept::Always, ret);
+        // The states that need 1, 2 or 3 more continuation bytes.
+        let cont1 = dfa.add_state(Accept::Never, None);
+        let cont2 = dfa.add_state(Accept::Never, None);
+        let cont3 = dfa.add_state(Accept::Never, None);
+        // The states after a lead byte that restricts the range of the next continuation byte.
+        let after_e0 = dfa.add_state(Accept::Never, None);
+        let after_ed = dfa.add_state(Accept::Never, None);
+        let after_f0 = dfa.add_state(Accept::Never, None);
+        let after_f4 = dfa.add_state(Accept::Never, None);
+
+        let trans = |v: &[(u8, u8, StateIdx)]| -> RangeMap<u8, StateIdx> {
+            v.iter().map(|&(a, b, tgt)| (Range::new(a, b), tgt)).collect()
+        };
+        dfa.set_transitions(start, trans(&[(0x00, 0x7F, start),
+                                           (0xC2, 0xDF, cont1),
+                                           (0xE0, 0xE0, after_e0),
+                                           (0xE1, 0xEC, cont2),
+                                           (0xED, 0xED, after_ed),
+                                           (0xEE, 0xEF, cont2),
+                                           (0xF0, 0xF0, after_f0),
+                                           (0xF1, 0xF3, cont3),
+                                           (0xF4, 0xF4, after_f4)]));
+        dfa.set_transitions(cont1, trans(&[(0x80, 0xBF, start)]));
+        dfa.set_transitions(cont2, trans(&[(0x80, 0xBF, cont1)]));
+        dfa.set_transitions(cont3, trans(&[(0x80, 0xBF, cont2)]));
+        dfa.set_transitions(after_e0, trans(&[(0xA0, 0xBF, cont1)]));
+        dfa.set_transitions(after_ed, trans(&[(0x80, 0x9F, cont1)]));
+        dfa.set_transitions(after_f0, trans(&[(0x90, 0xBF, cont2)]));
+        dfa.set_transitions(after_f4, trans(&[(0x80, 0x8F, cont2)]));
+
+        dfa.init = vec![Some(start); Look::num()];
+        dfa
+    }
+
+    /// Returns a `Dfa` that accepts every string of bytes, returning `ret`.
+    pub fn any_bytes(ret: Option<Ret>) -> Dfa<Ret> {
+        let mut dfa = Dfa::new();
+        let start = dfa.add_state(Accept::Always, ret);
+        dfa.set_transitions(start, vec![(Range::new(0, 255), start)].into_iter().collect());
+        dfa.init = vec![Some(start); Look::num()];
+        dfa
+    }
+}
+
+impl<Ret: Debug, Tok: TokTrait> Debug for Dfa<Ret, Tok> {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
         try!(f.write_fmt(format_args!("Dfa ({} states):\n", self.states.len())));
 
@@ -403,7 +898,7 @@ impl<Ret: Debug> Debug for Dfa<Ret> {
                 try!(f.write_str("\t\tTransitions:\n"));
                 // Cap it at 5 transitions, since it gets unreadable otherwise.
                 for &(range, target) in st.transitions.ranges_values().take(5) {
-                    try!(f.write_fmt(format_args!("\t\t\t{} -- {} => {}\n",
+                    try!(f.write_fmt(format_args!("\t\t\t{:?} -- {:?} => {}\n",
                                                   range.start, range.end, target)));
                 }
                 if st.transitions.num_ranges() > 5 {
@@ -425,6 +920,7 @@ pub mod tests {
     use look::Look;
     use nfa::{Accept, Nfa, StateIdx};
     use range_map::{Range, RangeMap};
+    use runner::program::Accel;
     use std::usize;
 
     // Creates a non-backtracking dfa from a regex string.
@@ -505,6 +1001,52 @@ pub mod tests {
         assert_eq!(pref, vec!["abc".as_bytes()]);
     }
 
+    #[test]
+    fn test_accel() {
+        // The state inside the quotes loops on everything except the closing quote.
+        let prog = make_anchored(r#""[^"]*""#).compile();
+        assert!(prog.accel.contains(&Accel::One(b'"')));
+
+        let prog = make_anchored(r#""[^"\\]*""#).compile();
+        assert!(prog.accel.contains(&Accel::Two(b'"', b'\\')));
+
+        // Too many ways to leave.
+        let prog = make_anchored("a[^bcde]*b").compile();
+        assert!(prog.accel.iter().all(|x| *x == Accel::None));
+    }
+
+    #[test]
+    fn test_special_states_last() {
+        let prog = make_anchored("ab+c*d|[0-9]+x").compile();
+        let first_special = (prog.special_min >> prog.log_num_classes) as usize;
+        assert!(first_special < prog.accept.len());
+        for idx in 0..prog.accept.len() {
+            let special = prog.accept[idx].is_some() || prog.accel[idx] != Accel::None;
+            assert_eq!(special, idx >= first_special);
+        }
+    }
+
+    #[test]
+    fn test_match_len() {
+        macro_rules! len {
+            ($re:expr, $min:expr, $max:expr) => {
+                {
+                    let dfa = make_dfa($re).unwrap();
+                    assert_eq!((dfa.min_match_len(), dfa.max_match_len()), ($min, $max));
+                }
+            };
+        }
+
+        len!("abc", Some(3), Some(3));
+        len!("a|bcd", Some(1), Some(3));
+        len!("ab?", Some(1), Some(2));
+        len!("x{2,5}", Some(2), Some(5));
+        len!("ab*c", Some(2), None);
+        len!("(ab)+", Some(2), None);
+        len!("é", Some(2), Some(2));
+        len!(r"test\B", None, Some(0));
+    }
+
     #[test]
     fn test_minimize() {
         let auto = make_dfa("a*?b*?").unwrap();
@@ -0,0 +1,496 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Counting, listing and sampling the strings that a `Dfa` accepts.
+//!
+//! Here, the strings accepted by a `Dfa` are the ones that it accepts at the end of the input when
+//! starting from its `Look::Boundary` initial state. A `Dfa<Ret>` works byte-by-byte, but it can
+//! optionally be restricted to valid UTF-8 by first intersecting with a `Dfa` that only accepts
+//! valid UTF-8. A `Dfa<Ret, u32>` (like the `whole_match_dfa` of an `Nfa<u32, NoLooks>`) works
+//! char-by-char instead.
+
+use dfa::{Dfa, RetTrait, TokTrait};
+use nfa::StateIdx;
+use num_traits::NumCast;
+use rand::Rng;
+use range_map::{Range, RangeSet};
+use std::borrow::Cow;
+use std::char;
+use std::cmp::{max, min};
+
+#[cfg(feature = "quickcheck")]
+use nfa::{Nfa, NoLooks};
+#[cfg(feature = "quickcheck")]
+use quickcheck::Gen;
+
+// The number of tokens in `range`.
+fn range_len<Tok: TokTrait>(range: Range<Tok>) -> u64 {
+    (range.end - range.start).to_u64().unwrap() + 1
+}
+
+impl<Ret: RetTrait, Tok: TokTrait> Dfa<Ret, Tok> {
+    // Returns a table `t` such that `t[k][s]` is the number of strings of length `k` that take
+    // state `s` to a state accepting at the end of the input. The counts saturate at `u64::MAX`.
+    fn completion_counts(&self, max_len: usize) -> Vec<Vec<u64>> {
+        let mut ret = Vec::with_capacity(max_len + 1);
+        ret.push(self.states.iter()
+            .map(|st| if st.accept.accepts_at_eoi() { 1 } else { 0 })
+            .collect::<Vec<u64>>());
+
+        for k in 0..max_len {
+            let next = self.states.iter()
+                .map(|st| {
+                    st.transitions.ranges_values().fold(0u64, |acc, &(range, tgt)| {
+                        acc.saturating_add(range_len(range).saturating_mul(ret[k][tgt]))
+                    })
+                })
+                .collect();
+            ret.push(next);
+        }
+        ret
+    }
+
+    // Returns a random string of length `len` that takes the initial state to a state accepting at
+    // the end of the input, given the table returned by `completion_counts` (for some length at
+    // least `len`).
+    fn sample_with_counts<R: Rng>(&self, rng: &mut R, len: usize, counts: &[Vec<u64>])
+    -> Option<Vec<Tok>> {
+        let mut state = match self.init_at_start() {
+            Some(init) => init,
+            None => return None,
+        };
+        if counts[len][state] == 0 {
+            return None;
+        }
+
+        // Choose each token with probability proportional to the number of ways that we can
+        // accept after choosing it.
+        let mut ret = Vec::with_capacity(len);
+        for remaining in (0..len).rev() {
+            let mut x = rng.gen_range(0, counts[remaining + 1][state]);
+            for &(range, tgt) in self.states[state].transitions.ranges_values() {
+                let num_toks = range_len(range);
+                let count = counts[remaining][tgt];
+                let weight = num_toks.saturating_mul(count);
+                if x < weight {
+                    // If the counts saturated, `x / count` might be too big.
+                    let offset: Tok = NumCast::from(min(x / count, num_toks - 1)).unwrap();
+                    ret.push(range.start + offset);
+                    state = tgt;
+                    break;
+                }
+                x -= weight;
+            }
+        }
+        Some(ret)
+    }
+}
+
+impl<Ret: RetTrait> Dfa<Ret> {
+    /// Returns the number of strings of length `len` (in bytes) that this `Dfa` accepts at the end
+    /// of the input, starting from its `Look::Boundary` initial state.
+    ///
+    /// If `utf8_only` is true, only valid UTF-8 strings are counted. The count saturates at
+    /// `u64::MAX`.
+    pub fn count_strings_of_length(&self, len: usize, utf8_only: bool) -> u64 {
+        if utf8_only {
+            return self.intersect(&Dfa::valid_utf8(None)).count_strings_of_length(len, false);
+        }
+
+        match self.init_at_start() {
+            Some(init) => self.completion_counts(len)[len][init],
+            None => 0,
+        }
+    }
+
+    /// Lists the strings that this `Dfa` accepts at the end of the input (starting from its
+    /// `Look::Boundary` initial state), in length-lexicographic order.
+    ///
+    /// Only strings of at most `max_len` bytes are listed, and we stop after `max_results` of
+    /// them. If `utf8_only` is true, only valid UTF-8 strings are listed.
+    ///
+    /// The strings are found lazily, so it is fine to ask for many more of them than you take.
+    pub fn iter_strings<'a>(&'a self, max_len: usize, max_results: usize, utf8_only: bool)
+    -> StringIter<'a, Ret> {
+        let dfa = if utf8_only {
+            Cow::Owned(self.intersect(&Dfa::valid_utf8(None)))
+        } else {
+            Cow::Borrowed(self)
+        };
+        let counts = dfa.completion_counts(max_len);
+        StringIter {
+            // If there is no initial state, start past the last length.
+            len: if dfa.init_at_start().is_some() { 0 } else { max_len + 1 },
+            dfa: dfa,
+            counts: counts,
+            max_len: max_len,
+            remaining: max_results,
+            stack: Vec::new(),
+            prefix: Vec::new(),
+        }
+    }
+
+    /// Returns a random string of length `len` (in bytes) that this `Dfa` accepts at the end of
+    /// the input (starting from its `Look::Boundary` initial state), or `None` if there aren't
+    /// any.
+    ///
+    /// The string is chosen uniformly among all such strings, as long as there are fewer than
+    /// `u64::MAX` of them. If `utf8_only` is true, the string is chosen among the valid UTF-8
+    /// ones. To choose among the strings with `len` chars instead, use `sample_chars`.
+    pub fn sample<R: Rng>(&self, rng: &mut R, len: usize, utf8_only: bool) -> Option<Vec<u8>> {
+        if utf8_only {
+            return self.intersect(&Dfa::valid_utf8(None)).sample(rng, len, false);
+        }
+        self.sample_with_counts(rng, len, &self.completion_counts(len))
+    }
+}
+
+/// An iterator over the strings that a `Dfa` accepts, in length-lexicographic order.
+///
+/// This is created by `Dfa::iter_strings`.
+#[derive(Debug)]
+pub struct StringIter<'a, Ret: RetTrait + 'static> {
+    dfa: Cow<'a, Dfa<Ret>>,
+    counts: Vec<Vec<u64>>,
+    max_len: usize,
+    // The number of strings that we may still return.
+    remaining: usize,
+    // The length of the strings that we are currently listing.
+    len: usize,
+    // The path from the initial state to the string that we are looking at. Along with each state
+    // on the path, we store the index of the range in its transitions and the byte that we will
+    // try next.
+    stack: Vec<(StateIdx, usize, u32)>,
+    // The bytes leading to the last state in `stack`.
+    prefix: Vec<u8>,
+}
+
+impl<'a, Ret: RetTrait> StringIter<'a, Ret> {
+    // Finds the first byte (starting from `byte` in the range with index `range_idx`) that leads
+    // from `state` to a state that can accept after the rest of the current length. Returns the
+    // byte, the state that it leads to, and the index of the range that the byte is in.
+    fn next_byte(&self, state: StateIdx, mut range_idx: usize, byte: u32, depth: usize)
+    -> Option<(u8, StateIdx, usize)> {
+        let trans = self.dfa.transitions(state).ranges_values().as_slice();
+        while let Some(&(range, tgt)) = trans.get(range_idx) {
+            // The ranges are sorted, so `byte` is never past the start of a later one.
+            if byte <= range.end as u32 && self.counts[self.len - depth - 1][tgt] > 0 {
+                return Some((max(byte, range.start as u32) as u8, tgt, range_idx));
+            }
+            range_idx += 1;
+        }
+        None
+    }
+
+    // Goes back to the previous state on the stack, or on to the next length if there isn't one.
+    fn backtrack(&mut self) {
+        self.stack.pop();
+        self.prefix.pop();
+        if self.stack.is_empty() {
+            self.len += 1;
+        }
+    }
+}
+
+impl<'a, Ret: RetTrait> Iterator for StringIter<'a, Ret> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        while self.remaining > 0 && self.len <= self.max_len {
+            let depth = self.prefix.len();
+            let (state, range_idx, byte) = match self.stack.last() {
+                Some(&top) => top,
+                None => {
+                    // Start on the strings of the current length, if there are any.
+                    let init = self.dfa.init_at_start().unwrap();
+                    if self.counts[self.len][init] > 0 {
+                        self.stack.push((init, 0, 0));
+                    } else {
+                        self.len += 1;
+                    }
+                    continue;
+                },
+            };
+
+            if depth == self.len {
+                // We only go to states that can accept in time, so `prefix` is accepted.
+                let ret = self.prefix.clone();
+                self.backtrack();
+                self.remaining -= 1;
+                return Some(ret);
+            }
+
+            match self.next_byte(state, range_idx, byte, depth) {
+                Some((b, tgt, range_idx)) => {
+                    *self.stack.last_mut().unwrap() = (state, range_idx, b as u32 + 1);
+                    self.prefix.push(b);
+                    self.stack.push((tgt, 0, 0));
+                },
+                None => self.backtrack(),
+            }
+        }
+        None
+    }
+}
+
+impl<Ret: RetTrait> Dfa<Ret, u32> {
+    // Returns a copy of this `Dfa` without any transitions on tokens that aren't chars.
+    fn chars_only(&self) -> Dfa<Ret, u32> {
+        let chars: RangeSet<u32> = vec![Range::new(0, 0xD7FF), Range::new(0xE000, 0x10FFFF)]
+            .into_iter()
+            .collect();
+        let mut ret = self.clone();
+        for st in &mut ret.states {
+            st.transitions = st.transitions.intersection(&chars);
+        }
+        ret
+    }
+
+    /// Returns a random string of `len` chars that this `Dfa` accepts at the end of the input
+    /// (starting from its `Look::Boundary` initial state), or `None` if there aren't any.
+    ///
+    /// Each token of this `Dfa` is a char, so the string is always valid. (Tokens that aren't
+    /// chars, like surrogates, are skipped.) As with `sample`, it is chosen uniformly among all
+    /// such strings, as long as there are fewer than `u64::MAX` of them.
+    pub fn sample_chars<R: Rng>(&self, rng: &mut R, len: usize) -> Option<String> {
+        let dfa = self.chars_only();
+        dfa.sample_with_counts(rng, len, &dfa.completion_counts(len))
+            .map(|s| s.into_iter().map(|c| char::from_u32(c).unwrap()).collect())
+    }
+}
+
+/// A generator of random strings that match a regex in their entirety, for implementing
+/// `quickcheck::Arbitrary`.
+///
+/// This is only available with the `quickcheck` feature.
+#[cfg(feature = "quickcheck")]
+#[derive(Clone, Debug)]
+pub struct MatchGen {
+    dfa: Dfa<(), u32>,
+}
+
+#[cfg(feature = "quickcheck")]
+impl MatchGen {
+    /// Creates a generator for the strings that `re` matches in their entirety.
+    pub fn new(re: &str) -> ::Result<MatchGen> {
+        MatchGen::new_bounded(re, ::std::usize::MAX)
+    }
+
+    /// Creates a generator for the strings that `re` matches in their entirety, but only if its
+    /// `Dfa` doesn't need too many states.
+    pub fn new_bounded(re: &str, max_states: usize) -> ::Result<MatchGen> {
+        let nfa = try!(Nfa::from_regex(re)).remove_looks();
+        MatchGen::from_nfa(&nfa, max_states)
+    }
+
+    /// Creates a generator for the strings that `nfa` matches in their entirety.
+    pub fn from_nfa(nfa: &Nfa<u32, NoLooks>, max_states: usize) -> ::Result<MatchGen> {
+        let dfa = try!(nfa.whole_match_dfa(max_states)).chars_only();
+        Ok(MatchGen { dfa: dfa })
+    }
+
+    /// Returns a random matching string of at most `g.size()` chars, or `None` if there aren't
+    /// any.
+    ///
+    /// The length is chosen uniformly among the lengths that some matching string has, and then
+    /// the string is chosen uniformly among those of that length (as in `Dfa::sample_chars`).
+    pub fn gen<G: Gen>(&self, g: &mut G) -> Option<String> {
+        let init = match self.dfa.init_at_start() {
+            Some(init) => init,
+            None => return None,
+        };
+        let max_len = g.size();
+        let counts = self.dfa.completion_counts(max_len);
+        let lens: Vec<usize> = (0..(max_len + 1)).filter(|&len| counts[len][init] > 0).collect();
+        if lens.is_empty() {
+            return None;
+        }
+
+        let len = lens[g.gen_range(0, lens.len())];
+        self.dfa.sample_with_counts(g, len, &counts)
+            .map(|s| s.into_iter().map(|c| char::from_u32(c).unwrap()).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa::Dfa;
+    use look::Look;
+    use nfa::{Accept, Nfa};
+    use nfa::tests::re_nfa;
+    use quickcheck::{Arbitrary, Gen, quickcheck};
+    use rand::{SeedableRng, XorShiftRng};
+    use range_map::Range;
+    use regex::Regex;
+    use std::collections::HashMap;
+    use std::{u64, usize};
+
+    fn lang(re: &str) -> Dfa<()> {
+        re_nfa(re).byte_me(usize::MAX).unwrap().whole_match_dfa(usize::MAX).unwrap()
+    }
+
+    fn char_lang(re: &str) -> Dfa<(), u32> {
+        let nfa = Nfa::from_regex(re).unwrap().remove_looks();
+        nfa.whole_match_dfa(usize::MAX).unwrap()
+    }
+
+    fn strings(re: &str, max_len: usize, max_results: usize) -> Vec<Vec<u8>> {
+        lang(re).iter_strings(max_len, max_results, false).collect()
+    }
+
+    #[test]
+    fn count() {
+        assert_eq!(lang("[a-c]{2}x?").count_strings_of_length(2, false), 9);
+        assert_eq!(lang("[a-c]{2}x?").count_strings_of_length(3, false), 9);
+        assert_eq!(lang("[a-c]{2}x?").count_strings_of_length(4, false), 0);
+        assert_eq!(lang("a*").count_strings_of_length(1000, false), 1);
+        assert_eq!(lang("").count_strings_of_length(0, false), 1);
+        assert_eq!(Dfa::<()>::new().count_strings_of_length(0, false), 0);
+
+        // Two-byte chars come in 1920 flavors.
+        assert_eq!(lang(".").count_strings_of_length(2, false), 1920);
+        assert_eq!(lang(".*").count_strings_of_length(200, false), u64::MAX);
+    }
+
+    #[test]
+    fn count_utf8() {
+        // A `Dfa` accepting any two bytes.
+        let mut dfa = Dfa::<()>::new();
+        let s0 = dfa.add_state(Accept::Never, None);
+        let s1 = dfa.add_state(Accept::Never, None);
+        let s2 = dfa.add_state(Accept::AtEoi, Some(()));
+        dfa.set_transitions(s0, vec![(Range::full(), s1)].into_iter().collect());
+        dfa.set_transitions(s1, vec![(Range::full(), s2)].into_iter().collect());
+        dfa.init[Look::Boundary.as_usize()] = Some(s0);
+
+        assert_eq!(dfa.count_strings_of_length(2, false), 256 * 256);
+        // Either two ASCII chars or one two-byte char.
+        assert_eq!(dfa.count_strings_of_length(2, true), 128 * 128 + 1920);
+        assert_eq!(dfa.iter_strings(2, 1, true).next(), Some(vec![0, 0]));
+        assert_eq!(dfa.iter_strings(2, 100000, true).last(), Some(vec![0xDF, 0xBF]));
+    }
+
+    #[test]
+    fn iter() {
+        let v = |x: &[&str]| x.iter().map(|s| s.as_bytes().to_vec()).collect::<Vec<_>>();
+        assert_eq!(strings("b|ab|a", 10, 10), v(&["a", "b", "ab"]));
+        assert_eq!(strings("a*", 3, 10), v(&["", "a", "aa", "aaa"]));
+        assert_eq!(strings("a*", 100, 2), v(&["", "a"]));
+        assert_eq!(strings("[ab]{2}", 5, 3), v(&["aa", "ab", "ba"]));
+        assert!(strings(r"\btest\B\b", 10, 10).is_empty());
+    }
+
+    #[test]
+    fn iter_lazy() {
+        // There are far too many of these to list them all.
+        let first: Vec<_> = lang(".*").iter_strings(1000, usize::MAX, false).take(3).collect();
+        assert_eq!(first, vec![vec![], vec![0], vec![1]]);
+
+        let dfa = lang("[a-c]{2}x?|[0-9]+");
+        let all: Vec<_> = dfa.iter_strings(4, usize::MAX, false).collect();
+        let num = (0..5).map(|len| dfa.count_strings_of_length(len, false) as usize).sum();
+        assert_eq!(all.len(), num);
+        assert!(all.windows(2).all(|w| (w[0].len(), &w[0]) < (w[1].len(), &w[1])));
+    }
+
+    #[test]
+    fn sample_uniform() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let dfa = lang("a[bc]|[d-f]x|ghi");
+        assert_eq!(dfa.sample(&mut rng, 1, false), None);
+        assert_eq!(dfa.sample(&mut rng, 3, false), Some(b"ghi".to_vec()));
+
+        let mut freqs = HashMap::new();
+        for _ in 0..5000 {
+            *freqs.entry(dfa.sample(&mut rng, 2, false).unwrap()).or_insert(0) += 1;
+        }
+        assert_eq!(freqs.len(), 5);
+        assert!(freqs.values().all(|&f| f > 800 && f < 1200));
+    }
+
+    #[test]
+    fn sample_utf8() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        // Two bytes: either two ASCII chars or one two-byte char.
+        let dfa = lang(r"\x00{2}|[\x00-\x{10FFFF}]");
+        for _ in 0..100 {
+            let s = String::from_utf8(dfa.sample(&mut rng, 2, true).unwrap()).unwrap();
+            assert!(s == "\0\0" || s.chars().count() == 1);
+        }
+    }
+
+    #[test]
+    fn sample_chars() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        // The length counts chars, not bytes.
+        let dfa = char_lang("é{3}|a{4}");
+        assert_eq!(dfa.sample_chars(&mut rng, 3), Some("ééé".to_owned()));
+        assert_eq!(dfa.sample_chars(&mut rng, 4), Some("aaaa".to_owned()));
+        assert_eq!(dfa.sample_chars(&mut rng, 6), None);
+
+        let mut freqs = HashMap::new();
+        for _ in 0..4000 {
+            *freqs.entry(char_lang("[aé€😀]").sample_chars(&mut rng, 1).unwrap())
+                .or_insert(0) += 1;
+        }
+        assert_eq!(freqs.len(), 4);
+        assert!(freqs.values().all(|&f| f > 800 && f < 1200));
+
+        // Surrogates aren't chars, so they never come out.
+        let mut dfa = Dfa::<(), u32>::new();
+        let s0 = dfa.add_state(Accept::Never, None);
+        let s1 = dfa.add_state(Accept::AtEoi, Some(()));
+        dfa.set_transitions(s0, vec![(Range::new(0xD7FF, 0xE000), s1)].into_iter().collect());
+        dfa.init[Look::Boundary.as_usize()] = Some(s0);
+        for _ in 0..100 {
+            let s = dfa.sample_chars(&mut rng, 1).unwrap();
+            assert!(s == "\u{D7FF}" || s == "\u{E000}");
+        }
+    }
+
+    #[cfg(feature = "quickcheck")]
+    #[test]
+    fn match_gen() {
+        use dfa::MatchGen;
+        use quickcheck::StdGen;
+        use rand;
+
+        let gen = MatchGen::new(r"[αβ]+-\d").unwrap();
+        let mut g = StdGen::new(rand::thread_rng(), 10);
+        let re = Regex::new(r"^[αβ]+-\d$").unwrap();
+        for _ in 0..100 {
+            let s = gen.gen(&mut g).unwrap();
+            assert!(re.is_match(&s));
+            assert!(s.chars().count() <= 10);
+        }
+        assert_eq!(MatchGen::new(r"a{11}").unwrap().gen(&mut g), None);
+    }
+
+    // A date, for checking that we can generate arbitrary matches with quickcheck.
+    #[derive(Clone, Debug)]
+    struct Date(String);
+
+    lazy_static! {
+        static ref DATE: Dfa<()> = lang(r"[0-9]{4}-[0-9]{2}-[0-9]{2}");
+    }
+
+    impl Arbitrary for Date {
+        fn arbitrary<G: Gen>(g: &mut G) -> Date {
+            let bytes = DATE.sample(g, 10, true).unwrap();
+            Date(String::from_utf8(bytes).unwrap())
+        }
+    }
+
+    #[test]
+    fn sample_arbitrary() {
+        fn prop(d: Date) -> bool {
+            Regex::new(r"^\d{4}-\d{2}-\d{2}$").unwrap().is_match(&d.0)
+        }
+        quickcheck(prop as fn(_) -> _);
+    }
+}
@@ -0,0 +1,562 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Turning a `Dfa` back into a regular expression.
+//!
+//! We do this in two steps. First, we turn the byte-level `Dfa` into a char-level automaton: its
+//! states are the states of the `Dfa` that we can be in at a char boundary, and its transitions are
+//! labelled by classes of chars. Then we use the standard state elimination algorithm: we remove
+//! the states one by one, and every time we remove a state we replace the paths going through it
+//! by transitions labelled with regular expressions.
+//!
+//! The regular expressions that come out of state elimination tend to be huge, so we simplify them
+//! as we go (for example, by factoring out common prefixes and suffixes of alternations).
+
+use dfa::{Dfa, RetTrait};
+use nfa::StateIdx;
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::str;
+use utf8_ranges::{Utf8Range, Utf8Sequences};
+
+// A set of chars, as a sorted list of disjoint, non-adjacent ranges of codepoints.
+//
+// Since surrogates aren't chars, two ranges that are separated only by surrogates count as adjacent
+// (and so they are merged).
+type CharSet = Vec<(u32, u32)>;
+
+const MAX_CHAR: u32 = 0x10FFFF;
+const SURROGATE_START: u32 = 0xD800;
+const SURROGATE_END: u32 = 0xDFFF;
+
+fn normalize(mut ranges: Vec<(u32, u32)>) -> CharSet {
+    ranges.sort();
+    let mut ret: CharSet = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        if let Some(last) = ret.last_mut() {
+            let adjacent = start <= last.1 + 1
+                || (last.1 + 1 == SURROGATE_START && start == SURROGATE_END + 1);
+            if adjacent {
+                last.1 = max(last.1, end);
+                continue;
+            }
+        }
+        ret.push((start, end));
+    }
+    ret
+}
+
+fn negate(set: &CharSet) -> CharSet {
+    let mut ret = Vec::new();
+    let mut next = 0;
+    for &(start, end) in set {
+        if start > next {
+            ret.push((next, start - 1));
+        }
+        next = end + 1;
+    }
+    if next <= MAX_CHAR {
+        ret.push((next, MAX_CHAR));
+    }
+
+    // Surrogates aren't chars, so trim them off the ends of the ranges.
+    ret.into_iter()
+        .map(|(start, end)| {
+            let start = if start >= SURROGATE_START && start <= SURROGATE_END {
+                SURROGATE_END + 1
+            } else {
+                start
+            };
+            let end = if end >= SURROGATE_START && end <= SURROGATE_END {
+                SURROGATE_START - 1
+            } else {
+                end
+            };
+            (start, end)
+        })
+        .filter(|&(start, end)| start <= end)
+        .collect()
+}
+
+// A regular expression. The constructors below keep these in a simplified form.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Re {
+    // Matches nothing.
+    Nothing,
+    // Matches only the empty string.
+    Empty,
+    Class(CharSet),
+    Concat(Vec<Re>),
+    Alt(Vec<Re>),
+    Star(Box<Re>),
+    Plus(Box<Re>),
+    Opt(Box<Re>),
+}
+
+impl Re {
+    fn into_factors(self) -> Vec<Re> {
+        match self {
+            Re::Concat(v) => v,
+            Re::Empty => Vec::new(),
+            x => vec![x],
+        }
+    }
+
+    fn from_factors(mut v: Vec<Re>) -> Re {
+        match v.len() {
+            0 => Re::Empty,
+            1 => v.pop().unwrap(),
+            _ => Re::Concat(v),
+        }
+    }
+
+    fn concat(self, other: Re) -> Re {
+        if self == Re::Nothing || other == Re::Nothing {
+            return Re::Nothing;
+        }
+
+        let mut ret: Vec<Re> = self.into_factors();
+        for x in other.into_factors() {
+            // Turn `x x*` and `x* x` into `x+`, and `x* x*` into `x*`.
+            let merged = match (ret.last(), &x) {
+                (Some(&Re::Star(ref a)), &Re::Star(ref b)) if a == b => Some(Re::Star(a.clone())),
+                (Some(&Re::Star(ref a)), b) if **a == *b => Some(Re::Plus(a.clone())),
+                (Some(a), &Re::Star(ref b)) if *a == **b => Some(Re::Plus(b.clone())),
+                _ => None,
+            };
+            if let Some(m) = merged {
+                ret.pop();
+                ret.push(m);
+            } else {
+                ret.push(x);
+            }
+        }
+        Re::from_factors(ret)
+    }
+
+    fn alt(self, other: Re) -> Re {
+        Re::alt_all(vec![self, other])
+    }
+
+    fn alt_all(alts: Vec<Re>) -> Re {
+        let mut has_empty = false;
+        let mut class = Vec::new();
+        let mut items: Vec<Re> = Vec::new();
+        // This is a stack, so the alternatives go in backwards.
+        let mut todo = alts;
+        todo.reverse();
+        while let Some(x) = todo.pop() {
+            match x {
+                Re::Nothing => {},
+                Re::Empty => has_empty = true,
+                Re::Opt(x) => {
+                    has_empty = true;
+                    todo.push(*x);
+                },
+                Re::Alt(v) => todo.extend(v.into_iter().rev()),
+                Re::Class(c) => class.extend(c),
+                x => {
+                    if !items.contains(&x) {
+                        items.push(x);
+                    }
+                },
+            }
+        }
+        if !class.is_empty() {
+            items.insert(0, Re::Class(normalize(class)));
+        }
+
+        // If something already matches the empty string, we don't need to add it separately.
+        if items.iter().any(|x| match *x { Re::Star(_) => true, _ => false }) {
+            has_empty = false;
+        }
+
+        let items = Re::factor_prefixes(items);
+        let items = Re::factor_suffixes(items);
+        let ret = match items.len() {
+            0 => if has_empty { Re::Empty } else { Re::Nothing },
+            1 => items.into_iter().next().unwrap(),
+            _ => Re::Alt(items),
+        };
+        if has_empty { ret.opt() } else { ret }
+    }
+
+    // Rewrites `ab|ac` as `a(b|c)`.
+    fn factor_prefixes(items: Vec<Re>) -> Vec<Re> {
+        // Group the items by their first factor, keeping the groups in order of first appearance.
+        let mut groups: Vec<(Re, Vec<Re>)> = Vec::new();
+        for item in items {
+            let mut factors = item.into_factors();
+            let head = factors.remove(0);
+            let tail = Re::from_factors(factors);
+            if let Some(g) = groups.iter_mut().find(|g| g.0 == head) {
+                g.1.push(tail);
+                continue;
+            }
+            groups.push((head, vec![tail]));
+        }
+
+        groups.into_iter()
+            .map(|(head, tails)| {
+                if tails.len() == 1 {
+                    head.concat(tails.into_iter().next().unwrap())
+                } else {
+                    head.concat(Re::alt_all(tails))
+                }
+            })
+            .collect()
+    }
+
+    // Rewrites `ac|bc` as `(a|b)c`.
+    fn factor_suffixes(items: Vec<Re>) -> Vec<Re> {
+        let mut groups: Vec<(Re, Vec<Re>)> = Vec::new();
+        for item in items {
+            let mut factors = item.into_factors();
+            let last = factors.pop().unwrap();
+            let init = Re::from_factors(factors);
+            if let Some(g) = groups.iter_mut().find(|g| g.0 == last) {
+                g.1.push(init);
+                continue;
+            }
+            groups.push((last, vec![init]));
+        }
+
+        groups.into_iter()
+            .map(|(last, inits)| {
+                if inits.len() == 1 {
+                    inits.into_iter().next().unwrap().concat(last)
+                } else {
+                    Re::alt_all(inits).concat(last)
+                }
+            })
+            .collect()
+    }
+
+    fn star(self) -> Re {
+        match self {
+            Re::Nothing | Re::Empty => Re::Empty,
+            Re::Star(x) | Re::Plus(x) | Re::Opt(x) => Re::Star(x),
+            x => Re::Star(Box::new(x)),
+        }
+    }
+
+    fn opt(self) -> Re {
+        match self {
+            Re::Nothing | Re::Empty => Re::Empty,
+            Re::Plus(x) => Re::Star(x),
+            x @ Re::Star(_) | x @ Re::Opt(_) => x,
+            x => Re::Opt(Box::new(x)),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut ret = String::new();
+        self.write(&mut ret, 0);
+        ret
+    }
+
+    // Writes this regex to `out`. If `prec` is 1, the output needs to be something that can be
+    // concatenated; if it's 2, it needs to be something that can be an operand of a repetition
+    // operator; and if it's 3, it needs to be something that can be an operand of a repetition
+    // operator and isn't itself a repetition.
+    fn write(&self, out: &mut String, prec: u8) {
+        match *self {
+            Re::Nothing => out.push_str(r"\b\B"),
+            Re::Empty => {},
+            Re::Class(ref c) => write_class(c, out),
+            Re::Concat(ref v) => {
+                if prec > 1 { out.push_str("(?:"); }
+                for x in v {
+                    x.write(out, 2);
+                }
+                if prec > 1 { out.push_str(")"); }
+            },
+            Re::Alt(ref v) => {
+                if prec > 0 { out.push_str("(?:"); }
+                for (i, x) in v.iter().enumerate() {
+                    if i > 0 { out.push('|'); }
+                    x.write(out, 1);
+                }
+                if prec > 0 { out.push_str(")"); }
+            },
+            Re::Star(ref x) | Re::Plus(ref x) | Re::Opt(ref x) => {
+                if prec > 2 { out.push_str("(?:"); }
+                x.write(out, 3);
+                out.push(match *self { Re::Star(_) => '*', Re::Plus(_) => '+', _ => '?' });
+                if prec > 2 { out.push_str(")"); }
+            },
+        }
+    }
+}
+
+fn write_char(c: u32, out: &mut String, in_class: bool) {
+    let ch = ::std::char::from_u32(c).expect("BUG: not a char");
+    let special = if in_class { "\\[]^" } else { "\\.+*?()|[]{}^$#" };
+    if special.contains(ch) {
+        out.push('\\');
+        out.push(ch);
+    } else if ch.is_control() || (ch.is_whitespace() && ch != ' ') || (in_class && ch == '-') {
+        write!(out, "\\x{{{:X}}}", c).unwrap();
+    } else {
+        out.push(ch);
+    }
+}
+
+fn write_class(set: &CharSet, out: &mut String) {
+    if set.len() == 1 && set[0].0 == set[0].1 {
+        return write_char(set[0].0, out, false);
+    }
+
+    let neg = negate(set);
+    if neg.is_empty() {
+        return out.push_str("(?s:.)");
+    } else if neg == vec![(b'\n' as u32, b'\n' as u32)] {
+        return out.push('.');
+    }
+
+    out.push('[');
+    let ranges = if neg.len() < set.len() {
+        out.push('^');
+        &neg
+    } else {
+        set
+    };
+    for &(start, end) in ranges {
+        write_char(start, out, true);
+        if end > start + 1 {
+            out.push('-');
+        }
+        if end > start {
+            write_char(end, out, true);
+        }
+    }
+    out.push(']');
+}
+
+// Returns the set of chars whose UTF-8 encodings belong to the given sequence of byte ranges.
+fn chars_of(seq: &[Utf8Range]) -> CharSet {
+    let decode = |bytes: &[u8]| str::from_utf8(bytes).unwrap().chars().next().unwrap() as u32;
+    let full = |r: &Utf8Range| r.start == 0x80 && r.end == 0xBF;
+
+    // The bytes after `split` are unconstrained continuation bytes, so for every choice of the
+    // bytes before `split`, the chars form a single range.
+    let mut split = seq.len();
+    while split > 1 && full(&seq[split - 1]) {
+        split -= 1;
+    }
+
+    let mut ret = Vec::new();
+    let mut prefixes: Vec<Vec<u8>> = vec![Vec::new()];
+    for r in &seq[..(split - 1)] {
+        prefixes = prefixes.into_iter()
+            .flat_map(|p| (r.start as u32..(r.end as u32 + 1)).map(move |b| {
+                let mut p = p.clone();
+                p.push(b as u8);
+                p
+            }))
+            .collect();
+    }
+    for p in prefixes {
+        let mut lo = p.clone();
+        let mut hi = p;
+        lo.push(seq[split - 1].start);
+        hi.push(seq[split - 1].end);
+        for _ in split..seq.len() {
+            lo.push(0x80);
+            hi.push(0xBF);
+        }
+        ret.push((decode(&lo), decode(&hi)));
+    }
+    ret
+}
+
+// Adds `re` as an alternative to the transition from `src` to `tgt`.
+fn add_edge(out: &mut [BTreeMap<usize, Re>],
+            inc: &mut [BTreeSet<usize>],
+            src: usize,
+            tgt: usize,
+            re: Re) {
+    let old = out[src].remove(&tgt).unwrap_or(Re::Nothing);
+    out[src].insert(tgt, old.alt(re));
+    inc[tgt].insert(src);
+}
+
+impl<Ret: RetTrait> Dfa<Ret> {
+    // Finds all the char-level transitions out of a state at a char boundary, by following every
+    // UTF-8 sequence through the `Dfa`. Only states that can reach an accepting state are
+    // considered.
+    fn char_transitions(&self, state: StateIdx, live: &[bool]) -> BTreeMap<StateIdx, CharSet> {
+        fn follow<Ret: RetTrait>(dfa: &Dfa<Ret>,
+                                 state: StateIdx,
+                                 seq: &[Utf8Range],
+                                 path: &mut Vec<Utf8Range>,
+                                 live: &[bool],
+                                 out: &mut BTreeMap<StateIdx, Vec<(u32, u32)>>) {
+            let i = path.len();
+            for &(range, tgt) in dfa.transitions(state).ranges_values() {
+                let start = max(range.start, seq[i].start);
+                let end = min(range.end, seq[i].end);
+                if start > end || !live[tgt] {
+                    continue;
+                }
+
+                path.push(Utf8Range { start: start, end: end });
+                if i + 1 == seq.len() {
+                    out.entry(tgt).or_insert_with(Vec::new).extend(chars_of(path));
+                } else {
+                    follow(dfa, tgt, seq, path, live, out);
+                }
+                path.pop();
+            }
+        }
+
+        let mut ret = BTreeMap::new();
+        for seq in Utf8Sequences::new('\u{0}', '\u{10FFFF}') {
+            follow(self, state, seq.as_slice(), &mut Vec::new(), live, &mut ret);
+        }
+        ret.into_iter().map(|(tgt, chars)| (tgt, normalize(chars))).collect()
+    }
+
+    /// Returns a regular expression whose language is the same as that of this `Dfa`.
+    ///
+    /// Here, the language of a `Dfa` is the set of strings that it accepts at the end of the input
+    /// when starting from its `Look::Boundary` initial state. Strings that aren't valid UTF-8 are
+    /// ignored (since a regular expression can't match them anyway). That is, a string matches
+    /// the returned expression in its entirety if and only if it is valid UTF-8 and it belongs to
+    /// the language of this `Dfa`.
+    pub fn to_regex_string(&self) -> String {
+        let dfa = self.intersect(&Dfa::valid_utf8(None));
+        let live = dfa.coreachable_states();
+        let init = match dfa.init_at_start() {
+            Some(init) if live[init] => init,
+            _ => return Re::Nothing.render(),
+        };
+
+        // Find the char-level automaton. Its states are `0..n`, and we add an initial state `n`
+        // and a final state `n + 1`.
+        let n = dfa.num_states();
+        let (start, end) = (n, n + 1);
+        let mut out: Vec<BTreeMap<usize, Re>> = vec![BTreeMap::new(); n + 2];
+        let mut inc: Vec<BTreeSet<usize>> = vec![BTreeSet::new(); n + 2];
+
+        let mut boundary_states = vec![init];
+        let mut seen = vec![false; n];
+        seen[init] = true;
+        add_edge(&mut out, &mut inc, start, init, Re::Empty);
+        while let Some(s) = boundary_states.pop() {
+            if dfa.accept(s).accepts_at_eoi() {
+                add_edge(&mut out, &mut inc, s, end, Re::Empty);
+            }
+            for (tgt, chars) in dfa.char_transitions(s, &live) {
+                add_edge(&mut out, &mut inc, s, tgt, Re::Class(chars));
+                if !seen[tgt] {
+                    seen[tgt] = true;
+                    boundary_states.push(tgt);
+                }
+            }
+        }
+
+        // Eliminate the states, starting with the ones that create the fewest new transitions.
+        let mut remaining: Vec<usize> = (0..n).filter(|&s| seen[s]).collect();
+        while !remaining.is_empty() {
+            let (pos, q) = {
+                let cost = |s: usize| inc[s].len() * out[s].len();
+                let (pos, &q) = remaining.iter()
+                    .enumerate()
+                    .min_by_key(|&(_, &s)| cost(s))
+                    .unwrap();
+                (pos, q)
+            };
+            remaining.swap_remove(pos);
+
+            let loop_re = out[q].remove(&q).map_or(Re::Empty, |re| re.star());
+            inc[q].remove(&q);
+            let outs: Vec<(usize, Re)> = out[q].iter().map(|(&t, re)| (t, re.clone())).collect();
+            let ins: Vec<usize> = inc[q].iter().cloned().collect();
+            for &p in &ins {
+                let in_re = out[p].remove(&q).unwrap();
+                let prefix = in_re.concat(loop_re.clone());
+                for &(r, ref out_re) in &outs {
+                    add_edge(&mut out, &mut inc, p, r, prefix.clone().concat(out_re.clone()));
+                }
+            }
+            for &(r, _) in &outs {
+                inc[r].remove(&q);
+            }
+            out[q].clear();
+            inc[q].clear();
+        }
+
+        out[start].remove(&end).unwrap_or(Re::Nothing).render()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa::Dfa;
+    use look::Look;
+    use nfa::Accept;
+    use nfa::tests::re_nfa;
+    use range_map::Range;
+    use std::usize;
+
+    fn lang(re: &str) -> Dfa<()> {
+        re_nfa(re).byte_me(usize::MAX).unwrap().whole_match_dfa(usize::MAX).unwrap()
+    }
+
+    fn check_round_trip(re: &str) {
+        let out = lang(re).to_regex_string();
+        println!("{} -> {}", re, out);
+        assert_eq!(lang(&out).is_equivalent(&lang(re)), Ok(()));
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(lang("abc").to_regex_string(), "abc");
+        assert_eq!(lang("a|b").to_regex_string(), "[ab]");
+        assert_eq!(lang("ab|ac").to_regex_string(), "a[bc]");
+        assert_eq!(lang("ac|bc").to_regex_string(), "[ab]c");
+        assert_eq!(lang("a*").to_regex_string(), "a*");
+        assert_eq!(lang("aa*").to_regex_string(), "a+");
+        assert_eq!(lang("ab?").to_regex_string(), "ab?");
+        assert_eq!(lang("é|[α-ω]").to_regex_string(), "[éα-ω]");
+        assert_eq!(lang("[^a]").to_regex_string(), "[^a]");
+        assert_eq!(lang(".").to_regex_string(), ".");
+        assert_eq!(lang("").to_regex_string(), "");
+        assert_eq!(lang(r"\btest\B\b").to_regex_string(), r"\b\B");
+        assert_eq!(lang(r"a\.b").to_regex_string(), r"a\.b");
+        assert_eq!(lang(r"[\x2D\]]").to_regex_string(), r"[\x{2D}\]]");
+        assert_eq!(lang("(a*b)*").to_regex_string(), "(?:a*b)*");
+    }
+
+    #[test]
+    fn round_trip() {
+        check_round_trip("a(b|c)*d");
+        check_round_trip("[a-z]+@[a-z]+\\.com");
+        check_round_trip("(ab)*|c+");
+        check_round_trip("é+|ü?x");
+        check_round_trip("[^\\n]*");
+        check_round_trip("(?s).");
+        check_round_trip("[\\x{D000}-\\x{F000}]");
+        check_round_trip("((a|b)(c|d))*e?");
+        check_round_trip("\\w{2}");
+        check_round_trip("(a$|b)c?");
+    }
+
+    #[test]
+    fn ignores_invalid_utf8() {
+        // A `Dfa` accepting only the byte 0xFF, which isn't valid UTF-8.
+        let mut dfa = Dfa::<()>::new();
+        let s0 = dfa.add_state(Accept::Never, None);
+        let s1 = dfa.add_state(Accept::AtEoi, Some(()));
+        dfa.set_transitions(s0, vec![(Range::single(0xFF), s1)].into_iter().collect());
+        dfa.init[Look::Boundary.as_usize()] = Some(s0);
+        assert_eq!(dfa.to_regex_string(), r"\b\B");
+    }
+}
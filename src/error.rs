@@ -9,21 +9,33 @@
 use regex_syntax;
 use std::error;
 use std::fmt;
+use std::ops::Range;
 
 #[derive(Debug)]
 pub enum Error {
-    RegexSyntax(regex_syntax::Error),
+    /// The regex couldn't be parsed. `span` is the range of bytes in the regex where the problem
+    /// is.
+    RegexSyntax { msg: String, span: Range<usize> },
     TooManyStates,
     InvalidEngine(&'static str),
+    InvalidNfa(String),
+    InvalidGlob(String),
+    NotLatin1(String),
+    Unsupported(String),
 }
 
 use error::Error::*;
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            RegexSyntax(ref e) => write!(f, "Regex syntax error: {}", e),
+            RegexSyntax { ref msg, ref span } =>
+                write!(f, "Regex syntax error at {}..{}: {}", span.start, span.end, msg),
             TooManyStates => write!(f, "State overflow"),
             InvalidEngine(s) => write!(f, "Invalid engine: {}", s),
+            InvalidNfa(ref s) => write!(f, "Invalid NFA: {}", s),
+            InvalidGlob(ref s) => write!(f, "Invalid glob: {}", s),
+            NotLatin1(ref s) => write!(f, "Not Latin-1: {}", s),
+            Unsupported(ref s) => write!(f, "Unsupported: {}", s),
         }
     }
 }
@@ -31,16 +43,32 @@ impl fmt::Display for Error {
 impl error::Error for Error {
     fn description(&self) -> &str {
         match *self {
-            RegexSyntax(ref e) => e.description(),
+            RegexSyntax { .. } => "The regex could not be parsed.",
             TooManyStates => "This NFA required too many states to represent as a DFA.",
             InvalidEngine(_) => "The regex was not compatible with the requested engine.",
+            InvalidNfa(_) => "The NFA did not satisfy the required invariants.",
+            InvalidGlob(_) => "The glob pattern was malformed.",
+            NotLatin1(_) => "The regex needed chars that aren't in Latin-1.",
+            Unsupported(_) => "The regex used a feature that isn't supported.",
         }
     }
 }
 
 impl From<regex_syntax::Error> for Error {
     fn from(e: regex_syntax::Error) -> Error {
-        RegexSyntax(e)
+        use regex_syntax::ast::Span;
+
+        let offsets = |span: &Span| span.start.offset..span.end.offset;
+        let (msg, span) = match e {
+            regex_syntax::Error::Parse(ref e) => (e.kind().to_string(), offsets(e.span())),
+            regex_syntax::Error::Translate(ref e) => (e.kind().to_string(), offsets(e.span())),
+            // The parser's errors always come from one of the above, but its `Error` type
+            // is non-exhaustive.
+            ref e => (e.to_string(), 0..0),
+        };
+        RegexSyntax {
+            msg: msg,
+            span: span,
+        }
     }
 }
-
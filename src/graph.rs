@@ -6,7 +6,7 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use dfa::{Dfa, RetTrait};
+use dfa::{Dfa, RetTrait, TokTrait};
 use nfa::{Nfa, NoLooks, StateIdx};
 use num_traits::PrimInt;
 use std::collections::HashSet;
@@ -160,7 +160,7 @@ pub trait Graph {
     }
 }
 
-impl<T: RetTrait> Graph for Dfa<T> {
+impl<T: RetTrait, Tok: TokTrait> Graph for Dfa<T, Tok> {
     fn num_states(&self) -> usize {
         Dfa::num_states(self)
     }
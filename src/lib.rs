@@ -59,22 +59,22 @@ compiler plugin story stabilizes a bit.
 */
 
 #![cfg_attr(test, feature(test))]
-#[cfg(test)]
+#[cfg(any(test, feature = "quickcheck"))]
 extern crate quickcheck;
 
 #[cfg(test)]
 #[macro_use]
 extern crate matches;
 
-#[cfg(test)]
-extern crate rand;
-
 #[cfg(test)]
 extern crate test;
 
 extern crate itertools;
 extern crate memchr;
 extern crate num_traits;
+extern crate rand;
+#[cfg(feature = "parallel")]
+extern crate rayon;
 extern crate range_map;
 extern crate refinery;
 extern crate regex_syntax;
@@ -88,11 +88,16 @@ mod error;
 mod look;
 mod graph;
 pub mod nfa;
+#[cfg(feature = "parallel")]
+mod parallel;
 mod regex;
+mod regex16;
 mod runner;
 mod unicode;
 
 pub use error::Error;
-pub use regex::Regex;
+pub use look::Look;
+pub use regex::{FindIter, Regex};
+pub use regex16::Regex16;
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -12,12 +12,42 @@ use range_map::{Range, RangeSet};
 use std::cmp::Ordering;
 use unicode::PERLW;
 
+/// A condition on a single char of input, used for look-ahead and look-behind.
+///
+/// Apart from `Boundary`, each of these is a set of chars: `Full` is every char, `WordChar` and
+/// `NotWordChar` are the chars that are (or aren't) word chars for the purposes of `\b`, `NewLine`
+/// is the chars that end a line (`as_set` says `'\n'`, but see `nfa::LineTerminator`), and `Empty`
+/// is no chars at all. `AsciiWordChar` and `NotAsciiWordChar` are like `WordChar` and
+/// `NotWordChar`, but only ASCII chars count as word chars; they are used for ASCII word
+/// boundaries. `NonAsciiWordChar` is the set of word chars that aren't ASCII, which is what you get
+/// by intersecting `WordChar` and `NotAsciiWordChar`. Sets that include `Boundary` (which are
+/// `Full`, `NotWordChar`, `NotAsciiWordChar`, `NewLine`, `FinalNewLine` and `Boundary` itself) also
+/// match at the beginning or end of the input, and `Boundary` matches only there.
+///
+/// `FinalNewLine` is the look-ahead for `\Z`: it is like `NewLine`, except that the line
+/// terminator must be the last char of the input. Since a single char of look-ahead can't see
+/// that far, the look-ahead state that accepts after a `FinalNewLine` only accepts at the end of
+/// the input (see `nfa::State`). As far as sets of chars go, it is the same as `NewLine`.
+///
+/// The rest are only used when lines end with `"\r\n"` (see `nfa::LineTerminator::Crlf`). There,
+/// a `'\r'` only ends a line if it isn't followed by a `'\n'`, which is more than one char of
+/// look-behind can say. So a `'\r'` isn't part of `NewLine`; instead, the previous char being a
+/// `'\r'` is `CarriageReturn`, and `NotCarriageReturn`, `NotWordCharNotCr` and
+/// `NotAsciiWordCharNotCr` are `Full`, `NotWordChar` and `NotAsciiWordChar` without it.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Ord)]
 pub enum Look {
     Full,
     WordChar,
     NotWordChar,
     NewLine,
+    AsciiWordChar,
+    NotAsciiWordChar,
+    NonAsciiWordChar,
+    FinalNewLine,
+    CarriageReturn,
+    NotCarriageReturn,
+    NotWordCharNotCr,
+    NotAsciiWordCharNotCr,
     Boundary,
     Empty,
 }
@@ -28,19 +58,47 @@ lazy_static! {
         PERLW.iter().map(|&(x, y)| Range::new(x as u32, y as u32)).collect();
     static ref NOT_WORD_CHAR: RangeSet<u32> = WORD_CHAR.negated();
     static ref NEW_LINE: RangeSet<u32> = RangeSet::single('\n' as u32);
+    static ref ASCII_WORD_CHAR: RangeSet<u32> =
+        PERLW.iter().take_while(|&&(x, _)| x < '\u{80}')
+            .map(|&(x, y)| Range::new(x as u32, y as u32)).collect();
+    static ref NOT_ASCII_WORD_CHAR: RangeSet<u32> = ASCII_WORD_CHAR.negated();
+    static ref NON_ASCII_WORD_CHAR: RangeSet<u32> = WORD_CHAR.intersection(&NOT_ASCII_WORD_CHAR);
+    static ref CARRIAGE_RETURN: RangeSet<u32> = RangeSet::single('\r' as u32);
+    static ref NOT_CARRIAGE_RETURN: RangeSet<u32> = CARRIAGE_RETURN.negated();
+    static ref NOT_WORD_CHAR_NOT_CR: RangeSet<u32> =
+        NOT_WORD_CHAR.intersection(&NOT_CARRIAGE_RETURN);
+    static ref NOT_ASCII_WORD_CHAR_NOT_CR: RangeSet<u32> =
+        NOT_ASCII_WORD_CHAR.intersection(&NOT_CARRIAGE_RETURN);
     static ref EMPTY: RangeSet<u32> = RangeSet::new();
 }
 
-static ALL: [Look; 6] = [Look::Full, Look::WordChar, Look::NotWordChar,
-    Look::NewLine, Look::Boundary, Look::Empty];
+static ALL: [Look; 14] = [Look::Full, Look::WordChar, Look::NotWordChar,
+    Look::NewLine, Look::AsciiWordChar, Look::NotAsciiWordChar, Look::NonAsciiWordChar,
+    Look::FinalNewLine, Look::CarriageReturn, Look::NotCarriageReturn, Look::NotWordCharNotCr,
+    Look::NotAsciiWordCharNotCr, Look::Boundary, Look::Empty];
+
+// Every `Look` is a union of some of these disjoint pieces, so we can compute intersections by
+// representing each `Look` as a bit-set of pieces. A line terminator is split into two pieces,
+// depending on whether it is the last char of the input.
+const ASCII_WORD: u8 = 1;
+const NON_ASCII_WORD: u8 = 2;
+const NEW_LINE_PIECE: u8 = 4;
+const OTHER_NOT_WORD: u8 = 8;
+const BOUNDARY: u8 = 16;
+const FINAL_NEW_LINE_PIECE: u8 = 32;
+const CARRIAGE_RETURN_PIECE: u8 = 64;
+const NEW_LINE_PIECES: u8 = NEW_LINE_PIECE | FINAL_NEW_LINE_PIECE;
+const FULL_PIECES: u8 = ASCII_WORD | NON_ASCII_WORD | NEW_LINE_PIECES | OTHER_NOT_WORD | BOUNDARY
+    | CARRIAGE_RETURN_PIECE;
 
 impl PartialOrd for Look {
     fn partial_cmp(&self, other: &Look) -> Option<Ordering> {
+        let common = self.pieces() & other.pieces();
         if self == other {
             Some(Ordering::Equal)
-        } else if self.intersection(other) == *self {
+        } else if common == self.pieces() {
             Some(Ordering::Less)
-        } else if self.intersection(other) == *other {
+        } else if common == other.pieces() {
             Some(Ordering::Greater)
         } else {
             None
@@ -49,43 +107,48 @@ impl PartialOrd for Look {
 }
 
 impl Look {
-    pub fn intersection(&self, other: &Look) -> Look {
+    // Returns the pieces that make up this `Look`.
+    fn pieces(&self) -> u8 {
         use self::Look::*;
+
         match *self {
-            Full => *other,
-            WordChar => match *other {
-                Full => WordChar,
-                WordChar => WordChar,
-                _ => Empty,
-            },
-            NotWordChar => match *other {
-                Full => NotWordChar,
-                NotWordChar => NotWordChar,
-                NewLine => NewLine,
-                Boundary => Boundary,
-                _ => Empty,
-            },
-            NewLine => match *other {
-                Full => NewLine,
-                NotWordChar => NewLine,
-                NewLine => NewLine,
-                Boundary => Boundary,
-                _ => Empty,
-            },
-            Boundary => match *other {
-                WordChar => Empty,
-                Empty => Empty,
-                _ => Boundary,
-            },
-            Empty => Empty,
+            Full => FULL_PIECES,
+            WordChar => ASCII_WORD | NON_ASCII_WORD,
+            NotWordChar => NEW_LINE_PIECES | OTHER_NOT_WORD | BOUNDARY | CARRIAGE_RETURN_PIECE,
+            NewLine => NEW_LINE_PIECES | BOUNDARY,
+            AsciiWordChar => ASCII_WORD,
+            NotAsciiWordChar => FULL_PIECES & !ASCII_WORD,
+            NonAsciiWordChar => NON_ASCII_WORD,
+            FinalNewLine => FINAL_NEW_LINE_PIECE | BOUNDARY,
+            CarriageReturn => CARRIAGE_RETURN_PIECE,
+            NotCarriageReturn => FULL_PIECES & !CARRIAGE_RETURN_PIECE,
+            NotWordCharNotCr => NEW_LINE_PIECES | OTHER_NOT_WORD | BOUNDARY,
+            NotAsciiWordCharNotCr => FULL_PIECES & !ASCII_WORD & !CARRIAGE_RETURN_PIECE,
+            Boundary => BOUNDARY,
+            Empty => 0,
         }
     }
 
+    fn from_pieces(pieces: u8) -> Look {
+        // The set of `Look`s is closed under intersection, so this always finds something.
+        *ALL.iter().find(|look| look.pieces() == pieces)
+            .expect("BUG: the intersection of two Looks should be a Look")
+    }
+
+    pub fn intersection(&self, other: &Look) -> Look {
+        Look::from_pieces(self.pieces() & other.pieces())
+    }
+
+    /// Returns this `Look` without `CarriageReturn`.
+    pub fn without_carriage_return(&self) -> Look {
+        Look::from_pieces(self.pieces() & !CARRIAGE_RETURN_PIECE)
+    }
+
     pub fn supersets(&self) -> Vec<Look> {
         ALL.iter().cloned().filter(|x| *self <= *x).collect()
     }
 
-    pub fn as_set(&self) -> &RangeSet<u32> {
+    pub fn as_set(&self) -> &'static RangeSet<u32> {
         use self::Look::*;
 
         match *self {
@@ -93,22 +156,21 @@ impl Look {
             WordChar => &WORD_CHAR,
             NotWordChar => &NOT_WORD_CHAR,
             NewLine => &NEW_LINE,
+            AsciiWordChar => &ASCII_WORD_CHAR,
+            NotAsciiWordChar => &NOT_ASCII_WORD_CHAR,
+            NonAsciiWordChar => &NON_ASCII_WORD_CHAR,
+            FinalNewLine => &NEW_LINE,
+            CarriageReturn => &CARRIAGE_RETURN,
+            NotCarriageReturn => &NOT_CARRIAGE_RETURN,
+            NotWordCharNotCr => &NOT_WORD_CHAR_NOT_CR,
+            NotAsciiWordCharNotCr => &NOT_ASCII_WORD_CHAR_NOT_CR,
             Boundary => &EMPTY,
             Empty => &EMPTY,
         }
     }
 
     pub fn allows_eoi(&self) -> bool {
-        use self::Look::*;
-
-        match *self {
-            Full => true,
-            WordChar => false,
-            NotWordChar => true,
-            NewLine => true,
-            Boundary => true,
-            Empty => false,
-        }
+        self.pieces() & BOUNDARY != 0
     }
 
     pub fn is_full(&self) -> bool {
@@ -128,10 +190,18 @@ impl Look {
             NewLine => 3,
             Boundary => 4,
             Empty => 5,
+            AsciiWordChar => 6,
+            NotAsciiWordChar => 7,
+            NonAsciiWordChar => 8,
+            FinalNewLine => 9,
+            CarriageReturn => 10,
+            NotCarriageReturn => 11,
+            NotWordCharNotCr => 12,
+            NotAsciiWordCharNotCr => 13,
         }
     }
 
-    pub fn num() -> usize { 6 }
+    pub fn num() -> usize { 14 }
 
     pub fn all() -> &'static [Look] {
         &ALL
@@ -147,7 +217,9 @@ mod tests {
         fn arbitrary<G: Gen>(g: &mut G) -> Look {
             use look::Look::*;
 
-            *g.choose(&[Full, WordChar, NotWordChar, NewLine, Boundary, Empty]).unwrap()
+            *g.choose(&[Full, WordChar, NotWordChar, NewLine, AsciiWordChar, NotAsciiWordChar,
+                NonAsciiWordChar, FinalNewLine, CarriageReturn, NotCarriageReturn,
+                NotWordCharNotCr, NotAsciiWordCharNotCr, Boundary, Empty]).unwrap()
         }
     }
 
@@ -175,6 +247,45 @@ mod tests {
         quickcheck(prop as fn(_, _) -> _);
     }
 
+    #[test]
+    fn ascii_word_char() {
+        use look::Look::*;
+
+        assert_eq!(WordChar.intersection(&NotAsciiWordChar), NonAsciiWordChar);
+        assert_eq!(NotWordChar.intersection(&NotAsciiWordChar), NotWordChar);
+        assert_eq!(AsciiWordChar.supersets(),
+                   vec![Full, WordChar, AsciiWordChar, NotCarriageReturn]);
+        assert_eq!(NewLine.supersets(), vec![Full, NotWordChar, NewLine, NotAsciiWordChar,
+                   NotCarriageReturn, NotWordCharNotCr, NotAsciiWordCharNotCr]);
+        assert!(AsciiWordChar.as_set().ranges().all(|r| r.end < 0x80));
+    }
+
+    #[test]
+    fn final_new_line() {
+        use look::Look::*;
+
+        assert_eq!(NewLine.intersection(&FinalNewLine), FinalNewLine);
+        assert_eq!(NotWordChar.intersection(&FinalNewLine), FinalNewLine);
+        assert_eq!(WordChar.intersection(&FinalNewLine), Empty);
+        assert_eq!(Boundary.intersection(&FinalNewLine), Boundary);
+        assert_eq!(FinalNewLine.supersets(),
+                   vec![Full, NotWordChar, NewLine, NotAsciiWordChar, FinalNewLine,
+                        NotCarriageReturn, NotWordCharNotCr, NotAsciiWordCharNotCr]);
+    }
+
+    #[test]
+    fn carriage_return() {
+        use look::Look::*;
+
+        assert_eq!(NewLine.intersection(&CarriageReturn), Empty);
+        assert_eq!(Full.without_carriage_return(), NotCarriageReturn);
+        assert_eq!(NotWordChar.without_carriage_return(), NotWordCharNotCr);
+        assert_eq!(NotAsciiWordChar.without_carriage_return(), NotAsciiWordCharNotCr);
+        assert_eq!(NewLine.without_carriage_return(), NewLine);
+        assert_eq!(CarriageReturn.supersets(),
+                   vec![Full, NotWordChar, NotAsciiWordChar, CarriageReturn]);
+    }
+
     #[test]
     fn intersection_set() {
         fn prop(a: Look, b: Look) -> bool {
@@ -0,0 +1,377 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A checked interface for building `Nfa`s by hand, for when the automaton doesn't come from a
+//! regular expression string.
+
+use error::Error;
+use look::Look;
+use nfa::{Accept, HasLooks, Nfa, NoLooks, StateIdx};
+use num_traits::PrimInt;
+use range_map::Range;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+// A transition out of some state.
+#[derive(Clone, Debug)]
+enum Edge<Tok> {
+    Consuming(Range<Tok>, StateIdx),
+    Looking(Look, Look, StateIdx),
+}
+
+/// Builds an `Nfa` one state and one transition at a time.
+///
+/// The methods on `Nfa` for adding states and transitions don't check anything, and it's easy to
+/// use them to make an `Nfa` that breaks the invariants that the rest of this crate relies on.
+/// `NfaBuilder` checks everything in `build`, and returns an error if something is wrong.
+///
+/// There are two kinds of builders:
+///
+/// - `NfaBuilder<u32, HasLooks>` builds an `Nfa` that consumes `char`s and can have look
+///   transitions (like the ones made by `Nfa::from_regex`). State 0 is the initial state.
+/// - `NfaBuilder<Tok, NoLooks>` builds an `Nfa` that consumes tokens of type `Tok` and has
+///   explicit initial states. With `Tok = u8`, this is the kind of `Nfa` that `Regex::from_nfa`
+///   takes; other token types can be turned into a `Dfa` with `determinize`.
+///
+/// In both cases, the transitions out of each state are ordered: if there are several ways to
+/// match, the preferred one is the one that takes the transition that was added first.
+///
+/// # Example
+///
+/// ```rust
+/// extern crate range_map;
+/// extern crate regex_dfa;
+///
+/// use range_map::Range;
+/// use regex_dfa::{Look, Regex};
+/// use regex_dfa::nfa::{HasLooks, NfaBuilder};
+///
+/// # fn main() {
+/// // Matches "ab" or "ac" at the start of a word.
+/// let mut b = NfaBuilder::<u32, HasLooks>::new();
+/// let s0 = b.add_state(false);
+/// let s1 = b.add_state(false);
+/// let s2 = b.add_state(false);
+/// let s3 = b.add_state(true);
+/// b.add_look(s0, s1, Look::NotWordChar, Look::Full);
+/// b.add_transition(s1, s2, Range::single('a' as u32));
+/// b.add_transition(s2, s3, Range::new('b' as u32, 'c' as u32));
+///
+/// let nfa = b.build().unwrap().remove_looks().byte_me(std::usize::MAX).unwrap();
+/// let re = Regex::from_nfa(nfa).unwrap();
+/// assert_eq!(re.find("bab ac"), Some((4, 6)));
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct NfaBuilder<Tok, Variant> {
+    accept: Vec<Accept>,
+    // All the transitions, in the order that they were added.
+    edges: Vec<(StateIdx, Edge<Tok>)>,
+    init: Vec<(Look, StateIdx)>,
+    phantom: PhantomData<Variant>,
+}
+
+impl<Tok: Debug + PrimInt, Variant> NfaBuilder<Tok, Variant> {
+    pub fn new() -> NfaBuilder<Tok, Variant> {
+        NfaBuilder {
+            accept: Vec::new(),
+            edges: Vec::new(),
+            init: Vec::new(),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the number of states that have been added so far.
+    pub fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    /// Adds a transition that moves from `source` to `target` on consuming a token in `range`.
+    pub fn add_transition(&mut self, source: StateIdx, target: StateIdx, range: Range<Tok>) {
+        self.edges.push((source, Edge::Consuming(range, target)));
+    }
+
+    // Checks that all the transitions and initial states refer to states that exist, and that
+    // the ranges aren't empty.
+    fn check_edges(&self) -> ::Result<()> {
+        let len = self.accept.len();
+        for &(src, ref edge) in &self.edges {
+            let tgt = match *edge {
+                Edge::Consuming(range, tgt) => {
+                    if range.start > range.end {
+                        return Err(Error::InvalidNfa(
+                            format!("the transition from {} to {} has an empty range", src, tgt)));
+                    }
+                    tgt
+                },
+                Edge::Looking(_, _, tgt) => tgt,
+            };
+            if src >= len || tgt >= len {
+                return Err(Error::InvalidNfa(
+                    format!("there is a transition from {} to {}, but there are only {} states",
+                            src, tgt, len)));
+            }
+        }
+        if let Some(&(_, idx)) = self.init.iter().find(|pair| pair.1 >= len) {
+            return Err(Error::InvalidNfa(format!("the initial state {} doesn't exist", idx)));
+        }
+        Ok(())
+    }
+}
+
+impl NfaBuilder<u32, HasLooks> {
+    /// Adds a new state and returns its index. The first state to be added is the initial state.
+    pub fn add_state(&mut self, accepting: bool) -> StateIdx {
+        self.accept.push(if accepting { Accept::Always } else { Accept::Never });
+        self.accept.len() - 1
+    }
+
+    /// Adds a transition from `source` to `target` that doesn't consume any input.
+    ///
+    /// The transition can be taken if the previous char of input matches `behind` and the next
+    /// char of input matches `ahead`.
+    pub fn add_look(&mut self, source: StateIdx, target: StateIdx, behind: Look, ahead: Look) {
+        self.edges.push((source, Edge::Looking(behind, ahead, target)));
+    }
+
+    /// Adds a transition from `source` to `target` that doesn't consume any input and that can
+    /// always be taken.
+    pub fn add_eps(&mut self, source: StateIdx, target: StateIdx) {
+        self.add_look(source, target, Look::Full, Look::Full);
+    }
+
+    /// Builds the `Nfa`, or returns an error if the states and transitions don't make sense.
+    ///
+    /// Stopping at an accepting state has lower priority than taking any of the transitions out
+    /// of it.
+    pub fn build(&self) -> ::Result<Nfa<u32, HasLooks>> {
+        try!(self.check_edges());
+        if let Some(&(src, _)) = self.edges.iter().find(|&&(_, ref e)| match *e {
+            Edge::Consuming(range, _) => range.end > 0x10FFFF,
+            _ => false,
+        }) {
+            return Err(Error::InvalidNfa(
+                format!("a transition out of {} has a range that isn't made of chars", src)));
+        }
+
+        // `Nfa<u32, HasLooks>` has a rather rigid structure (see `check_invariants`), so we
+        // don't use our states directly. Instead, each of our states becomes a "hub" that only
+        // has look transitions. A consuming transition from `src` to `tgt` becomes a pair of
+        // states with a consuming transition between them, connected to the hubs of `src` and
+        // `tgt` with eps transitions.
+        let hub = |s: StateIdx| s + 1;
+        let mut ret: Nfa<u32, HasLooks> =
+            Nfa::with_capacity(self.accept.len() + 2 * self.edges.len() + 2);
+        ret.add_state(Accept::Never);
+        for _ in &self.accept {
+            ret.add_state(Accept::Never);
+        }
+        if !self.accept.is_empty() {
+            ret.add_eps(0, hub(0));
+        }
+
+        for &(src, ref edge) in &self.edges {
+            match *edge {
+                Edge::Consuming(range, tgt) => {
+                    let idx = ret.add_state(Accept::Never);
+                    ret.add_state(Accept::Never);
+                    ret.add_transition(idx, idx + 1, range);
+                    ret.add_eps(hub(src), idx);
+                    ret.add_eps(idx + 1, hub(tgt));
+                },
+                Edge::Looking(behind, ahead, tgt) => {
+                    ret.add_look(hub(src), hub(tgt), behind, ahead);
+                },
+            }
+        }
+
+        let final_idx = ret.add_state(Accept::Always);
+        for (idx, &acc) in self.accept.iter().enumerate() {
+            if acc == Accept::Always {
+                ret.add_eps(hub(idx), final_idx);
+            }
+        }
+
+        try!(ret.check_invariants());
+        Ok(ret)
+    }
+}
+
+impl<Tok: Debug + PrimInt> NfaBuilder<Tok, NoLooks> {
+    /// Adds a new state and returns its index.
+    ///
+    /// `accept` says when the new state is accepting; `Accept::NotAtEoi` is not allowed.
+    pub fn add_state(&mut self, accept: Accept) -> StateIdx {
+        self.accept.push(accept);
+        self.accept.len() - 1
+    }
+
+    /// Adds an initial state.
+    ///
+    /// The `Nfa` starts in `state` whenever the previous char of input matches `look`. Initial
+    /// states that are added earlier have higher priority.
+    pub fn add_init(&mut self, look: Look, state: StateIdx) {
+        self.init.push((look, state));
+    }
+
+    /// Builds the `Nfa`, or returns an error if the states and transitions don't make sense.
+    pub fn build(&self) -> ::Result<Nfa<Tok, NoLooks>> {
+        try!(self.check_edges());
+
+        let mut ret: Nfa<Tok, NoLooks> = Nfa::with_capacity(self.accept.len());
+        for &acc in &self.accept {
+            ret.add_state(acc);
+        }
+        for &(src, ref edge) in &self.edges {
+            if let Edge::Consuming(range, tgt) = *edge {
+                ret.add_transition(src, tgt, range);
+            }
+        }
+        ret.init = self.init.clone();
+
+        try!(ret.check_invariants());
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use look::Look;
+    use nfa::{Accept, HasLooks, Nfa, NoLooks};
+    use nfa::builder::NfaBuilder;
+    use range_map::Range;
+    use regex::Regex;
+    use std::usize;
+
+    fn char_range(start: char, end: char) -> Range<u32> {
+        Range::new(start as u32, end as u32)
+    }
+
+    fn has_looks_regex(b: &NfaBuilder<u32, HasLooks>) -> Regex {
+        let nfa = b.build().unwrap().remove_looks().byte_me(usize::MAX).unwrap();
+        Regex::from_nfa(nfa).unwrap()
+    }
+
+    #[test]
+    fn has_looks() {
+        // Matches "a+\b".
+        let mut b = NfaBuilder::<u32, HasLooks>::new();
+        let s0 = b.add_state(false);
+        let s1 = b.add_state(false);
+        let s2 = b.add_state(true);
+        b.add_transition(s0, s1, char_range('a', 'a'));
+        b.add_transition(s1, s1, char_range('a', 'a'));
+        b.add_look(s1, s2, Look::WordChar, Look::NotWordChar);
+
+        let re = has_looks_regex(&b);
+        assert_eq!(re.find("aab aa"), Some((4, 6)));
+        assert_eq!(re.find("aab"), None);
+    }
+
+    #[test]
+    fn has_looks_priority() {
+        // Matches "a+?", since stopping is preferred to continuing.
+        let mut b = NfaBuilder::<u32, HasLooks>::new();
+        let s0 = b.add_state(false);
+        let s1 = b.add_state(false);
+        let s2 = b.add_state(true);
+        b.add_transition(s0, s1, char_range('a', 'a'));
+        b.add_eps(s1, s2);
+        b.add_eps(s1, s0);
+        assert_eq!(has_looks_regex(&b).find("aaa"), Some((0, 1)));
+
+        // Matches "a+".
+        let mut b = NfaBuilder::<u32, HasLooks>::new();
+        let s0 = b.add_state(false);
+        let s1 = b.add_state(false);
+        let s2 = b.add_state(true);
+        b.add_transition(s0, s1, char_range('a', 'a'));
+        b.add_eps(s1, s0);
+        b.add_eps(s1, s2);
+        assert_eq!(has_looks_regex(&b).find("aaa"), Some((0, 3)));
+    }
+
+    #[test]
+    fn has_looks_empty() {
+        let b = NfaBuilder::<u32, HasLooks>::new();
+        assert!(b.build().unwrap().remove_looks().is_empty());
+    }
+
+    #[test]
+    fn no_looks() {
+        // Matches "[a-c]x$", but only at the beginning of the input.
+        let mut b = NfaBuilder::<u8, NoLooks>::new();
+        let s0 = b.add_state(Accept::Never);
+        let s1 = b.add_state(Accept::Never);
+        let s2 = b.add_state(Accept::AtEoi);
+        b.add_init(Look::Boundary, s0);
+        b.add_transition(s0, s1, Range::new(b'a', b'c'));
+        b.add_transition(s1, s2, Range::single(b'x'));
+
+        let re = Regex::from_nfa(b.build().unwrap()).unwrap();
+        assert_eq!(re.find("bx"), Some((0, 2)));
+        assert_eq!(re.find(" bx"), None);
+        assert_eq!(re.find("bxx"), None);
+    }
+
+    #[test]
+    fn no_looks_unreachable() {
+        // There's an accepting state, but no initial state.
+        let mut b = NfaBuilder::<u8, NoLooks>::new();
+        b.add_state(Accept::Always);
+
+        let re = Regex::from_nfa(b.build().unwrap()).unwrap();
+        assert_eq!(re.find("anything"), None);
+    }
+
+    #[test]
+    fn errors() {
+        let mut b = NfaBuilder::<u8, NoLooks>::new();
+        let s0 = b.add_state(Accept::Never);
+        b.add_transition(s0, 1, Range::single(b'a'));
+        assert!(b.build().is_err());
+        b.add_state(Accept::Always);
+        assert!(b.build().is_ok());
+        b.add_init(Look::Full, 2);
+        assert!(b.build().is_err());
+
+        let mut b = NfaBuilder::<u8, NoLooks>::new();
+        let s0 = b.add_state(Accept::NotAtEoi);
+        b.add_init(Look::Full, s0);
+        assert!(b.build().is_err());
+
+        let mut b = NfaBuilder::<u8, NoLooks>::new();
+        let s0 = b.add_state(Accept::Always);
+        b.add_transition(s0, s0, Range { start: b'z', end: b'a' });
+        assert!(b.build().is_err());
+
+        let mut b = NfaBuilder::<u32, HasLooks>::new();
+        let s0 = b.add_state(true);
+        b.add_transition(s0, s0, Range::new(0, 0x110000));
+        assert!(b.build().is_err());
+
+        let mut b = NfaBuilder::<u32, HasLooks>::new();
+        let s0 = b.add_state(true);
+        b.add_eps(s0, 1);
+        assert!(b.build().is_err());
+    }
+
+    #[test]
+    fn check_invariants() {
+        let nfa = Nfa::from_regex(r"a\b|b*").unwrap();
+        assert!(nfa.check_invariants().is_ok());
+        let nfa = nfa.remove_looks();
+        assert!(nfa.check_invariants().is_ok());
+        let nfa = nfa.byte_me(usize::MAX).unwrap();
+        assert!(nfa.check_invariants().is_ok());
+
+        let mut nfa = Nfa::from_regex("a").unwrap();
+        nfa.states[0].accept = Accept::Always;
+        assert!(nfa.check_invariants().is_err());
+    }
+}
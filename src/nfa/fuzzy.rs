@@ -0,0 +1,238 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Approximate matching, with a bounded number of edits.
+//!
+//! We make a Levenshtein-style product automaton: if the original `Nfa` has `n` states and we
+//! allow up to `k` edits, the new `Nfa` has `k + 1` layers of `n` states each. Being in state `i`
+//! of layer `l` means being in state `i` of the original `Nfa`, having made `l` edits so far.
+//! Consuming a char moves within a layer if it is a transition of the original `Nfa`. Inserting
+//! (consuming any char and staying in the same state) or substituting (consuming any char and
+//! following a transition of the original `Nfa`) moves to the next layer.
+//!
+//! Deleting a char means following a transition of the original `Nfa` without consuming anything.
+//! Since we work with an `Nfa<_, NoLooks>`, which doesn't have non-consuming transitions, we
+//! remove them on the fly: every state gets the transitions (and the acceptance) of every state
+//! that it can reach by deletions.
+
+use nfa::{Accept, Nfa, NoLooks, StateIdx};
+use range_map::Range;
+
+impl Nfa<u32, NoLooks> {
+    /// Returns an `Nfa` matching every string that is at most `max_edits` edits away from a
+    /// string that this `Nfa` matches. An edit means inserting, deleting or substituting a single
+    /// char.
+    ///
+    /// If this `Nfa` has `n` states, state `l * n + i` of the returned `Nfa` corresponds to state
+    /// `i` of this one, after making `l` edits.
+    ///
+    /// Look-around is never edited: for example, `\bfoo` with one edit won't match `afoo`.
+    pub fn fuzzy(&self, max_edits: u32) -> Nfa<u32, NoLooks> {
+        let mut ret = self.fuzzy_layers(max_edits, false);
+        ret.init = self.init.clone();
+        ret
+    }
+
+    /// Like `fuzzy`, but laid out so that the reversal of the returned `Nfa` can tell how many
+    /// edits a match needed.
+    ///
+    /// Every layer gets a copy of the initial states, but only the last layer accepts: a match
+    /// that starts in layer `l` makes exactly `max_edits - l` edits. After reversing (and
+    /// possibly calling `byte_me` first), the `accept_state` of an accepting state is one of
+    /// these initial states, so `determinize_weighted` can find the smallest number of edits.
+    pub fn fuzzy_reversible(&self, max_edits: u32) -> Nfa<u32, NoLooks> {
+        let n = self.num_states();
+        let mut ret = self.fuzzy_layers(max_edits, true);
+        for layer in 0..(max_edits as usize + 1) {
+            ret.init.extend(self.init.iter().map(|&(look, i)| (look, layer * n + i)));
+        }
+        ret
+    }
+
+    // Makes the states and transitions of `fuzzy` (if `last_layer_only` is false) or
+    // `fuzzy_reversible` (if it is true), but not the initial states.
+    fn fuzzy_layers(&self, max_edits: u32, last_layer_only: bool) -> Nfa<u32, NoLooks> {
+        let n = self.num_states();
+        let layers = max_edits as usize + 1;
+        let mut ret = Nfa::with_capacity(n * layers);
+
+        for layer in 0..layers {
+            let accepting = !last_layer_only || layer + 1 == layers;
+            for st in &self.states {
+                let idx = ret.add_state(if accepting { st.accept } else { Accept::Never });
+                if accepting {
+                    ret.states[idx].accept_look = st.accept_look;
+                    ret.states[idx].accept_tokens = st.accept_tokens;
+                    ret.states[idx].accept_state = layer * n + st.accept_state;
+                }
+            }
+        }
+
+        for layer in 0..layers {
+            for i in 0..n {
+                let src = layer * n + i;
+                // The token that leads to a look-ahead state isn't part of the match, so we don't
+                // allow editing it, or anything after it.
+                if self.is_look_ahead(i) {
+                    for &(range, tgt) in self.states[i].consuming.ranges_values() {
+                        ret.add_transition(src, layer * n + tgt, range);
+                    }
+                    continue;
+                }
+
+                let closure = self.deletion_closure(i, layer, layers);
+                for &(j, l) in &closure {
+                    for &(range, tgt) in self.states[j].consuming.ranges_values() {
+                        ret.add_transition(src, l * n + tgt, range);
+                    }
+                }
+
+                let mut edited = Vec::new();
+                for &(j, l) in closure.iter().filter(|&&(_, l)| l + 1 < layers) {
+                    edited.push((l + 1) * n + j);
+                    edited.extend(self.states[j].consuming.ranges_values()
+                                  .filter(|&&(_, tgt)| !self.is_look_ahead(tgt))
+                                  .map(|&(_, tgt)| (l + 1) * n + tgt));
+                }
+                let mut seen = vec![false; n * layers];
+                for tgt in edited {
+                    if !seen[tgt] {
+                        seen[tgt] = true;
+                        ret.add_transition(src, tgt, Range::full());
+                    }
+                }
+
+                // If we can reach an accepting state by deleting, then we accept here too. The
+                // closure is ordered by the number of deletions, so we take the cheapest one.
+                // (When only the last layer accepts, a path with fewer edits also gets there, by
+                // starting in a later layer.) Since deletions don't consume anything, the match
+                // really ends here.
+                let acc = closure.iter()
+                    .filter(|&&(_, l)| !last_layer_only || l + 1 == layers)
+                    .find(|&&(j, _)| self.states[j].accept != Accept::Never);
+                if let Some(&(j, _)) = acc {
+                    let st = &self.states[j];
+                    ret.states[src].accept = st.accept;
+                    ret.states[src].accept_look = st.accept_look;
+                    ret.states[src].accept_tokens = st.accept_tokens;
+                    ret.states[src].accept_state = src;
+                }
+            }
+        }
+        ret
+    }
+
+    fn is_look_ahead(&self, state: StateIdx) -> bool {
+        self.states[state].accept_tokens > 0
+    }
+
+    // Returns all the pairs `(state, layer)` that we can reach from `state` in layer `layer` by
+    // deleting chars, in order of increasing layer. For each state, we only return the smallest
+    // layer in which it can be reached.
+    fn deletion_closure(&self, state: StateIdx, layer: usize, layers: usize)
+    -> Vec<(StateIdx, usize)> {
+        let mut seen = vec![false; self.num_states()];
+        let mut ret = vec![(state, layer)];
+        seen[state] = true;
+
+        let mut next = 0;
+        while next < ret.len() {
+            let (i, l) = ret[next];
+            next += 1;
+            if l + 1 >= layers {
+                continue;
+            }
+            for &(_, tgt) in self.states[i].consuming.ranges_values() {
+                if !seen[tgt] && !self.is_look_ahead(tgt) {
+                    seen[tgt] = true;
+                    ret.push((tgt, l + 1));
+                }
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use look::Look;
+    use nfa::Nfa;
+    use runner::program::Program;
+    use std::usize;
+
+    // Finds the smallest number of edits needed for `re` to match `s[start..end]`, the same way
+    // that `Regex::find_with_edits` does: by running the reversed `Nfa` backwards from `end`.
+    fn edits_at(re: &str, max_edits: u32, s: &str, start: usize, end: usize) -> Option<u32> {
+        let nfa = Nfa::from_regex(re).unwrap().remove_looks();
+        let n = nfa.num_states();
+        let rev = nfa.fuzzy_reversible(max_edits).byte_me(usize::MAX).unwrap()
+            .reverse(usize::MAX).unwrap();
+        let dfa = rev.determinize_weighted(true, usize::MAX, |i| max_edits - (i / n) as u32)
+            .unwrap();
+        let init = match dfa.init_state(Look::Full) {
+            Some(init) => init,
+            None => return None,
+        };
+        dfa.compile()
+            .longest_backward_find_from(s.as_bytes(), start, end, init, |p, ret| {
+                p == start && ret.1 == 0
+            })
+            .map(|(_, (_, _, edits))| edits)
+    }
+
+    fn edits(re: &str, max_edits: u32, s: &str) -> Option<u32> {
+        edits_at(re, max_edits, s, 0, s.len())
+    }
+
+    #[test]
+    fn exact() {
+        assert_eq!(edits("invoice", 0, "invoice"), Some(0));
+        assert_eq!(edits("invoice", 0, "invoise"), None);
+        assert_eq!(edits("invoice", 2, "invoice"), Some(0));
+    }
+
+    #[test]
+    fn edit_kinds() {
+        // Substitution, insertion and deletion.
+        assert_eq!(edits("invoice", 1, "invoise"), Some(1));
+        assert_eq!(edits("invoice", 1, "invoicce"), Some(1));
+        assert_eq!(edits("invoice", 1, "invice"), Some(1));
+        assert_eq!(edits("invoice", 1, "nvoice"), Some(1));
+        assert_eq!(edits("invoice", 1, "invoic"), Some(1));
+        assert_eq!(edits("invoice", 1, "xinvoice"), Some(1));
+        assert_eq!(edits("invoice", 1, "invoicex"), Some(1));
+        assert_eq!(edits("invoice", 1, "imvoise"), None);
+        assert_eq!(edits("invoice", 2, "imvoise"), Some(2));
+    }
+
+    #[test]
+    fn multibyte() {
+        assert_eq!(edits("café", 1, "cafe"), Some(1));
+        assert_eq!(edits("cafe", 1, "café"), Some(1));
+        assert_eq!(edits("a", 1, "é"), Some(1));
+        assert_eq!(edits("ab", 1, "aéb"), Some(1));
+    }
+
+    #[test]
+    fn regex() {
+        assert_eq!(edits("ab+c", 1, "abbbd"), Some(1));
+        assert_eq!(edits("ab+c", 1, "ac"), Some(1));
+        assert_eq!(edits("a|bcd", 1, "bd"), Some(1));
+        assert_eq!(edits("", 2, "ab"), Some(2));
+        assert_eq!(edits("ab", 2, ""), Some(2));
+    }
+
+    #[test]
+    fn sub_match() {
+        assert_eq!(edits_at("bcd", 1, "abxdd", 1, 4), Some(1));
+        assert_eq!(edits_at("bcd", 1, "abcdd", 1, 4), Some(0));
+        assert_eq!(edits_at("bcd", 1, "abcdd", 1, 3), Some(1));
+        assert_eq!(edits_at("bcd", 1, "abcdd", 1, 5), Some(1));
+        assert_eq!(edits_at("bcd", 1, "abxxd", 1, 5), None);
+    }
+}
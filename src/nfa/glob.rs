@@ -0,0 +1,436 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parsing shell-style glob patterns.
+//!
+//! Rather than building an `Nfa` directly, we translate the glob into a `regex_syntax::hir::Hir`.
+//! That way, `Nfa::from_glob` builds its states in the same way as `Nfa::from_regex`, once the
+//! regex has been parsed.
+
+use error::Error;
+use range_map::{Range, RangeSet};
+use regex_syntax::hir::{Class, ClassUnicode, ClassUnicodeRange, Hir, Look, Repetition};
+use std::char;
+use std::cmp::{max, min};
+
+/// Options for `Nfa::from_glob`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GlobOptions {
+    /// The chars that separate the components of a path. The wildcards `*` and `?` (and also
+    /// classes like `[a-z]`) never match a separator, but `**` does.
+    pub separators: Vec<char>,
+    /// If true, letters in the glob match both their lower- and upper-case versions.
+    pub case_insensitive: bool,
+}
+
+impl Default for GlobOptions {
+    fn default() -> GlobOptions {
+        GlobOptions {
+            separators: vec!['/'],
+            case_insensitive: false,
+        }
+    }
+}
+
+/// Translates a glob into a `Hir` that matches the same strings.
+///
+/// The `Hir` is anchored at both ends, since a glob has to match the whole string.
+pub fn parse(glob: &str, options: &GlobOptions) -> ::Result<Hir> {
+    let mut parser = Parser {
+        chars: glob.chars().collect(),
+        pos: 0,
+        options: options,
+    };
+    let body = try!(parser.parse_seq(false));
+    Ok(Hir::concat(vec![Hir::look(Look::Start), body, Hir::look(Look::End)]))
+}
+
+fn invalid<T>(msg: &str) -> ::Result<T> {
+    Err(Error::InvalidGlob(msg.to_owned()))
+}
+
+// Converts a set of code points into a class, leaving out anything that isn't a char.
+fn unicode_class(set: &RangeSet<u32>) -> ClassUnicode {
+    // The code points that aren't chars are the surrogates and everything after char::MAX.
+    let valid = [(0, 0xD7FF), (0xE000, char::MAX as u32)];
+    let mut ranges = Vec::new();
+    for r in set.ranges() {
+        for &(start, end) in &valid {
+            let (start, end) = (max(r.start, start), min(r.end, end));
+            if start <= end {
+                ranges.push(ClassUnicodeRange::new(char::from_u32(start).unwrap(),
+                                                   char::from_u32(end).unwrap()));
+            }
+        }
+    }
+    ClassUnicode::new(ranges)
+}
+
+fn set_to_class(set: &RangeSet<u32>) -> Hir {
+    Hir::class(Class::Unicode(unicode_class(set)))
+}
+
+// Adds to `set` all the chars that are the same as one of its chars up to simple case folding.
+//
+// This uses the same Unicode data as `(?i)` in a regex, so that the two agree.
+fn case_fold(set: &RangeSet<u32>) -> RangeSet<u32> {
+    let mut class = unicode_class(set);
+    class.case_fold_simple();
+    class.iter().map(|r| Range::new(r.start() as u32, r.end() as u32)).collect()
+}
+
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    options: &'a GlobOptions,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let ret = self.peek();
+        self.pos += 1;
+        ret
+    }
+
+    // Returns the next char, interpreting a backslash as an escape.
+    fn next_escaped(&mut self) -> ::Result<Option<char>> {
+        match self.next() {
+            Some('\\') => match self.next() {
+                None => invalid("the glob ends with an unescaped backslash"),
+                c => Ok(c),
+            },
+            c => Ok(c),
+        }
+    }
+
+    fn is_separator(&self, c: char) -> bool {
+        self.options.separators.contains(&c)
+    }
+
+    fn separators(&self) -> RangeSet<u32> {
+        self.options.separators.iter().map(|&c| Range::single(c as u32)).collect()
+    }
+
+    // Matches any single char that isn't a separator.
+    fn non_separator(&self) -> Hir {
+        set_to_class(&self.separators().negated())
+    }
+
+    fn repeat(&self, h: Hir, max: Option<u32>) -> Hir {
+        Hir::repetition(Repetition { min: 0, max: max, greedy: true, sub: Box::new(h) })
+    }
+
+    // Matches `lit`, ignoring case if the options say so.
+    fn literal(&self, lit: &[char]) -> Hir {
+        if self.options.case_insensitive {
+            Hir::concat(lit.iter().map(|&c| set_to_class(&case_fold(&RangeSet::single(c as u32))))
+                .collect())
+        } else {
+            Hir::literal(lit.iter().cloned().collect::<String>().into_bytes())
+        }
+    }
+
+    // Parses a sequence of glob items. If `in_braces` is true, the sequence is one of the
+    // alternatives in `{a,b}`, so it stops (without consuming) at `,` or `}`.
+    fn parse_seq(&mut self, in_braces: bool) -> ::Result<Hir> {
+        let mut items = Vec::new();
+        let mut lit = Vec::new();
+
+        loop {
+            let c = match self.peek() {
+                None if in_braces => return invalid("unclosed '{'"),
+                None => break,
+                Some(c) => c,
+            };
+            if in_braces && (c == ',' || c == '}') {
+                break;
+            }
+            if c != '*' && c != '?' && c != '[' && c != '{' {
+                lit.push(try!(self.next_escaped()).unwrap());
+                continue;
+            }
+
+            if !lit.is_empty() {
+                items.push(self.literal(&lit));
+                lit.clear();
+            }
+            match c {
+                '*' => items.push(self.parse_star()),
+                '?' => {
+                    self.next();
+                    items.push(self.non_separator());
+                },
+                '[' => items.push(try!(self.parse_class())),
+                _ => items.push(try!(self.parse_braces())),
+            }
+        }
+
+        if !lit.is_empty() {
+            items.push(self.literal(&lit));
+        }
+        Ok(Hir::concat(items))
+    }
+
+    // Parses `*` or `**`.
+    //
+    // A single `*` matches any number of non-separators. `**` also matches separators. If `**`
+    // makes up a whole path component and is followed by a separator (as in `a/**/b`), then that
+    // separator is optional, so that `a/**/b` matches `a/b` too.
+    fn parse_star(&mut self) -> Hir {
+        let start = self.pos;
+        while self.peek() == Some('*') {
+            self.next();
+        }
+        if self.pos - start == 1 {
+            return self.repeat(self.non_separator(), None);
+        }
+
+        let component_start = start == 0 || self.is_separator(self.chars[start - 1]);
+        let any = self.repeat(set_to_class(&RangeSet::full()), None);
+        match self.peek() {
+            Some(c) if component_start && self.is_separator(c) => {
+                self.next();
+                let sep = set_to_class(&self.separators());
+                self.repeat(Hir::concat(vec![any, sep]), Some(1))
+            },
+            _ => any,
+        }
+    }
+
+    // Parses a class like `[a-z]` or `[!abc]`.
+    fn parse_class(&mut self) -> ::Result<Hir> {
+        self.next();
+        let negated = match self.peek() {
+            Some('!') | Some('^') => { self.next(); true },
+            _ => false,
+        };
+
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            // A `]` right at the beginning is a literal, not the end of the class.
+            if !first && self.peek() == Some(']') {
+                self.next();
+                break;
+            }
+            first = false;
+
+            let start = match try!(self.next_escaped()) {
+                None => return invalid("unclosed '['"),
+                Some(c) => c,
+            };
+            let end = if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                self.next();
+                match try!(self.next_escaped()) {
+                    None => return invalid("unclosed '['"),
+                    Some(c) => c,
+                }
+            } else {
+                start
+            };
+            if end < start {
+                return invalid("a range in a class has its end before its start");
+            }
+            ranges.push(Range::new(start as u32, end as u32));
+        }
+
+        let mut set: RangeSet<u32> = ranges.into_iter().collect();
+        if self.options.case_insensitive {
+            set = case_fold(&set);
+        }
+        if negated {
+            set = set.negated();
+        }
+        let set = set.intersection(&self.separators().negated());
+        Ok(set_to_class(&set))
+    }
+
+    // Parses an alternation like `{a,b}`.
+    fn parse_braces(&mut self) -> ::Result<Hir> {
+        self.next();
+        let mut alts = vec![try!(self.parse_seq(true))];
+        while self.next() == Some(',') {
+            alts.push(try!(self.parse_seq(true)));
+        }
+        // `parse_seq` only stops at `,` or `}`, so we just consumed a `}`.
+        Ok(Hir::alternation(alts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nfa::{GlobOptions, HasLooks, Nfa};
+    use regex::Regex;
+    use std::usize;
+
+    fn to_regex(nfa: Nfa<u32, HasLooks>) -> Regex {
+        Regex::from_nfa(nfa.remove_looks().byte_me(usize::MAX).unwrap()).unwrap()
+    }
+
+    fn glob_opts(glob: &str, opts: &GlobOptions) -> Regex {
+        to_regex(Nfa::from_glob(glob, opts).unwrap())
+    }
+
+    fn glob(glob: &str) -> Regex {
+        glob_opts(glob, &GlobOptions::default())
+    }
+
+    #[test]
+    fn literal() {
+        let re = glob("foo.txt");
+        assert!(re.is_match("foo.txt"));
+        assert!(!re.is_match("foo.txt~"));
+        assert!(!re.is_match("afoo.txt"));
+        assert!(glob("").is_match(""));
+        assert!(!glob("").is_match("a"));
+        assert!(glob(r"\*\?").is_match("*?"));
+    }
+
+    #[test]
+    fn star() {
+        let re = glob("*.rs");
+        assert!(re.is_match("lib.rs"));
+        assert!(re.is_match(".rs"));
+        assert!(!re.is_match("src/lib.rs"));
+        assert!(!re.is_match("lib.rs.bak"));
+    }
+
+    #[test]
+    fn question() {
+        let re = glob("a?c");
+        assert!(re.is_match("abc"));
+        assert!(re.is_match("aéc"));
+        assert!(!re.is_match("ac"));
+        assert!(!re.is_match("a/c"));
+    }
+
+    #[test]
+    fn double_star() {
+        let re = glob("src/**/*.rs");
+        assert!(re.is_match("src/lib.rs"));
+        assert!(re.is_match("src/nfa/glob.rs"));
+        assert!(re.is_match("src/a/b/c.rs"));
+        assert!(!re.is_match("src.rs"));
+        assert!(!re.is_match("test/lib.rs"));
+
+        let re = glob("**/foo");
+        assert!(re.is_match("foo"));
+        assert!(re.is_match("a/b/foo"));
+        assert!(!re.is_match("afoo"));
+
+        let re = glob("a/**");
+        assert!(re.is_match("a/"));
+        assert!(re.is_match("a/b/c"));
+        assert!(!re.is_match("a"));
+
+        // If `**` isn't a whole component, it just matches anything.
+        let re = glob("a**b");
+        assert!(re.is_match("ab"));
+        assert!(re.is_match("a/x/b"));
+    }
+
+    #[test]
+    fn class() {
+        let re = glob("[a-c]x");
+        assert!(re.is_match("bx"));
+        assert!(!re.is_match("dx"));
+
+        let re = glob("[!a-c]x");
+        assert!(re.is_match("dx"));
+        assert!(!re.is_match("bx"));
+        assert!(!re.is_match("/x"));
+
+        let re = glob("[]-]");
+        assert!(re.is_match("]"));
+        assert!(re.is_match("-"));
+        assert!(!re.is_match("a"));
+
+        assert!(glob(r"[\]]").is_match("]"));
+        assert!(!glob("[/]").is_match("/"));
+    }
+
+    #[test]
+    fn braces() {
+        let re = glob("*.{rs,toml}");
+        assert!(re.is_match("lib.rs"));
+        assert!(re.is_match("Cargo.toml"));
+        assert!(!re.is_match("Cargo.lock"));
+
+        let re = glob("a{,b{c,d}}");
+        assert!(re.is_match("a"));
+        assert!(re.is_match("abc"));
+        assert!(re.is_match("abd"));
+        assert!(!re.is_match("ab"));
+
+        assert!(glob("{a/*,b}").is_match("a/x"));
+    }
+
+    #[test]
+    fn separators() {
+        let opts = GlobOptions { separators: vec!['/', ':'], ..GlobOptions::default() };
+        let re = glob_opts("*.rs", &opts);
+        assert!(!re.is_match("a:b.rs"));
+        assert!(!re.is_match("a/b.rs"));
+        let re = glob_opts("a:**:c", &opts);
+        assert!(re.is_match("a:c"));
+        assert!(re.is_match("a:b/d:c"));
+
+        let opts = GlobOptions { separators: vec![], ..GlobOptions::default() };
+        assert!(glob_opts("*.rs", &opts).is_match("src/lib.rs"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let opts = GlobOptions { case_insensitive: true, ..GlobOptions::default() };
+        let re = glob_opts("*.RS", &opts);
+        assert!(re.is_match("lib.rs"));
+        assert!(re.is_match("lib.Rs"));
+        let re = glob_opts("[a-c]", &opts);
+        assert!(re.is_match("B"));
+        let re = glob_opts("[!a-c]", &opts);
+        assert!(!re.is_match("B"));
+        assert!(re.is_match("d"));
+        assert!(!glob("*.RS").is_match("lib.rs"));
+
+        // Folding isn't just for ASCII.
+        assert!(glob_opts("k", &opts).is_match("\u{212A}"));
+        assert!(glob_opts("[r-t]", &opts).is_match("\u{17F}"));
+        assert!(glob_opts("Σ", &opts).is_match("ς"));
+        assert!(glob_opts("ß", &opts).is_match("\u{1E9E}"));
+        assert!(glob_opts("[!ß]", &opts).is_match("s"));
+        assert!(!glob_opts("[!ß]", &opts).is_match("\u{1E9E}"));
+
+        // Globs fold case in the same way as regexes do.
+        for c in "kKßµΣ\u{130}\u{131}\u{13F8}\u{1FBC}\u{10400}".chars() {
+            let re = Regex::new(&format!("^(?i){}$", c)).unwrap();
+            let glob = glob_opts(&c.to_string(), &opts);
+            assert_eq!(re.matches_same_language_as(&glob), Ok(()), "{}", c);
+        }
+    }
+
+    #[test]
+    fn anchored() {
+        let nfa = Nfa::from_glob("*.rs", &GlobOptions::default()).unwrap();
+        let nfa = nfa.remove_looks().byte_me(usize::MAX).unwrap();
+        assert!(nfa.is_anchored());
+    }
+
+    #[test]
+    fn errors() {
+        let opts = GlobOptions::default();
+        assert!(Nfa::from_glob("[ab", &opts).is_err());
+        assert!(Nfa::from_glob("[]", &opts).is_err());
+        assert!(Nfa::from_glob("{a,b", &opts).is_err());
+        assert!(Nfa::from_glob("a\\", &opts).is_err());
+        assert!(Nfa::from_glob("[z-a]", &opts).is_err());
+    }
+}
@@ -130,54 +130,498 @@
 //! non-consuming transition leads to an accepting state, it means that the source of that
 //! transition should become a conditionally accepting state.
 
+use error::Error;
 use look::Look;
-use nfa::{Accept, HasLooks, LookPair, Nfa, NoLooks, StateIdx};
+use nfa::{Accept, GlobOptions, HasLooks, LineTerminator, LookPair, Nfa, NoLooks, StateIdx};
+use nfa::glob;
 use std::cmp::max;
 use std::collections::HashSet;
 use std::ops::Deref;
+use std::str;
+use std::u8;
 use range_map::{Range, RangeSet};
-use regex_syntax::{CharClass, ClassRange, Expr, Repeater};
+use regex_syntax::ast::{self, Ast, GroupKind};
+use regex_syntax::hir::translate::TranslatorBuilder;
+use regex_syntax::hir::{self, Class, Hir, HirKind, Look as HirLook};
+
+/// Options for `Nfa::from_regex_with`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RegexOptions {
+    /// If true, word boundaries (like `\b`, `\B` and `\b{start}`) only consider ASCII letters,
+    /// digits and underscores to be word chars. Since this doesn't require looking at multi-byte
+    /// chars, it can make the DFA much smaller.
+    pub ascii_word_boundary: bool,
+    /// The chars that end a line, for `^`, `$` and `.`. If this is `LineTerminator::Crlf`, the
+    /// regex is parsed in CRLF mode, as though it started with `(?R)`.
+    ///
+    /// An `Nfa` built with this option should have its looks removed by `remove_looks_with`.
+    pub line_terminator: LineTerminator,
+    /// If true, it is an error for the regex to name a char outside of Latin-1, either as a
+    /// literal or in a class like `[a€]`. Chars that it only reaches through `.`, `\w`, negated
+    /// classes and the like are still allowed; `byte_me_with(Encoding::Latin1, _)` leaves them
+    /// out.
+    pub latin1: bool,
+}
 
-// Converts a `CharClass` into a `RangeSet`
-fn class_to_set(cc: &CharClass) -> RangeSet<u32> {
-    cc.iter().map(|r| Range::new(r.start as u32, r.end as u32)).collect()
+// Converts a `hir::Class` into a `RangeSet`.
+fn hir_class_to_set(class: &Class) -> RangeSet<u32> {
+    match *class {
+        Class::Unicode(ref c) => {
+            c.iter().map(|r| Range::new(r.start() as u32, r.end() as u32)).collect()
+        },
+        Class::Bytes(ref c) => {
+            c.iter().map(|r| Range::new(r.start() as u32, r.end() as u32)).collect()
+        },
+    }
 }
 
-impl Nfa<u32, HasLooks> {
-    /// Asserts that the invariants that are supposed to hold do.
-    pub fn check_invariants(&self) {
-        // The init state is implicitly the first one, so there are no explicit init states.
-        debug_assert!(self.init.is_empty());
+// Returns an error if `hir` contains something that we can't build an `Nfa` for, given that lines
+// end with `terminator`.
+//
+// Since `Look::NewLine` can only stand for one kind of line ending, we can't support a regex that
+// mixes CRLF mode with the other kind of line anchors.
+fn check_supported(hir: &Hir, terminator: LineTerminator) -> ::Result<()> {
+    let crlf = terminator == LineTerminator::Crlf;
+    match *hir.kind() {
+        HirKind::Look(look) => match look {
+            HirLook::StartLF | HirLook::EndLF if crlf => {
+                Err(Error::Unsupported("line anchors outside of CRLF mode".to_owned()))
+            },
+            HirLook::StartCRLF | HirLook::EndCRLF if !crlf => {
+                Err(Error::Unsupported("line anchors in CRLF mode, unless the line terminator \
+                                       is LineTerminator::Crlf".to_owned()))
+            },
+            _ => Ok(()),
+        },
+        HirKind::Repetition(ref rep) => check_supported(&rep.sub, terminator),
+        HirKind::Capture(ref cap) => check_supported(&cap.sub, terminator),
+        HirKind::Concat(ref hirs) | HirKind::Alternation(ref hirs) => {
+            for h in hirs {
+                try!(check_supported(h, terminator));
+            }
+            Ok(())
+        },
+        _ => Ok(()),
+    }
+}
 
-        // The final state is accepting, and no others are.
-        debug_assert!(self.states.last().unwrap().accept == Accept::Always);
-        debug_assert!(self.states.iter().rev().skip(1).all(|s| s.accept == Accept::Never));
+// The name of the capture groups that `parse_regex` puts in place of `\Z`s. The parser only
+// allows letters, digits, `_`, `.`, `[` and `]` in group names, so this can't clash with a group
+// from the regex itself.
+const FINAL_NEW_LINE: &'static str = r"\Z";
+
+// Parses `re` into a `Hir`, in which every `\Z` has become a capture group named
+// `FINAL_NEW_LINE`.
+//
+// The parser doesn't know about `\Z`, so we first swap each one for a `\z` (see
+// `swap_final_new_lines`), and then we find those `\z`s in the syntax tree and mark them.
+fn parse_regex(re: &str, options: &RegexOptions) -> ::Result<Hir> {
+    let (pattern, final_new_lines) = swap_final_new_lines(re);
+    // Like the `regex` crate's old parser, we read `\1` as an octal escape rather than refusing it
+    // as a backreference.
+    let mut ast = try!(ast::parse::ParserBuilder::new().octal(true).build().parse(&pattern)
+        .map_err(regex_syntax::Error::from));
+    mark_final_new_lines(&mut ast, &final_new_lines);
+    fix_ascii_classes(&mut ast, &pattern);
+    if options.latin1 {
+        try!(check_latin1(&ast));
+    }
 
-        // No state has both a look transition and a consuming transition.
-        debug_assert!(self.states.iter().all(|s| s.looking.is_empty() || s.consuming.is_empty()));
+    let mut translator = TranslatorBuilder::new();
+    match options.line_terminator {
+        LineTerminator::Byte(b) => translator.line_terminator(b),
+        LineTerminator::Crlf => translator.crlf(true),
+    };
+    translator.build().translate(&pattern, &ast)
+        .map_err(|e| regex_syntax::Error::from(e).into())
+}
 
-        // All targets of a consuming transition are just the next state.
-        debug_assert!(self.states.iter()
-                        .enumerate()
-                        .all(|(idx, s)| s.consuming.ranges_values().all(|&(_, val)| val == idx + 1)));
+// Swaps every `\Z` in `re` for a `\z`, returning the new pattern along with the offsets of the
+// swapped escapes.
+//
+// This doesn't move anything, so syntax errors are still reported at the right place. It skips
+// over other escapes, and it leaves alone any `\Z` in a char class, so that the parser can reject
+// that one itself.
+fn swap_final_new_lines(re: &str) -> (String, Vec<usize>) {
+    let mut pattern = String::with_capacity(re.len());
+    let mut positions = Vec::new();
+    // How deeply nested in char classes we are.
+    let mut depth = 0;
+    let mut chars = re.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        pattern.push(c);
+        match c {
+            '\\' => {
+                if let Some((_, next)) = chars.next() {
+                    if next == 'Z' && depth == 0 {
+                        pattern.push('z');
+                        positions.push(i);
+                    } else {
+                        pattern.push(next);
+                    }
+                }
+            },
+            '[' => {
+                depth += 1;
+                // A `]` at the very start of a class (possibly after a `^`) doesn't close it.
+                if chars.peek().map(|&(_, c)| c) == Some('^') {
+                    pattern.push('^');
+                    chars.next();
+                }
+                if chars.peek().map(|&(_, c)| c) == Some(']') {
+                    pattern.push(']');
+                    chars.next();
+                }
+            },
+            ']' if depth > 0 => depth -= 1,
+            _ => {},
+        }
     }
+    (pattern, positions)
+}
 
-    /// Creates a new Nfa from a regex string.
-    pub fn from_regex(re: &str) -> ::Result<Nfa<u32, HasLooks>> {
-        let expr = try!(Expr::parse(re));
+// Replaces the `\z`s that start at `positions` with empty capture groups named `FINAL_NEW_LINE`.
+fn mark_final_new_lines(ast: &mut Ast, positions: &[usize]) {
+    let span = match *ast {
+        Ast::Assertion(ref a) if positions.contains(&a.span.start.offset) => a.span,
+        Ast::Repetition(ref mut rep) => return mark_final_new_lines(&mut rep.ast, positions),
+        Ast::Group(ref mut group) => return mark_final_new_lines(&mut group.ast, positions),
+        Ast::Alternation(ref mut alt) => {
+            for a in &mut alt.asts {
+                mark_final_new_lines(a, positions);
+            }
+            return;
+        },
+        Ast::Concat(ref mut cat) => {
+            for a in &mut cat.asts {
+                mark_final_new_lines(a, positions);
+            }
+            return;
+        },
+        _ => return,
+    };
+    let name = ast::CaptureName { span: span, name: FINAL_NEW_LINE.to_owned(), index: 0 };
+    *ast = Ast::group(ast::Group {
+        span: span,
+        kind: GroupKind::CaptureName { starts_with_p: false, name: name },
+        ast: Box::new(Ast::empty(span)),
+    });
+}
+
+// Turns each `[:name:]` (or `[:^name:]`) that isn't inside a char class into the ASCII class with
+// that name, as though it were written `[[:name:]]`.
+//
+// The `regex` crate's old parser read them this way, but the parser we use now reads `[:word:]` as
+// the class of `:`, `w`, `o`, `r` and `d`.
+fn fix_ascii_classes(ast: &mut Ast, pattern: &str) {
+    let class = match *ast {
+        Ast::ClassBracketed(ref c) => {
+            let text = &pattern[c.span.start.offset..c.span.end.offset];
+            if !text.starts_with("[:") || !text.ends_with(":]") || text.len() < 5 {
+                return;
+            }
+            let name = &text[2..(text.len() - 2)];
+            let (negated, name) = if name.starts_with('^') {
+                (true, &name[1..])
+            } else {
+                (false, name)
+            };
+            match ast::ClassAsciiKind::from_name(name) {
+                Some(kind) => ast::ClassAscii { span: c.span, kind: kind, negated: negated },
+                None => return,
+            }
+        },
+        Ast::Repetition(ref mut rep) => return fix_ascii_classes(&mut rep.ast, pattern),
+        Ast::Group(ref mut group) => return fix_ascii_classes(&mut group.ast, pattern),
+        Ast::Alternation(ref mut alt) => {
+            for a in &mut alt.asts {
+                fix_ascii_classes(a, pattern);
+            }
+            return;
+        },
+        Ast::Concat(ref mut cat) => {
+            for a in &mut cat.asts {
+                fix_ascii_classes(a, pattern);
+            }
+            return;
+        },
+        _ => return,
+    };
+    *ast = Ast::class_bracketed(ast::ClassBracketed {
+        span: class.span,
+        negated: false,
+        kind: ast::ClassSet::Item(ast::ClassSetItem::Ascii(class)),
+    });
+}
+
+// Fails if `ast` asks for a char outside of Latin-1 by name, either as a literal or as a member of
+// a class.
+//
+// Chars that only come in through `.`, named classes, negated classes and the like aren't checked,
+// because the Latin-1 encoder just leaves them out.
+fn check_latin1(ast: &Ast) -> ::Result<()> {
+    match *ast {
+        Ast::Literal(ref lit) => check_latin1_char(lit.c),
+        Ast::ClassBracketed(ref class) => check_latin1_class(class),
+        Ast::Repetition(ref rep) => check_latin1(&rep.ast),
+        Ast::Group(ref group) => check_latin1(&group.ast),
+        Ast::Alternation(ref alt) => alt.asts.iter().map(check_latin1).collect(),
+        Ast::Concat(ref cat) => cat.asts.iter().map(check_latin1).collect(),
+        _ => Ok(()),
+    }
+}
+
+fn check_latin1_char(c: char) -> ::Result<()> {
+    if c as u32 > u8::MAX as u32 {
+        Err(Error::NotLatin1(format!("U+{:04X} is not in Latin-1", c as u32)))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_latin1_class(class: &ast::ClassBracketed) -> ::Result<()> {
+    fn check_set(set: &ast::ClassSet) -> ::Result<()> {
+        match *set {
+            ast::ClassSet::Item(ref item) => check_item(item),
+            ast::ClassSet::BinaryOp(ref op) => {
+                try!(check_set(&op.lhs));
+                // The right-hand side of a difference only takes chars away.
+                if op.kind != ast::ClassSetBinaryOpKind::Difference {
+                    try!(check_set(&op.rhs));
+                }
+                Ok(())
+            },
+        }
+    }
+
+    fn check_item(item: &ast::ClassSetItem) -> ::Result<()> {
+        match *item {
+            ast::ClassSetItem::Literal(ref lit) => check_latin1_char(lit.c),
+            ast::ClassSetItem::Range(ref range) => {
+                try!(check_latin1_char(range.start.c));
+                check_latin1_char(range.end.c)
+            },
+            ast::ClassSetItem::Bracketed(ref class) => check_latin1_class(class),
+            ast::ClassSetItem::Union(ref union) => union.items.iter().map(check_item).collect(),
+            _ => Ok(()),
+        }
+    }
+
+    if class.negated {
+        Ok(())
+    } else {
+        check_set(&class.kind)
+    }
+}
+
+// Something that can be appended to an `Nfa<u32, HasLooks>`.
+//
+// Appending a fragment adds at least one new state. The first new state is the one where the
+// fragment starts, and the last new state is the one where it finishes; the last new state has no
+// transitions out of it.
+trait Fragment {
+    fn add_to(&self, nfa: &mut Nfa<u32, HasLooks>);
+}
+
+impl Fragment for Hir {
+    fn add_to(&self, nfa: &mut Nfa<u32, HasLooks>) {
+        nfa.add_hir(self);
+    }
+}
+
+impl Fragment for Nfa<u32, HasLooks> {
+    fn add_to(&self, nfa: &mut Nfa<u32, HasLooks>) {
+        nfa.add_nfa(self);
+    }
+}
+
+impl<'a, F: Fragment> Fragment for &'a F {
+    fn add_to(&self, nfa: &mut Nfa<u32, HasLooks>) {
+        (*self).add_to(nfa);
+    }
+}
+
+impl Nfa<u32, HasLooks> {
+    /// Checks that the invariants that are supposed to hold do.
+    ///
+    /// These invariants are the ones that `remove_looks` relies on:
+    ///
+    /// - there are no explicit initial states (the initial state is implicitly state 0),
+    /// - the last state is accepting (with `Accept::Always`), and no others are,
+    /// - the last state has no transitions out of it,
+    /// - no state has both a look transition and a consuming transition,
+    /// - every consuming transition leads from a state to the next one, and
+    /// - every look transition leads to a state that exists.
+    pub fn check_invariants(&self) -> ::Result<()> {
+        let invalid = |msg: String| Err(Error::InvalidNfa(msg));
+
+        if !self.init.is_empty() {
+            return invalid("an Nfa with looks can't have explicit initial states".to_owned());
+        }
+        match self.states.last() {
+            None => return invalid("there must be at least one state".to_owned()),
+            Some(st) if st.accept != Accept::Always =>
+                return invalid("the last state must be accepting".to_owned()),
+            Some(st) if !st.consuming.is_empty() || !st.looking.is_empty() =>
+                return invalid("the last state can't have transitions out of it".to_owned()),
+            Some(_) => {},
+        }
+
+        let len = self.states.len();
+        for (idx, st) in self.states.iter().enumerate() {
+            if idx + 1 < len && st.accept != Accept::Never {
+                return invalid(format!("state {} is accepting, but it isn't the last state", idx));
+            }
+            if !st.looking.is_empty() && !st.consuming.is_empty() {
+                return invalid(format!("state {} has both look and consuming transitions", idx));
+            }
+            if st.consuming.ranges_values().any(|&(_, tgt)| tgt != idx + 1) {
+                return invalid(format!("state {} has a consuming transition to a state other \
+                                        than {}", idx, idx + 1));
+            }
+            if let Some(look) = st.looking.iter().find(|look| look.target_state >= len) {
+                return invalid(format!("state {} has a look transition to the non-existent \
+                                        state {}", idx, look.target_state));
+            }
+        }
+        Ok(())
+    }
+
+    // Creates a new Nfa that matches a single fragment, which is added by `add_fragment`.
+    fn from_fragment<F>(add_fragment: F) -> Nfa<u32, HasLooks>
+    where F: FnOnce(&mut Nfa<u32, HasLooks>) {
         let mut ret = Nfa::new();
 
         ret.add_state(Accept::Never);
-        ret.add_expr(&expr);
+        add_fragment(&mut ret);
         ret.add_eps(0, 1);
 
         let len = ret.num_states();
         ret.states[len - 1].accept = Accept::Always;
 
-        ret.check_invariants();
+        debug_assert!(ret.check_invariants().is_ok());
+        ret
+    }
+
+    /// Creates a new Nfa from a regex string.
+    pub fn from_regex(re: &str) -> ::Result<Nfa<u32, HasLooks>> {
+        Nfa::from_regex_with(re, &RegexOptions::default())
+    }
+
+    /// Creates a new Nfa from a regex string, with some options.
+    ///
+    /// Returns an error if `re` isn't a valid regex, or if it uses a feature that we don't
+    /// support (like `(?R)` without `LineTerminator::Crlf`).
+    ///
+    /// As well as the syntax that the `regex` crate understands, this supports `\Z`, which
+    /// matches at the end of the input or just before a line terminator that ends it. (In CRLF
+    /// mode, that line terminator is either `"\r\n"` or a `'\n'` that doesn't come after a
+    /// `'\r'`.) Since a `\Z` can only see one line terminator ahead, nothing that consumes input
+    /// can match after it: for example, `a\Z\n` doesn't match `"a\n"`.
+    pub fn from_regex_with(re: &str, options: &RegexOptions) -> ::Result<Nfa<u32, HasLooks>> {
+        try!(options.line_terminator.check());
+        let hir = try!(parse_regex(re, options));
+        try!(check_supported(&hir, options.line_terminator));
+        let mut ret = Nfa::from_fragment(|nfa| nfa.add_hir(&hir));
+        if options.ascii_word_boundary {
+            ret.use_ascii_word_chars();
+        }
         Ok(ret)
     }
 
+    // Changes every word boundary in this Nfa into an ASCII word boundary.
+    //
+    // The only looks that mention word chars are the ones that come from word boundaries, so we
+    // can just replace them.
+    fn use_ascii_word_chars(&mut self) {
+        fn ascii(look: Look) -> Look {
+            match look {
+                Look::WordChar => Look::AsciiWordChar,
+                Look::NotWordChar => Look::NotAsciiWordChar,
+                x => x,
+            }
+        }
+
+        for st in &mut self.states {
+            for look in &mut st.looking {
+                look.behind = ascii(look.behind);
+                look.ahead = ascii(look.ahead);
+            }
+        }
+    }
+
+    /// Creates a new Nfa from a shell-style glob.
+    ///
+    /// The glob must match the whole string. It supports the usual wildcards: `?` matches any char
+    /// except a separator, `*` matches any sequence of them, and `**` matches any sequence of
+    /// chars at all. A class like `[a-z]` or `[!a-z]` matches a single char (but never a
+    /// separator), `{a,b}` matches either `a` or `b` (which can contain other wildcards), and a
+    /// backslash escapes the char after it. If `**` is a whole path component followed by a
+    /// separator, the separator is optional, so `a/**/b` matches `a/b` as well as `a/x/y/b`.
+    pub fn from_glob(pattern: &str, options: &GlobOptions) -> ::Result<Nfa<u32, HasLooks>> {
+        let hir = try!(glob::parse(pattern, options));
+        Ok(Nfa::from_fragment(|nfa| nfa.add_hir(&hir)))
+    }
+
+    /// Creates a new Nfa that matches the string `s` (and no regex syntax is interpreted).
+    pub fn literal(s: &str) -> Nfa<u32, HasLooks> {
+        let chars: Vec<char> = s.chars().collect();
+        Nfa::from_fragment(|nfa| nfa.add_literal(chars.iter()))
+    }
+
+    /// Creates a new Nfa that matches `a` followed by `b`.
+    ///
+    /// This is the same as the regex `ab`, where `a` and `b` are the regexes that the two `Nfa`s
+    /// were made from. In particular, matches of `a` are prioritized in the same way.
+    pub fn concat(a: &Nfa<u32, HasLooks>, b: &Nfa<u32, HasLooks>) -> Nfa<u32, HasLooks> {
+        Nfa::from_fragment(|nfa| nfa.add_concat_exprs(&[a, b]))
+    }
+
+    /// Creates a new Nfa that matches any one of `alts`.
+    ///
+    /// This is the same as the regex `a|b|...`: if more than one of `alts` match, the earliest one
+    /// is preferred.
+    pub fn alternate(alts: &[Nfa<u32, HasLooks>]) -> Nfa<u32, HasLooks> {
+        Nfa::from_fragment(|nfa| nfa.add_alternate_exprs(alts))
+    }
+
+    /// Creates a new Nfa that matches between `min` and `max` copies of `a` (or at least `min`
+    /// copies, if `max` is `None`).
+    ///
+    /// This is the same as the regex `a{min,max}` if `greedy` is true, and `a{min,max}?` if it is
+    /// false.
+    ///
+    /// # Panics
+    /// - if `max` is smaller than `min`.
+    pub fn repeat(a: &Nfa<u32, HasLooks>, min: u32, max: Option<u32>, greedy: bool)
+    -> Nfa<u32, HasLooks> {
+        assert!(max.map_or(true, |max| max >= min), "the maximum is smaller than the minimum");
+        Nfa::from_fragment(|nfa| nfa.add_repeat_min_max(a, min, max, greedy))
+    }
+
+    // Appends a copy of `other`, which should satisfy `check_invariants`. The copy starts in the
+    // first new state and finishes in the last new state.
+    fn add_nfa(&mut self, other: &Nfa<u32, HasLooks>) {
+        if other.states.is_empty() {
+            // `other` doesn't match anything, so the start and finish aren't connected.
+            self.add_state(Accept::Never);
+            self.add_state(Accept::Never);
+            return;
+        }
+
+        let offset = self.states.len();
+        for st in &other.states {
+            let mut st = st.clone();
+            st.accept = Accept::Never;
+            st.accept_state += offset;
+            st.consuming.map_values(|x| *x + offset);
+            for look in &mut st.looking {
+                look.target_state += offset;
+            }
+            self.states.push(st);
+        }
+    }
+
     /// Adds a non-input consuming transition between states `source` and `target`.
     ///
     /// The transition will be traversed if the last consumed byte matches `behind` and the next
@@ -192,7 +636,18 @@ impl Nfa<u32, HasLooks> {
     }
 
     /// Removes all look transitions, converting this Nfa into an `Nfa<u32, NoLooks>`.
-    pub fn remove_looks(mut self) -> Nfa<u32, NoLooks> {
+    pub fn remove_looks(self) -> Nfa<u32, NoLooks> {
+        self.remove_looks_with(LineTerminator::default())
+    }
+
+    /// Removes all look transitions, where `Look::NewLine` stands for the chars in `terminator`.
+    ///
+    /// The returned `Nfa` may still have `Look::NewLine` in its initial states and accepting
+    /// states, so the same `terminator` needs to be passed to `Nfa::reverse_with` and
+    /// `Nfa::anchor_with`. With `LineTerminator::Crlf`, it may also have
+    /// `Look::CarriageReturn` (and the other looks that only exist for CRLF mode) in its initial
+    /// states.
+    pub fn remove_looks_with(mut self, terminator: LineTerminator) -> Nfa<u32, NoLooks> {
         if self.states.is_empty() {
             return Nfa::with_capacity(0);
         }
@@ -200,15 +655,28 @@ impl Nfa<u32, HasLooks> {
         // For every state with out transitions, add transitions from it to everything in the closure
         // of the target. Note that (according to `check_invariants`) the target state is always
         // the next state.
+        //
+        // In CRLF mode, a `'\r'` might be the first half of a `"\r\n"`, so consuming it can't lead
+        // to the usual state of a look that involves lines. Instead, it leads to a copy of that
+        // state that refuses to go on with a `'\n'`. The last field of `new_states` says whether
+        // the state is such a copy.
         let old_len = self.num_states();
-        let mut new_states: Vec<(StateIdx, Look, StateIdx)> = Vec::new();
+        let mut new_states: Vec<(StateIdx, Look, StateIdx, bool)> = Vec::new();
+        let cr = RangeSet::single('\r' as u32);
+        let not_lf = RangeSet::single('\n' as u32).negated();
         for src_idx in 0..self.states.len() {
             if !self.states[src_idx].consuming.is_empty() {
                 let consuming = self.states[src_idx].consuming.clone();
                 for look in self.closure(src_idx + 1) {
+                    let behind_set = terminator.look_set(look.behind);
+                    let splits = terminator.splits_crlf(&look);
+
                     // Add transitions into the look target.
-                    let new_idx = self.add_look_state(look);
-                    let filtered_consuming = consuming.intersection(look.behind.as_set());
+                    let new_idx = self.add_look_state(look, terminator, false);
+                    let mut filtered_consuming = consuming.intersection(&behind_set);
+                    if splits {
+                        filtered_consuming = filtered_consuming.intersection(&cr.negated());
+                    }
                     for &(range, _) in filtered_consuming.ranges_values() {
                         self.add_transition(src_idx, new_idx, range);
                     }
@@ -216,7 +684,13 @@ impl Nfa<u32, HasLooks> {
                     // out of it, because we need to make sure that all the transitions from
                     // look.target_state have been added first.
                     if new_idx >= old_len {
-                        new_states.push((new_idx, look.ahead, look.target_state));
+                        new_states.push((new_idx, look.ahead, look.target_state, false));
+                    }
+
+                    if splits && !consuming.intersection(&behind_set).intersection(&cr).is_empty() {
+                        let cr_idx = self.add_look_state(look, terminator, true);
+                        self.add_transition(src_idx, cr_idx, Range::single('\r' as u32));
+                        new_states.push((cr_idx, look.ahead, look.target_state, true));
                     }
                 }
             }
@@ -225,16 +699,34 @@ impl Nfa<u32, HasLooks> {
         // Add the new initial states: everything that was immediately reachable from state 0 is now
         // an initial state.
         for look in self.closure(0) {
-            let new_idx = self.add_look_state(look);
-            self.init.push((look.behind, new_idx));
+            let new_idx = self.add_look_state(look, terminator, false);
             if new_idx >= old_len {
-                new_states.push((new_idx, look.ahead, look.target_state));
+                new_states.push((new_idx, look.ahead, look.target_state, false));
+            }
+            if terminator.splits_crlf(&look) {
+                self.init.push((look.behind.without_carriage_return(), new_idx));
+                if terminator.look_set(look.behind).contains('\r' as u32) {
+                    let cr_idx = self.add_look_state(look, terminator, true);
+                    self.init.push((Look::CarriageReturn, cr_idx));
+                    new_states.push((cr_idx, look.ahead, look.target_state, true));
+                }
+            } else {
+                self.init.push((look.behind, new_idx));
             }
         }
 
-        // Now add transitions out of the new states.
-        for (src_idx, look, tgt_idx) in new_states {
-            let out_consuming = self.states[tgt_idx].consuming.intersection(look.as_set());
+        // Now add transitions out of the new states. We can't follow a `FinalNewLine`, because
+        // consuming its line terminator means that the input has to end straight afterwards, and
+        // there's no way to say that. So nothing that consumes input can come after a `\Z`.
+        for (src_idx, look, tgt_idx, after_cr) in new_states {
+            if look == Look::FinalNewLine {
+                continue;
+            }
+            let mut out_consuming =
+                self.states[tgt_idx].consuming.intersection(&terminator.look_set(look));
+            if after_cr {
+                out_consuming = out_consuming.intersection(&not_lf);
+            }
             for &(range, tgt) in out_consuming.ranges_values() {
                 self.states[src_idx].consuming.insert(range, tgt);
             }
@@ -251,11 +743,13 @@ impl Nfa<u32, HasLooks> {
     }
 
     // Adds a new state for a LookPair, if necessary. It is necessary to add a new state if and
-    // only if the LookPair needs to look ahead.
+    // only if the LookPair needs to look ahead, or if `after_cr` is true (meaning that the state
+    // comes right after a `'\r'` and must not be followed by a `'\n'`).
     //
     // Returns the index of the new state.
-    fn add_look_state(&mut self, look: LookPair) -> StateIdx {
-        if look.ahead.is_full() {
+    fn add_look_state(&mut self, look: LookPair, terminator: LineTerminator, after_cr: bool)
+    -> StateIdx {
+        if look.ahead.is_full() && !after_cr {
             look.target_state
         } else {
             let tgt_idx = look.target_state;
@@ -269,17 +763,43 @@ impl Nfa<u32, HasLooks> {
             }
 
             // If the target state of the look is accepting, add a new look-ahead accepting state.
-            if self.states[tgt_idx].accept == Accept::Always
-                    && !look.ahead.as_set().is_empty() {
-                let acc_idx = self.add_look_ahead_state(look.ahead, 1, new_idx);
-                for range in look.ahead.as_set().ranges() {
-                    self.add_transition(new_idx, acc_idx, range);
+            let mut ahead_set = terminator.look_set(look.ahead).into_owned();
+            if after_cr {
+                ahead_set = ahead_set.intersection(&RangeSet::single('\n' as u32).negated());
+            }
+            if self.states[tgt_idx].accept == Accept::Always && !ahead_set.is_empty() {
+                if look.ahead == Look::FinalNewLine && terminator == LineTerminator::Crlf {
+                    self.add_final_crlf_states(new_idx, after_cr);
+                } else {
+                    let acc_idx = self.add_look_ahead_state(look.ahead, 1, new_idx);
+                    // After a `FinalNewLine`, the line terminator must be the end of the input.
+                    if look.ahead == Look::FinalNewLine {
+                        self.states[acc_idx].accept = Accept::AtEoi;
+                    }
+                    for range in ahead_set.ranges() {
+                        self.add_transition(new_idx, acc_idx, range);
+                    }
                 }
             }
             new_idx
         }
     }
 
+    // Adds the look-ahead states for a `\Z` in CRLF mode, which accepts (on behalf of `state`) if
+    // the rest of the input is either `"\r\n"` or (unless `after_cr` is true) `"\n"`.
+    fn add_final_crlf_states(&mut self, state: StateIdx, after_cr: bool) {
+        let cr_idx = self.add_state(Accept::Never);
+        let crlf_idx = self.add_look_ahead_state(Look::FinalNewLine, 2, state);
+        self.states[crlf_idx].accept = Accept::AtEoi;
+        self.add_transition(state, cr_idx, Range::single('\r' as u32));
+        self.add_transition(cr_idx, crlf_idx, Range::single('\n' as u32));
+        if !after_cr {
+            let lf_idx = self.add_look_ahead_state(Look::FinalNewLine, 1, state);
+            self.states[lf_idx].accept = Accept::AtEoi;
+            self.add_transition(state, lf_idx, Range::single('\n' as u32));
+        }
+    }
+
     /// Finds (transitively) the set of all non-consuming transitions that can be made starting
     /// from `state`.
     ///
@@ -331,31 +851,25 @@ impl Nfa<u32, HasLooks> {
     }
 
     /// Appends a sequence of states that recognizes a literal.
-    fn add_literal<C, I>(&mut self, chars: I, case_insensitive: bool)
+    fn add_literal<C, I>(&mut self, chars: I)
         where C: Deref<Target=char>,
               I: Iterator<Item=C>
     {
         for ch in chars {
-            let ranges = if case_insensitive {
-                let cc = CharClass::new(vec![ClassRange { start: *ch, end: *ch }]);
-                class_to_set(&cc.case_fold())
-            } else {
-                RangeSet::single(*ch as u32)
-            };
-            self.add_state_with_chars(&ranges);
+            self.add_state_with_chars(&RangeSet::single(*ch as u32));
         }
         self.add_state(Accept::Never);
     }
 
     /// Appends a sequence of states that recognizes the concatenation of `exprs`.
-    fn add_concat_exprs(&mut self, exprs: &[Expr]) {
+    fn add_concat_exprs<E: Fragment>(&mut self, exprs: &[E]) {
         if let Some((expr, rest)) = exprs.split_first() {
-            self.add_expr(expr);
+            expr.add_to(self);
 
             for expr in rest {
                 let cur_len = self.num_states();
                 self.add_eps(cur_len - 1, cur_len);
-                self.add_expr(expr);
+                expr.add_to(self);
             }
         } else {
             self.add_state(Accept::Never);
@@ -365,7 +879,7 @@ impl Nfa<u32, HasLooks> {
     /// Appends a sequence of states that recognizes one of the expressions in `alts`.
     ///
     /// The earlier expressions in `alts` get higher priority when matching.
-    fn add_alternate_exprs(&mut self, alts: &[Expr]) {
+    fn add_alternate_exprs<E: Fragment>(&mut self, alts: &[E]) {
         // Add the new initial state that feeds into the alternate.
         let init_idx = self.num_states();
         self.add_state(Accept::Never);
@@ -374,7 +888,7 @@ impl Nfa<u32, HasLooks> {
         for expr in alts {
             let expr_init_idx = self.states.len();
             self.add_eps(init_idx, expr_init_idx);
-            self.add_expr(expr);
+            expr.add_to(self);
             expr_end_indices.push(self.states.len() - 1);
         }
 
@@ -386,44 +900,26 @@ impl Nfa<u32, HasLooks> {
         }
     }
 
-    /// Appends new states, representing multiple copies of `expr`.
-    fn add_repeat(&mut self, expr: &Expr, rep: Repeater, greedy: bool) {
-        match rep {
-            Repeater::ZeroOrOne => {
-                self.add_repeat_up_to(expr, 1, greedy);
-            },
-            Repeater::ZeroOrMore => {
-                self.add_repeat_zero_or_more(expr, greedy);
-            },
-            Repeater::OneOrMore => {
-                self.add_repeat_min_max(expr, 1, None, greedy);
-            },
-            Repeater::Range{ min, max } => {
-                self.add_repeat_min_max(expr, min, max, greedy);
-            }
-        }
-    }
-
     /// Repeats `expr` a fixed number of times (which must be positive).
-    fn add_repeat_exact(&mut self, expr: &Expr, n: u32) {
+    fn add_repeat_exact<E: Fragment>(&mut self, expr: &E, n: u32) {
         assert!(n > 0);
-        self.add_expr(expr);
+        expr.add_to(self);
         for _ in 1..n {
             let idx = self.states.len();
-            self.add_expr(expr);
+            expr.add_to(self);
             self.add_eps(idx - 1, idx);
         }
     }
 
     /// Repeats `expr` between zero and `n` times (`n` must be positive).
-    fn add_repeat_up_to(&mut self, expr: &Expr, n: u32, greedy: bool) {
+    fn add_repeat_up_to<E: Fragment>(&mut self, expr: &E, n: u32, greedy: bool) {
         assert!(n > 0);
 
         self.add_state(Accept::Never);
         let mut init_indices = Vec::<StateIdx>::with_capacity(n as usize);
         for _ in 0..n {
             init_indices.push(self.states.len() as StateIdx);
-            self.add_expr(expr);
+            expr.add_to(self);
         }
         let final_idx = self.states.len() - 1;
         for idx in init_indices {
@@ -447,7 +943,11 @@ impl Nfa<u32, HasLooks> {
     ///
     /// The new states represent a language that accepts at least `min` and at most `maybe_max`
     /// copies of `expr`. (If `maybe_max` is `None`, there is no upper bound.)
-    fn add_repeat_min_max(&mut self, expr: &Expr, min: u32, maybe_max: Option<u32>, greedy: bool) {
+    fn add_repeat_min_max<E: Fragment>(&mut self,
+                                        expr: &E,
+                                        min: u32,
+                                        maybe_max: Option<u32>,
+                                        greedy: bool) {
         if min == 0 && maybe_max == Some(0) {
             // We add a state anyway, in order to maintain the convention that every expr should
             // add at least one state (otherwise keeping track of indices becomes much more
@@ -476,10 +976,10 @@ impl Nfa<u32, HasLooks> {
     }
 
     /// Repeats the given expression zero or more times.
-    fn add_repeat_zero_or_more(&mut self, expr: &Expr, greedy: bool) {
+    fn add_repeat_zero_or_more<E: Fragment>(&mut self, expr: &E, greedy: bool) {
         let start_idx = self.num_states();
         self.add_state(Accept::Never);
-        self.add_expr(expr);
+        expr.add_to(self);
         self.add_state(Accept::Never);
         let end_idx = self.num_states() - 1;
 
@@ -500,42 +1000,85 @@ impl Nfa<u32, HasLooks> {
         self.add_look(len - 2, len - 1, behind, ahead);
     }
 
-    /// Appends a bunch of new states, representing `expr`.
+    /// Adds two new states, connected if we're at the boundary between `word` and `not_word`.
+    fn add_word_boundary(&mut self, word: Look, not_word: Look) {
+        self.add_look_pair(word, not_word);
+        self.extra_look(not_word, word);
+    }
+
+    /// Adds two new states, connected if we're not at the boundary between `word` and
+    /// `not_word`.
+    fn add_not_word_boundary(&mut self, word: Look, not_word: Look) {
+        self.add_look_pair(word, word);
+        self.extra_look(not_word, not_word);
+    }
+
+    /// Appends a bunch of new states, representing `hir`.
     ///
     /// This maintains the invariant that the last state is always empty (i.e. it doesn't have any
-    /// transitions leading out of it). It is also guaranteed to add at least one new state.
-    pub fn add_expr(&mut self, expr: &Expr) {
-        use regex_syntax::Expr::*;
-
-        match *expr {
-            Empty => { self.add_state(Accept::Never); },
-            Class(ref c) => self.add_single_transition(&class_to_set(c)),
-            AnyChar => self.add_single_transition(&RangeSet::full()),
-            AnyCharNoNL => {
-                let nls = b"\n\r".into_iter().map(|b| *b as u32);
-                self.add_single_transition(&RangeSet::except(nls))
+    /// transitions leading out of it). It is also guaranteed to add at least one new state. `hir`
+    /// must come either from `parse_regex` (and have passed `check_supported`) or from
+    /// `glob::parse`.
+    fn add_hir(&mut self, hir: &Hir) {
+        match *hir.kind() {
+            HirKind::Empty => { self.add_state(Accept::Never); },
+            HirKind::Literal(hir::Literal(ref bytes)) => {
+                // We always parse in UTF-8 mode, so literals are always valid UTF-8.
+                let s = str::from_utf8(bytes).expect("BUG: a literal wasn't UTF-8");
+                let chars: Vec<char> = s.chars().collect();
+                self.add_literal(chars.iter());
             },
-            Concat(ref es) => self.add_concat_exprs(es),
-            Alternate(ref es) => self.add_alternate_exprs(es),
-            Literal { ref chars, casei } => self.add_literal(chars.iter(), casei),
-            StartLine => self.add_look_pair(Look::NewLine, Look::Full),
-            StartText => self.add_look_pair(Look::Boundary, Look::Full),
-            EndLine => self.add_look_pair(Look::Full, Look::NewLine),
-            EndText => self.add_look_pair(Look::Full, Look::Boundary),
-            WordBoundary => {
-                self.add_look_pair(Look::WordChar, Look::NotWordChar);
-                self.extra_look(Look::NotWordChar, Look::WordChar);
+            HirKind::Class(ref c) => self.add_single_transition(&hir_class_to_set(c)),
+            HirKind::Look(look) => self.add_hir_look(look),
+            HirKind::Repetition(ref rep) => {
+                self.add_repeat_min_max(&*rep.sub, rep.min, rep.max, rep.greedy)
             },
-            NotWordBoundary => {
-                self.add_look_pair(Look::WordChar, Look::WordChar);
-                self.extra_look(Look::NotWordChar, Look::NotWordChar);
+
+            // Some capture groups stand for a `\Z` (see `parse_regex`); the rest are just groups.
+            HirKind::Capture(ref cap) => {
+                if cap.name.as_ref().map(|n| &**n) == Some(FINAL_NEW_LINE) {
+                    self.add_look_pair(Look::Full, Look::FinalNewLine);
+                } else {
+                    self.add_hir(&cap.sub);
+                }
             },
-            Repeat { ref e, r, greedy } => self.add_repeat(e, r, greedy),
+            HirKind::Concat(ref hirs) => self.add_concat_exprs(hirs),
+            HirKind::Alternation(ref hirs) => self.add_alternate_exprs(hirs),
+        }
+    }
 
-            // We don't support capture groups, so there is no need to keep track of
-            // the group name or number.
-            Group { ref e, .. } => self.add_expr(e),
+    /// Adds two new states, connected by `look`.
+    fn add_hir_look(&mut self, look: HirLook) {
+        match look {
+            HirLook::Start => self.add_look_pair(Look::Boundary, Look::Full),
+            HirLook::End => self.add_look_pair(Look::Full, Look::Boundary),
+            HirLook::StartLF | HirLook::StartCRLF => self.add_look_pair(Look::NewLine, Look::Full),
+            HirLook::EndLF | HirLook::EndCRLF => self.add_look_pair(Look::Full, Look::NewLine),
+            HirLook::WordUnicode => self.add_word_boundary(Look::WordChar, Look::NotWordChar),
+            HirLook::WordUnicodeNegate => {
+                self.add_not_word_boundary(Look::WordChar, Look::NotWordChar)
+            },
+            HirLook::WordAscii => {
+                self.add_word_boundary(Look::AsciiWordChar, Look::NotAsciiWordChar)
+            },
+            HirLook::WordAsciiNegate => {
+                self.add_not_word_boundary(Look::AsciiWordChar, Look::NotAsciiWordChar)
+            },
 
+            // The half boundaries only look on one side. The not-word side is the one that is
+            // allowed to be the edge of the input, and `NotWordChar` includes `Boundary`.
+            HirLook::WordStartUnicode => self.add_look_pair(Look::NotWordChar, Look::WordChar),
+            HirLook::WordEndUnicode => self.add_look_pair(Look::WordChar, Look::NotWordChar),
+            HirLook::WordStartHalfUnicode => self.add_look_pair(Look::NotWordChar, Look::Full),
+            HirLook::WordEndHalfUnicode => self.add_look_pair(Look::Full, Look::NotWordChar),
+            HirLook::WordStartAscii => {
+                self.add_look_pair(Look::NotAsciiWordChar, Look::AsciiWordChar)
+            },
+            HirLook::WordEndAscii => {
+                self.add_look_pair(Look::AsciiWordChar, Look::NotAsciiWordChar)
+            },
+            HirLook::WordStartHalfAscii => self.add_look_pair(Look::NotAsciiWordChar, Look::Full),
+            HirLook::WordEndHalfAscii => self.add_look_pair(Look::Full, Look::NotAsciiWordChar),
         }
     }
 }
@@ -543,8 +1086,10 @@ impl Nfa<u32, HasLooks> {
 #[cfg(test)]
 mod tests {
     use look::Look;
-    use nfa::{Accept, NoLooks, Nfa, StateIdx};
+    use nfa::{Accept, HasLooks, NoLooks, Nfa, RegexOptions, StateIdx};
     use nfa::tests::{re_nfa, trans_nfa};
+    use regex::Regex;
+    use std::usize;
 
     // Creates an Nfa with the given transitions, with initial state zero, and with the final
     // state the only accepting state.
@@ -567,8 +1112,9 @@ mod tests {
 
     #[test]
     fn alternate() {
-        let nfa = re_nfa("a|b");
-        let mut target = trans_nfa_extra(3, &[(0, 2, 'a'), (1, 2, 'b')]);
+        // (The parser would turn `a|b` into the class `[ab]`.)
+        let nfa = re_nfa("a|bc");
+        let mut target = trans_nfa_extra(4, &[(0, 3, 'a'), (1, 2, 'b'), (2, 3, 'c')]);
         target.init.push((Look::Full, 1));
 
         assert_eq!(nfa, target);
@@ -644,6 +1190,27 @@ mod tests {
         assert_eq!(nfa, target);
     }
 
+    #[test]
+    fn word_start() {
+        let nfa = re_nfa(r"\b{start}a");
+        let mut target = trans_nfa(2, &[(1, 0, 'a')]);
+        target.init.push((Look::NotWordChar, 1));
+        target.states[0].accept = Accept::Always;
+
+        assert_eq!(nfa, target);
+    }
+
+    #[test]
+    fn ascii_word_boundary_start() {
+        let opts = RegexOptions { ascii_word_boundary: true, ..RegexOptions::default() };
+        let nfa = Nfa::from_regex_with(r"\ba", &opts).unwrap().remove_looks();
+        let mut target = trans_nfa(2, &[(1, 0, 'a')]);
+        target.init.push((Look::NotAsciiWordChar, 1));
+        target.states[0].accept = Accept::Always;
+
+        assert_eq!(nfa, target);
+    }
+
     #[test]
     fn word_boundary_end() {
         let nfa = re_nfa(r"a\b");
@@ -677,5 +1244,68 @@ mod tests {
     fn empty() {
         assert_eq!(re_nfa(""), trans_nfa_extra(1, &[]));
     }
+
+    fn to_regex(nfa: Nfa<u32, HasLooks>) -> Regex {
+        Regex::from_nfa(nfa.remove_looks().byte_me(usize::MAX).unwrap()).unwrap()
+    }
+
+    fn regex_nfa(re: &str) -> Nfa<u32, HasLooks> {
+        Nfa::from_regex(re).unwrap()
+    }
+
+    #[test]
+    fn literal() {
+        assert_eq!(Nfa::literal("ab"), regex_nfa("ab"));
+        assert_eq!(Nfa::literal("a.b"), regex_nfa(r"a\.b"));
+        assert_eq!(Nfa::literal(""), regex_nfa(""));
+    }
+
+    #[test]
+    fn concat() {
+        let nfa = Nfa::concat(&regex_nfa("a|b"), &Nfa::literal("(c)"));
+        assert!(nfa.check_invariants().is_ok());
+        let re = to_regex(nfa);
+        assert_eq!(re.find("xb(c)"), Some((1, 5)));
+        assert_eq!(re.find("ac"), None);
+
+        // Looks still see the text around them.
+        let re = to_regex(Nfa::concat(&regex_nfa(r"a\b"), &Nfa::literal(" b")));
+        assert_eq!(re.find("a b"), Some((0, 3)));
+        let re = to_regex(Nfa::concat(&regex_nfa(r"a\B"), &Nfa::literal(" b")));
+        assert_eq!(re.find("a b"), None);
+    }
+
+    #[test]
+    fn alternate_nfas() {
+        let re = to_regex(Nfa::alternate(&[Nfa::literal("a"), Nfa::literal("ab")]));
+        assert_eq!(re.find("ab"), Some((0, 1)));
+        let re = to_regex(Nfa::alternate(&[Nfa::literal("ab"), Nfa::literal("a")]));
+        assert_eq!(re.find("ab"), Some((0, 2)));
+        let re = to_regex(Nfa::alternate(&[Nfa::literal("x"), regex_nfa("[0-9]+")]));
+        assert_eq!(re.find("a12x"), Some((1, 3)));
+
+        assert!(Nfa::alternate(&[]).remove_looks().is_empty());
+    }
+
+    #[test]
+    fn repeat() {
+        let a = Nfa::literal("a");
+        assert_eq!(to_regex(Nfa::repeat(&a, 2, Some(3), true)).find("aaaa"), Some((0, 3)));
+        assert_eq!(to_regex(Nfa::repeat(&a, 2, Some(3), false)).find("aaaa"), Some((0, 2)));
+        assert_eq!(to_regex(Nfa::repeat(&a, 2, Some(3), false)).find("a"), None);
+        assert_eq!(to_regex(Nfa::repeat(&a, 1, None, true)).find("baaa"), Some((1, 4)));
+        assert_eq!(to_regex(Nfa::repeat(&a, 0, None, false)).find("aaa"), Some((0, 0)));
+        assert_eq!(to_regex(Nfa::repeat(&a, 0, Some(0), true)).find("aaa"), Some((0, 0)));
+
+        // The same as `(?:a|ab){2}`.
+        let alt = Nfa::alternate(&[Nfa::literal("a"), Nfa::literal("ab")]);
+        assert_eq!(to_regex(Nfa::repeat(&alt, 2, Some(2), true)).find("abab"), Some((0, 3)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn repeat_bad_range() {
+        Nfa::repeat(&Nfa::literal("a"), 3, Some(2), true);
+    }
 }
 
@@ -6,14 +6,24 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use error::Error;
 use look::Look;
 use num_traits::PrimInt;
-use range_map::{Range, RangeMultiMap};
+use range_map::{Range, RangeMultiMap, RangeSet};
+use std::borrow::Cow;
 use std::fmt::{self, Debug, Formatter};
 use std::marker::PhantomData;
 
+mod builder;
+mod fuzzy;
+mod glob;
 mod has_looks;
 mod no_looks;
+mod utf16;
+
+pub use nfa::builder::NfaBuilder;
+pub use nfa::glob::GlobOptions;
+pub use nfa::has_looks::RegexOptions;
 
 // TODO: it would be nice to make StateIdx a new type instead of a type alias. The problem is that
 // we need to be able to index Vecs with it, and we can't impl<T> Index<StateIdx> for Vec<T>
@@ -54,29 +64,65 @@ impl LookPair {
 /// The enum for determining whether a state is accepting. Classical NFAs would only allow `Never`
 /// and `Always` here, but we also allow `AtEoi`, which means that the state should accept if and
 /// only if we've reached the end of the input.
+///
+/// A state that accepts `AtEoi` usually accepts right at the end of the input, but it may also
+/// accept just before a line terminator that ends the input: that's how `\Z` works. In that case,
+/// the state's look-ahead says so (with `Look::FinalNewLine`), and so does the return value of
+/// any `Dfa` made from it.
+///
+/// `NotAtEoi` is the opposite of `AtEoi`: the state accepts only if there is more input. It never
+/// appears in an `Nfa`; it can only arise from taking the complement or difference of `Dfa`s.
 #[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum Accept {
     Never,
     AtEoi,
     Always,
+    NotAtEoi,
+}
+
+impl Accept {
+    /// Does this accept when there is more input?
+    pub fn accepts_before_eoi(&self) -> bool {
+        *self == Accept::Always || *self == Accept::NotAtEoi
+    }
+
+    /// Does this accept at the end of the input?
+    pub fn accepts_at_eoi(&self) -> bool {
+        *self == Accept::Always || *self == Accept::AtEoi
+    }
+
+    /// The inverse of `accepts_before_eoi` and `accepts_at_eoi`.
+    pub fn from_conditions(before_eoi: bool, at_eoi: bool) -> Accept {
+        match (before_eoi, at_eoi) {
+            (false, false) => Accept::Never,
+            (false, true) => Accept::AtEoi,
+            (true, true) => Accept::Always,
+            (true, false) => Accept::NotAtEoi,
+        }
+    }
 }
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct State<Tok> {
     pub accept: Accept,
-    // If accept == Always and accept_tokens > 0, then we had to do some look-ahead in order to
-    // determine that we have a match. In that case, accept_state is the index of the state that
-    // should have accepted.
+    // If accept_tokens > 0, then we had to do some look-ahead in order to determine that we have
+    // a match. In that case, accept_state is the index of the state that should have accepted.
     accept_state: StateIdx,
     // In the case that we had some look-ahead, `accept_look` says what kind of char was involved
     // in the look-ahead, and `accept_tokens` says how many input tokens were consumed while
     // looking ahead. There are some restrictions on these values:
     // - if accept is Never then accept_look is Full and accept_tokens is zero
-    // - if accept is AtEoi then accept_look is Boundary and accept_tokens is zero
+    // - if accept is AtEoi then either accept_look is Boundary and accept_tokens is zero, or
+    //   accept_look is FinalNewLine and accept_tokens is non-zero (this is the look-ahead state
+    //   for `\Z`, which only accepts if the line terminator it consumed was the last one)
     // - accept_look is never Empty
-    // - if accept is Always then accept_look is neither empty nor Boundary
-    // - if Tok is u32 then accept_tokens is either 0 or 1
+    // - if accept is Always then accept_look is neither Empty, Boundary nor FinalNewLine
+    // - if Tok is u32 then accept_tokens is either 0 or 1, except that it can be 2 for the
+    //   look-ahead state for `\Z` (which consumes a `"\r\n"` in CRLF mode)
     // - if Tok is u8 then accept_tokens is at most 4.
+    //
+    // Every char of look-ahead except the last one is part of a line terminator, so it is a
+    // single token in every encoding.
     accept_look: Look,
     accept_tokens: u8,
 
@@ -120,9 +166,13 @@ pub struct State<Tok> {
 ///
 /// The typical life-cycle of an `Nfa` is as follows:
 ///
-/// - First, create an `Nfa<u32, HasLooks>` using `from_regex`.
-/// - Call `nfa.remove_looks()` to turn the `Nfa<u32, HasLooks>` to an `Nfa<u32, NoLooks>`.
-/// - Call `nfa.byte_me()` to turn the `Nfa<u32, NoLooks>` into an `Nfa<u8, NoLooks>`.
+/// - First, create an `Nfa<u32, HasLooks>` using `from_regex` or `from_glob` (or by
+///   hand, using `NfaBuilder`).
+/// - Call `nfa.remove_looks()` to turn the `Nfa<u32, HasLooks>` to an `Nfa<u32, NoLooks>` (or
+///   `nfa.remove_looks_with()` if lines don't end with `'\n'`).
+/// - Call `nfa.byte_me()` to turn the `Nfa<u32, NoLooks>` into an `Nfa<u8, NoLooks>` (or
+///   `nfa.byte_me_with()` to use Latin-1 instead of UTF-8, or `nfa.utf16_me()` to turn it into
+///   an `Nfa<u16, NoLooks>` that consumes UTF-16 code units).
 /// - Call one of the `nfa.determinize_*()` methods to make a `Dfa`.
 ///
 /// There are also some operations modifying `Nfa<u8, NoLooks>` that can be called between the last
@@ -143,6 +193,87 @@ pub struct Nfa<Tok, Variant> {
 
 pub trait Lookability {}
 
+/// The ways that an `Nfa` consuming chars can be turned into one consuming bytes.
+///
+/// See `Nfa::byte_me_with`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Each char is encoded as UTF-8.
+    Utf8,
+    /// Each char below 256 is a single byte, and there are no other chars. This is the encoding
+    /// called ISO-8859-1.
+    Latin1,
+}
+
+/// The chars that end a line, for the purposes of `^` and `$` in multi-line mode.
+///
+/// This is what `Look::NewLine` stands for in an `Nfa`. See `Nfa::remove_looks_with`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LineTerminator {
+    /// Lines end with a single byte, which must be an ASCII char that isn't a word char (for
+    /// example, `b'\0'` for the output of `find -print0`).
+    Byte(u8),
+    /// Lines end with `"\r\n"`, or with a `'\r'` or `'\n'` on its own. A `"\r\n"` is a single
+    /// line terminator, so `^` and `$` never match between its `'\r'` and its `'\n'`: (for
+    /// example) `(?m)^$` doesn't match anywhere in `"a\r\nb"`.
+    Crlf,
+}
+
+impl Default for LineTerminator {
+    fn default() -> LineTerminator {
+        LineTerminator::Byte(b'\n')
+    }
+}
+
+impl LineTerminator {
+    /// Returns the bytes that can end a line.
+    pub fn bytes(&self) -> Vec<u8> {
+        match *self {
+            LineTerminator::Byte(b) => vec![b],
+            LineTerminator::Crlf => vec![b'\r', b'\n'],
+        }
+    }
+
+    // Returns the bytes that a line always starts after. In CRLF mode that's only `'\n'`, since
+    // a line only starts after a `'\r'` if no `'\n'` comes next; that case has its own initial
+    // states, with `Look::CarriageReturn` (see `Nfa::remove_looks_with`).
+    fn line_start_bytes(&self) -> Vec<u8> {
+        match *self {
+            LineTerminator::Byte(b) => vec![b],
+            LineTerminator::Crlf => vec![b'\n'],
+        }
+    }
+
+    // Returns the chars that `look` stands for, when lines end with `self`.
+    fn look_set(&self, look: Look) -> Cow<'static, RangeSet<u32>> {
+        if look == Look::NewLine || look == Look::FinalNewLine {
+            Cow::Owned(self.bytes().into_iter().map(|b| Range::single(b as u32)).collect())
+        } else {
+            Cow::Borrowed(look.as_set())
+        }
+    }
+
+    // In CRLF mode, says whether `look` needs to know if a `'\r'` is followed by a `'\n'` (which
+    // is the case whenever it looks for the start or end of a line).
+    fn splits_crlf(&self, look: &LookPair) -> bool {
+        let is_line = |l: Look| l == Look::NewLine || l == Look::FinalNewLine;
+        *self == LineTerminator::Crlf && (is_line(look.behind) || is_line(look.ahead))
+    }
+
+    // Checks that the line terminator is allowed. Since `Look::NewLine` has to be a subset of
+    // `Look::NotWordChar` and `Look::NotAsciiWordChar`, word chars can't end lines; also, a
+    // line terminator has to be a single byte in every encoding.
+    fn check(&self) -> ::Result<()> {
+        if let LineTerminator::Byte(b) = *self {
+            let c = b as char;
+            if b >= 0x80 || c.is_alphanumeric() || c == '_' {
+                return Err(Error::Unsupported(format!("the line terminator {:?}", c)));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub struct HasLooks;
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -203,7 +334,7 @@ impl<Tok: Debug + PrimInt, L: Lookability> Nfa<Tok, L> {
     /// ahead a few tokens.
     pub fn add_look_ahead_state(&mut self, look: Look, tokens: u8, accept_state: StateIdx)
     -> StateIdx {
-        debug_assert!(look != Look::Boundary && look != Look::Full && look != Look::Empty);
+        debug_assert!(look != Look::Boundary && look != Look::Empty);
         debug_assert!(tokens > 0);
 
         let state_idx = self.states.len();
@@ -6,11 +6,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use dfa::Dfa;
+use dfa::{Dfa, RetTrait, TokTrait};
 use error::Error;
 use itertools::Itertools;
 use look::Look;
-use nfa::{Accept, Nfa, NoLooks, State, StateIdx, StateSet};
+use nfa::{Accept, Encoding, LineTerminator, Nfa, NoLooks, State, StateIdx, StateSet};
+use nfa::utf16::add_char_units;
 use num_traits::PrimInt;
 use range_map::{Range, RangeMap, RangeMultiMap};
 use std::{char, u8, usize};
@@ -18,7 +19,7 @@ use std::cmp::max;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::marker::PhantomData;
-use std::mem::swap;
+use std::mem::{self, swap};
 use utf8_ranges::{Utf8Range, Utf8Sequence, Utf8Sequences};
 
 // This provides a more compact way of representing UTF-8 sequences.
@@ -109,7 +110,7 @@ impl MergedUtf8Sequences {
 }
 
 // Creates a byte-based Dfa that matches all the chars in `look.as_set()`.
-fn make_char_dfa(look: Look) -> Dfa<(Look, u8)> {
+fn make_char_dfa(look: Look, encoding: Encoding) -> Dfa<(Look, u8)> {
     let mut nfa: Nfa<u32, NoLooks> = Nfa::with_capacity(2);
     nfa.add_state(Accept::Never);
     nfa.add_look_ahead_state(look, 1, 0);
@@ -119,8 +120,9 @@ fn make_char_dfa(look: Look) -> Dfa<(Look, u8)> {
     nfa.states[0].consuming
         = RangeMultiMap::from_vec(look.as_set().ranges().map(|x| (x, 1)).collect());
 
-    // These unwraps are OK because the only failures are caused by having too many states.
-    nfa.byte_me(usize::MAX).unwrap()
+    // These unwraps are OK because the only failures are caused by having too many states (and
+    // by having chars that aren't in Latin-1, but every look includes some Latin-1 chars).
+    nfa.byte_me_with(encoding, usize::MAX).unwrap()
         .determinize(usize::MAX).unwrap()
         .optimize()
 }
@@ -157,16 +159,95 @@ fn make_rev_char_dfa(look: Look) -> Dfa<(Look, u8)> {
         .optimize()
 }
 
+// Returns true if `look` is a set of chars that we need to check, as opposed to `Full` (which
+// allows anything), or `Boundary` and `Empty` (which don't contain any chars). `FinalNewLine` is
+// handled in the same way as `NewLine`, without a char Dfa.
+fn is_char_look(look: Look) -> bool {
+    !look.is_full() && !look.as_set().is_empty() && look != Look::FinalNewLine
+}
+
+// Makes a Dfa for each `look` such that `is_char_look(look)`, indexed by `look.as_usize()`.
+fn make_char_dfas<F>(make: F) -> Vec<Option<Dfa<(Look, u8)>>> where F: Fn(Look) -> Dfa<(Look, u8)> {
+    let mut ret: Vec<Option<Dfa<(Look, u8)>>> = (0..Look::num()).map(|_| None).collect();
+    for &look in Look::all().iter().filter(|&&look| is_char_look(look)) {
+        ret[look.as_usize()] = Some(make(look));
+    }
+    ret
+}
+
 // We cache optimized Dfas for the expensive looks. See `Nfa<u8, NoLooks>::add_min_utf8_sequences`
 // for an explanation.
 lazy_static! {
-    static ref WORD_CHAR_DFA: Dfa<(Look, u8)> = make_char_dfa(Look::WordChar);
-    static ref NOT_WORD_CHAR_DFA: Dfa<(Look, u8)> = make_char_dfa(Look::NotWordChar);
-    static ref REV_WORD_CHAR_DFA: Dfa<(Look, u8)> = make_rev_char_dfa(Look::WordChar);
-    static ref REV_NOT_WORD_CHAR_DFA: Dfa<(Look, u8)> = make_rev_char_dfa(Look::NotWordChar);
+    static ref CHAR_DFAS: Vec<Option<Dfa<(Look, u8)>>> =
+        make_char_dfas(|look| make_char_dfa(look, Encoding::Utf8));
+    static ref REV_CHAR_DFAS: Vec<Option<Dfa<(Look, u8)>>> = make_char_dfas(make_rev_char_dfa);
+    // In Latin-1, every char is a single byte, so these work both forwards and backwards.
+    static ref LATIN1_CHAR_DFAS: Vec<Option<Dfa<(Look, u8)>>> =
+        make_char_dfas(|look| make_char_dfa(look, Encoding::Latin1));
+}
+
+// Returns a Dfa matching a single char of `look` (backwards, if `reversed` is true).
+//
+// Panics unless `is_char_look(look)`.
+fn char_dfa(look: Look, encoding: Encoding, reversed: bool) -> &'static Dfa<(Look, u8)> {
+    let dfas: &'static Vec<Option<Dfa<(Look, u8)>>> = match (encoding, reversed) {
+        (Encoding::Utf8, false) => &*CHAR_DFAS,
+        (Encoding::Utf8, true) => &*REV_CHAR_DFAS,
+        (Encoding::Latin1, _) => &*LATIN1_CHAR_DFAS,
+    };
+    dfas[look.as_usize()].as_ref().expect("BUG: no char Dfa for this look")
 }
 
 impl<Tok: Debug + PrimInt> Nfa<Tok, NoLooks> {
+    /// Checks that the invariants that are supposed to hold do.
+    ///
+    /// These are the restrictions on the states' `accept` fields that are described in `State`,
+    /// together with the requirements that there are no look transitions and that every
+    /// transition (and every initial state) refers to a state that exists.
+    pub fn check_invariants(&self) -> ::Result<()> {
+        let invalid = |msg: String| Err(Error::InvalidNfa(msg));
+        let len = self.states.len();
+        let max_tokens = if mem::size_of::<Tok>() == 1 { 4 } else { 1 };
+        // In CRLF mode, `\Z` looks ahead at two chars.
+        let max_final_tokens = max(max_tokens, 2);
+
+        if let Some(&(_, idx)) = self.init.iter().find(|pair| pair.1 >= len) {
+            return invalid(format!("the initial state {} doesn't exist", idx));
+        }
+        for (idx, st) in self.states.iter().enumerate() {
+            if !st.looking.is_empty() {
+                return invalid(format!("state {} has look transitions", idx));
+            }
+            if let Some(&(_, tgt)) = st.consuming.ranges_values().find(|x| x.1 >= len) {
+                return invalid(format!("state {} has a transition to the non-existent state {}",
+                                       idx, tgt));
+            }
+            if st.accept_state >= len {
+                return invalid(format!("state {} accepts on behalf of the non-existent state {}",
+                                       idx, st.accept_state));
+            }
+
+            let accept_ok = match st.accept {
+                Accept::Never => st.accept_look == Look::Full && st.accept_tokens == 0,
+                Accept::AtEoi =>
+                    (st.accept_look == Look::Boundary && st.accept_tokens == 0)
+                        || (st.accept_look == Look::FinalNewLine && st.accept_tokens > 0
+                            && st.accept_tokens <= max_final_tokens),
+                Accept::Always =>
+                    st.accept_look != Look::Empty && st.accept_look != Look::Boundary
+                        && st.accept_look != Look::FinalNewLine
+                        && st.accept_tokens <= max_tokens,
+                Accept::NotAtEoi => false,
+            };
+            if !accept_ok {
+                return invalid(format!("state {} has inconsistent acceptance conditions ({:?}, \
+                                        look {:?}, tokens {})",
+                                       idx, st.accept, st.accept_look, st.accept_tokens));
+            }
+        }
+        Ok(())
+    }
+
     // Returns the set of all states that can be reached from some initial state.
     fn reachable_from<I>(&self, states: I) -> HashSet<StateIdx> where I: Iterator<Item=StateIdx> {
         let mut active: HashSet<StateIdx> = states.collect();
@@ -218,7 +299,15 @@ impl<Tok: Debug + PrimInt> Nfa<Tok, NoLooks> {
     /// Optimizes this Nfa by removing all states that cannot be reached from an initial state
     /// and all states that cannot lead to an accepting state.
     pub fn trim_unreachable(&mut self) {
-        let reachable = self.reachable_states();
+        let mut reachable = self.reachable_states();
+        // A look-ahead state refers to the state that it accepts on behalf of, so we have to keep
+        // that one too. It might not be reachable any more: `anchor_with` takes over the
+        // transitions of the initial states, so a look-ahead state that came after an initial
+        // state can outlive it.
+        let accept_states: Vec<StateIdx> = reachable.iter()
+            .map(|&i| self.states[i].accept_state)
+            .collect();
+        reachable.extend(accept_states);
 
         let mut old_states = Vec::new();
         swap(&mut self.states, &mut old_states);
@@ -241,11 +330,143 @@ impl<Tok: Debug + PrimInt> Nfa<Tok, NoLooks> {
     fn accept_union(&self, states: &StateSet) -> Accept {
         states.iter().map(|s| self.states[*s].accept).max().unwrap_or(Accept::Never)
     }
+
+    /// Can we accept immediately if the beginning of the input matches `look`?
+    fn init_accept(&self, look: Look) -> Accept {
+        let set = self.init.iter()
+            .filter(|pair| look <= pair.0)
+            .map(|pair| pair.1)
+            .collect::<Vec<_>>();
+        self.accept_union(&set)
+    }
+
+    // Reverses this `Nfa`, whatever its tokens encode.
+    //
+    // For an initial state `i` whose look needs to see a char, `look_behind(ret, i, look)` should
+    // make `i` accept in the reversal `ret` after reading (backwards) a char of `look`, and also
+    // at the beginning of the input if `look` allows it.
+    fn reverse_by<F>(&self, mut look_behind: F) -> ::Result<Nfa<Tok, NoLooks>>
+    where F: FnMut(&mut Nfa<Tok, NoLooks>, StateIdx, Look) -> ::Result<()> {
+        let mut ret = self.reversed_simple();
+
+        // Turn our initial states into ret's accepting states.
+        for &(look, i) in &self.init {
+            match look {
+                Look::Full => {
+                    ret.states[i].accept = Accept::Always;
+                    ret.states[i].accept_look = Look::Full;
+                },
+                Look::Boundary => {
+                    ret.states[i].accept = max(ret.states[i].accept, Accept::AtEoi);
+                    ret.states[i].accept_look = max(ret.states[i].accept_look, Look::Boundary);
+                },
+                Look::Empty => {
+                    panic!("Empty cannot be an init look");
+                },
+                _ => try!(look_behind(&mut ret, i, look)),
+            }
+        }
+
+        // Turn our accepting states into ret's initial states.
+        ret.init.clear();
+        for st in &self.states {
+            if st.accept != Accept::Never {
+                ret.init.push((st.accept_look, st.accept_state));
+            }
+        }
+        Ok(ret)
+    }
+
+    // Adds a `^.*`-like loop at the beginning, and an initial state for each look in `contexts`
+    // (which must not contain `Look::Full`). The loop state doubles as the initial state for
+    // `Look::Full`, which is only used if `contexts` has more than just `Look::Boundary`.
+    //
+    // `add_char(nfa, state, look, target)` should add transitions from `state` to `target` that
+    // read a single char of `look`.
+    fn anchor_by<F>(mut self, contexts: &[Look], mut add_char: F) -> ::Result<Nfa<Tok, NoLooks>>
+    where F: FnMut(&mut Nfa<Tok, NoLooks>, StateIdx, Look, StateIdx) -> ::Result<()> {
+        let loop_accept = self.init_accept(Look::Full);
+        let loop_state = self.add_state(loop_accept);
+        let mut context_states = vec![(Look::Full, loop_state)];
+        for &context in contexts {
+            let accept = self.init_accept(context);
+            let state = self.add_state(accept);
+            context_states.push((context, state));
+        }
+
+        // Swap out init so that we can iterate over it while modifying `self`.
+        let mut init = Vec::new();
+        swap(&mut init, &mut self.init);
+
+        for &(look, st_idx) in &init {
+            // If the previous char is described by `context`, we can go straight into `st_idx`.
+            for &(context, state) in &context_states {
+                if context <= look {
+                    // TODO: shouldn't need to clone here.
+                    for &(range, target) in self.states[st_idx].consuming.clone().ranges_values() {
+                        self.add_transition(state, target, range);
+                    }
+                }
+            }
+
+            // Otherwise, we can go into `st_idx` after reading a char that satisfies `look`.
+            match look {
+                Look::Boundary | Look::Full => {},
+                Look::Empty => {
+                    panic!("Cannot start with an empty look");
+                },
+                _ => {
+                    for &(_, state) in &context_states {
+                        try!(add_char(&mut self, state, look, st_idx));
+                    }
+                },
+            }
+
+            // Once we've found an init state that accepts immediately, don't look for any others
+            // (since any matches that we find starting from them are lower priority that the one
+            // we've found already). This check is *almost* unnecessary, since similar pruning
+            // happens when we turn the NFA into a DFA. The important case that needs to be handled
+            // here is the case that a high-priority init state has no transitions out of it. Such
+            // a state will be completely removed by this function, and so we need to acknowledge
+            // its existence here.
+            if self.states[st_idx].accept == Accept::Always {
+                break;
+            }
+        }
+
+        // Wire up the initial and loop states, but only if they aren't accepting. That's because
+        // if they are accepting then the accept should take priority over the transition (since
+        // making the transition means that we are searching for a match that starts later).
+        for &(_, state) in &context_states {
+            if self.states[state].accept != Accept::Always {
+                self.add_transition(state, loop_state, Range::full());
+            }
+        }
+
+        // The new Nfa is only allowed to start at the new initial states (and at the loop state,
+        // if we're starting in the middle of the input).
+        self.init = context_states.iter().skip(1).cloned().collect();
+        if contexts.len() > 1 {
+            self.init.push((Look::Full, loop_state));
+        }
+        self.trim_unreachable();
+        Ok(self)
+    }
 }
 
 impl Nfa<u32, NoLooks> {
-    /// Converts this `Nfa` into one that consumes the input byte-by-byte.
+    /// Converts this `Nfa` into one that consumes the input byte-by-byte, encoded as UTF-8.
     pub fn byte_me(self, max_states: usize) -> ::Result<Nfa<u8, NoLooks>> {
+        self.byte_me_with(Encoding::Utf8, max_states)
+    }
+
+    /// Converts this `Nfa` into one that consumes the input byte-by-byte, in the given encoding.
+    ///
+    /// With `Encoding::Latin1`, every char below 256 becomes a single byte and all other chars
+    /// are dropped. It is an error if some transition consumes only chars that aren't in Latin-1
+    /// (for example, because the regex contained `€`).
+    pub fn byte_me_with(self, encoding: Encoding, max_states: usize)
+    -> ::Result<Nfa<u8, NoLooks>> {
         let mut ret = Nfa::<u8, NoLooks> {
             states: self.states.iter().map(|s| State {
                 accept: s.accept,
@@ -264,16 +485,32 @@ impl Nfa<u32, NoLooks> {
             // can merge a bunch of Utf8Sequences before adding them, which saves a bunch of
             // states.
             for (tgt, transitions) in state.consuming.ranges_values().group_by(|x| x.1) {
-                try!(ret.add_utf8_sequences(i, transitions.into_iter().map(|x| x.0), tgt, max_states));
+                match encoding {
+                    Encoding::Utf8 => try!(ret.add_utf8_sequences(
+                        i, transitions.into_iter().map(|x| x.0), tgt, max_states)),
+                    Encoding::Latin1 => try!(ret.add_latin1_ranges(
+                        i, transitions.into_iter().map(|x| x.0), tgt)),
+                }
             }
         }
         Ok(ret)
     }
 }
 
-impl Nfa<u8, NoLooks> {
+impl<Tok: TokTrait> Nfa<Tok, NoLooks> {
+    /// Checks whether this `Nfa` has any look-around left: that is, whether some initial state
+    /// needs to see something before it, or some accepting state needs to see something after it.
+    pub fn has_look_around(&self) -> bool {
+        self.init.iter().any(|&(look, _)| look != Look::Full)
+            || self.states.iter().any(|st| {
+                st.accept != Accept::Never
+                    && (st.accept != Accept::Always || st.accept_look != Look::Full
+                        || st.accept_tokens > 0)
+            })
+    }
+
     /// Converts this `Nfa` into a `Dfa`.
-    pub fn determinize(&self, max_states: usize) -> ::Result<Dfa<(Look, u8)>> {
+    pub fn determinize(&self, max_states: usize) -> ::Result<Dfa<(Look, u8), Tok>> {
         Determinizer::determinize(self, max_states, MatchChoice::TransitionOrder, self.init.clone())
     }
 
@@ -282,10 +519,116 @@ impl Nfa<u8, NoLooks> {
     /// Whenever this `Nfa` matches some text, the `Dfa` also will. But if this `Nfa` has multiple
     /// possible endpoints for a match then the returned `Dfa` is only guaranteed to match the
     /// longest one.
-    pub fn determinize_longest(&self, max_states: usize) -> ::Result<Dfa<(Look, u8)>> {
+    pub fn determinize_longest(&self, max_states: usize) -> ::Result<Dfa<(Look, u8), Tok>> {
         Determinizer::determinize(self, max_states, MatchChoice::LongestMatch, self.init.clone())
     }
 
+    /// Like `determinize` (or `determinize_longest`, if `longest` is true), but the `Dfa` also
+    /// returns a number whenever it accepts.
+    ///
+    /// The number is the smallest value of `weight(state.accept_state)`, where `state` ranges over
+    /// the states of this `Nfa` that accept there (with the same amount of look-ahead). This is
+    /// how the reversal of a fuzzy `Nfa` tells how many edits a match needed (see
+    /// `fuzzy_reversible`).
+    pub fn determinize_weighted<F>(&self, longest: bool, max_states: usize, weight: F)
+    -> ::Result<Dfa<(Look, u8, u32), Tok>>
+    where F: Fn(StateIdx) -> u32 {
+        let choice = if longest { MatchChoice::LongestMatch } else { MatchChoice::TransitionOrder };
+        let mut det = Determinizer::new(self, max_states, choice);
+        try!(det.run(self.init.clone()));
+
+        let mut sets = vec![Vec::new(); det.dfa.num_states()];
+        for (set, idx) in det.state_map {
+            sets[idx] = set;
+        }
+
+        let dfa = det.dfa;
+        let mut ret = Dfa::new();
+        ret.init = dfa.init.clone();
+        for (idx, set) in sets.iter().enumerate() {
+            let acc = *dfa.accept(idx);
+            let ret_val = dfa.ret(idx).map(|&(look, bytes)| {
+                let w = set.iter()
+                    .map(|&i| &self.states[i])
+                    .filter(|st| st.accept != Accept::Never && st.accept_tokens == bytes)
+                    .filter(|st| acc == Accept::AtEoi || st.accept != Accept::AtEoi)
+                    .map(|st| weight(st.accept_state))
+                    .min();
+                (look, bytes, w.unwrap_or(0))
+            });
+            ret.add_state(acc, ret_val);
+            ret.set_transitions(idx, dfa.transitions(idx).clone());
+        }
+        Ok(ret)
+    }
+
+    /// Returns a `Dfa` whose language is the set of strings that this `Nfa` matches in their
+    /// entirety.
+    ///
+    /// The returned `Dfa` only has an initial state for `Look::Boundary`, and all of its accepting
+    /// states are `Accept::AtEoi`: a string belongs to its language if, starting from the
+    /// beginning of the string, it accepts at the end of the string.
+    pub fn whole_match_dfa(&self, max_states: usize) -> ::Result<Dfa<(), Tok>> {
+        // At the beginning of the input, we may start from any initial state that doesn't need
+        // to see a char before it.
+        let init = self.init.iter()
+            .filter(|pair| pair.0.allows_eoi())
+            .map(|pair| (Look::Boundary, pair.1))
+            .collect();
+        let choice = MatchChoice::LongestMatch;
+        let dfa = try!(Determinizer::determinize(self, max_states, choice, init));
+
+        let mut ret = Dfa::new();
+        ret.init = dfa.init.clone();
+        for idx in 0..dfa.num_states() {
+            // If we accepted after looking ahead, then the match ended some bytes ago. (For
+            // `Accept::AtEoi`, that only happens if we looked ahead for `\Z`.)
+            let whole = match *dfa.accept(idx) {
+                Accept::AtEoi => dfa.ret(idx).map_or(true, |&(look, _)| look != Look::FinalNewLine),
+                Accept::Always => dfa.ret(idx).map_or(false, |&(_, bytes)| bytes == 0),
+                _ => false,
+            };
+            if whole {
+                ret.add_state(Accept::AtEoi, Some(()));
+            } else {
+                ret.add_state(Accept::Never, None);
+            }
+            ret.set_transitions(idx, dfa.transitions(idx).clone());
+        }
+        Ok(ret.cut_dead_ends().optimize())
+    }
+
+    // Finds the transitions out of the given set of states, as a RangeMap.
+    fn transition_map(&self, states: &[StateIdx]) -> RangeMap<Tok, Vec<usize>> {
+        let mut transitions = states.into_iter()
+            .flat_map(|s| self.states[*s].consuming.ranges_values().cloned())
+            .collect::<RangeMultiMap<Tok, StateIdx>>()
+            .group();
+
+        // `scratch` is large enough to be indexed by anything in `elts`. It is full of `false`.
+        fn uniquify(elts: &mut Vec<StateIdx>, scratch: &mut Vec<bool>) {
+            elts.retain(|&e| {
+                let ret = !scratch[e];
+                scratch[e] = true;
+                ret
+            });
+
+            // Clean up scratch, so that it is full of `false` again.
+            for e in elts {
+                scratch[*e] = false;
+            }
+        }
+
+        let mut scratch = vec![false; self.num_states()];
+        for pair in transitions.as_mut_slice() {
+            uniquify(&mut pair.1, &mut scratch);
+        }
+
+        transitions
+    }
+}
+
+impl Nfa<u8, NoLooks> {
     /// Returns the reversal of this `Nfa`.
     ///
     /// If `self` matches some string of bytes, then the return value of this method will match
@@ -293,136 +636,132 @@ impl Nfa<u8, NoLooks> {
     ///
     /// Note that this loses information about match priorities.
     pub fn reverse(&self, max_states: usize) -> ::Result<Nfa<u8, NoLooks>> {
-        let mut ret = self.reversed_simple();
+        self.reverse_with(Encoding::Utf8, LineTerminator::default(), max_states)
+    }
 
-        // Turn our initial states into ret's accepting states.
-        for &(look, i) in &self.init {
+    /// Like `reverse`, but for an `Nfa` that was made by `byte_me_with` using `encoding`, and
+    /// whose lines end with `terminator` (see `remove_looks_with`).
+    pub fn reverse_with(&self, encoding: Encoding, terminator: LineTerminator, max_states: usize)
+    -> ::Result<Nfa<u8, NoLooks>> {
+        self.reverse_by(|ret, i, look| {
             match look {
-                Look::Full => {
-                    ret.states[i].accept = Accept::Always;
-                    ret.states[i].accept_look = Look::Full;
-                },
-                Look::Boundary => {
-                    ret.states[i].accept = max(ret.states[i].accept, Accept::AtEoi);
-                    ret.states[i].accept_look = max(ret.states[i].accept_look, Look::Boundary);
-                },
-                Look::NewLine => {
+                // `FinalNewLine` is only meant for looking ahead (at `\Z`), so nothing makes it
+                // into a look-behind. If something did, treating it like `NewLine` is the best
+                // that we can do with one char of context.
+                Look::NewLine | Look::FinalNewLine => {
                     let accept_state = ret.add_look_ahead_state(Look::NewLine, 1, i);
-                    ret.add_transition(i, accept_state, Range::new(b'\n', b'\n'));
+                    for b in terminator.line_start_bytes() {
+                        ret.add_transition(i, accept_state, Range::single(b));
+                    }
                     ret.states[i].accept = max(ret.states[i].accept, Accept::AtEoi);
                     ret.states[i].accept_look = max(ret.states[i].accept_look, Look::Boundary);
                 },
-                Look::WordChar | Look::NotWordChar => {
-                    // It would make more sense to put this outside the loop, but having it inside
-                    // prevents a deadlock: constructing REV_*_DFA ends up calling reverse(), but
-                    // with no look-ahead so it never gets inside this loop.
-                    let dfa: &Dfa<_> = if look == Look::WordChar {
-                        &REV_WORD_CHAR_DFA
-                    } else {
+                _ => {
+                    // It would make more sense to get the Dfa outside the loop, but having it
+                    // inside prevents a deadlock: constructing REV_CHAR_DFAS ends up calling
+                    // reverse(), but with no look-ahead so it never gets inside this loop.
+                    let dfa = char_dfa(look, encoding, true);
+                    if look.allows_eoi() {
                         ret.states[i].accept = max(ret.states[i].accept, Accept::AtEoi);
                         ret.states[i].accept_look = max(ret.states[i].accept_look, Look::Boundary);
-                        &REV_NOT_WORD_CHAR_DFA
-                    };
+                    }
                     let accept_state = ret.add_look_ahead_state(look, 1, i);
                     try!(ret.add_min_utf8_sequences(i, dfa, accept_state, max_states));
                 },
-                Look::Empty => {
-                    panic!("Empty cannot be an init look");
-                },
             }
-        }
-
-        // Turn our accepting states into ret's initial states.
-        ret.init.clear();
-        for st in &self.states {
-            if st.accept != Accept::Never {
-                ret.init.push((st.accept_look, st.accept_state));
-            }
-        }
-        Ok(ret)
-    }
-
-    /// Can we accept immediately if the beginning of the input matches `look`?
-    fn init_accept(&self, look: Look) -> Accept {
-        let set = self.init.iter()
-            .filter(|pair| look <= pair.0)
-            .map(|pair| pair.1)
-            .collect::<Vec<_>>();
-        self.accept_union(&set)
+            Ok(())
+        })
     }
 
     /// This essentially modifies `self` by adding a `^.*` at the beginning.
     ///
     /// The result is actually a little bit different, because `.` matches a whole code point,
     /// whereas the `^.*` that we add works at the byte level.
-    pub fn anchor(mut self, max_states: usize) -> ::Result<Nfa<u8, NoLooks>> {
-        let loop_accept = self.init_accept(Look::Full);
-        let loop_state = self.add_state(loop_accept);
-        let init_accept = self.init_accept(Look::Boundary);
-        let init_state = self.add_state(init_accept);
+    pub fn anchor(self, max_states: usize) -> ::Result<Nfa<u8, NoLooks>> {
+        self.anchor_with(Encoding::Utf8, LineTerminator::default(), max_states)
+    }
 
-        // Swap out init so that we can iterate over it while modifying `self`.
-        let mut init = Vec::new();
-        swap(&mut init, &mut self.init);
+    /// Like `anchor`, but for an `Nfa` that was made by `byte_me_with` using `encoding`, and
+    /// whose lines end with `terminator` (see `remove_looks_with`).
+    pub fn anchor_with(self, encoding: Encoding, terminator: LineTerminator, max_states: usize)
+    -> ::Result<Nfa<u8, NoLooks>> {
+        self.anchor_contexts(&[Look::Boundary], encoding, terminator, max_states)
+    }
 
-        for &(look, st_idx) in &init {
-            if look.allows_eoi() {
-                // TODO: shouldn't need to clone here.
-                for &(range, target) in self.states[st_idx].consuming.clone().ranges_values() {
-                    self.add_transition(init_state, target, range);
-                }
-            }
+    /// Like `anchor_with`, but the new `Nfa` can also start in the middle of the input.
+    ///
+    /// There is an initial state for each of `Look::Boundary`, `Look::NewLine`,
+    /// `Look::AsciiWordChar`, `Look::NonAsciiWordChar`, `Look::NotWordChar` and `Look::Full`: to
+    /// start searching at some position, choose the initial state describing the char just before
+    /// that position (using `Look::Full` if it isn't a valid char).
+    ///
+    /// With `LineTerminator::Crlf`, there is also an initial state for `Look::CarriageReturn`, and
+    /// `Look::NotWordCharNotCr` replaces `Look::NotWordChar`. Note that `Look::NewLine` then
+    /// only describes a `'\n'`.
+    pub fn anchor_by_prev_char(self,
+                               encoding: Encoding,
+                               terminator: LineTerminator,
+                               max_states: usize)
+    -> ::Result<Nfa<u8, NoLooks>> {
+        let contexts = if terminator == LineTerminator::Crlf {
+            vec![Look::Boundary, Look::NewLine, Look::CarriageReturn, Look::AsciiWordChar,
+                Look::NonAsciiWordChar, Look::NotWordCharNotCr]
+        } else {
+            vec![Look::Boundary, Look::NewLine, Look::AsciiWordChar, Look::NonAsciiWordChar,
+                Look::NotWordChar]
+        };
+        self.anchor_contexts(&contexts, encoding, terminator, max_states)
+    }
 
+    // Adds a `^.*`-like loop at the beginning, and an initial state for each look in `contexts`
+    // (which must not contain `Look::Full`), using the way that `encoding` encodes chars.
+    fn anchor_contexts(self,
+                       contexts: &[Look],
+                       encoding: Encoding,
+                       terminator: LineTerminator,
+                       max_states: usize)
+    -> ::Result<Nfa<u8, NoLooks>> {
+        self.anchor_by(contexts, |nfa, state, look, st_idx| {
             match look {
-                Look::Boundary => {},
-                Look::Full => {
-                    for &(range, target) in self.states[st_idx].consuming.clone().ranges_values() {
-                        self.add_transition(loop_state, target, range);
+                Look::NewLine | Look::FinalNewLine => {
+                    for b in terminator.line_start_bytes() {
+                        nfa.add_transition(state, st_idx, Range::single(b));
                     }
+                    Ok(())
                 },
-                Look::NewLine => {
-                    self.add_transition(init_state, st_idx, Range::new(b'\n', b'\n'));
-                    self.add_transition(loop_state, st_idx, Range::new(b'\n', b'\n'));
-                },
-                Look::WordChar | Look::NotWordChar => {
-                    let dfa: &Dfa<_> =
-                        if look == Look::WordChar { &WORD_CHAR_DFA } else { &NOT_WORD_CHAR_DFA };
-
-                    try!(self.add_min_utf8_sequences(loop_state, dfa, st_idx, max_states));
-                    try!(self.add_min_utf8_sequences(init_state, dfa, st_idx, max_states));
-                },
-                Look::Empty => {
-                    panic!("Cannot start with an empty look");
+                _ => {
+                    let dfa = char_dfa(look, encoding, false);
+                    nfa.add_min_utf8_sequences(state, dfa, st_idx, max_states)
                 },
             }
+        })
+    }
 
-            // Once we've found an init state that accepts immediately, don't look for any others
-            // (since any matches that we find starting from them are lower priority that the one
-            // we've found already). This check is *almost* unnecessary, since similar pruning
-            // happens when we turn the NFA into a DFA. The important case that needs to be handled
-            // here is the case that a high-priority init state has no transitions out of it. Such
-            // a state will be completely removed by this function, and so we need to acknowledge
-            // its existence here.
-            if self.states[st_idx].accept == Accept::Always {
-                break;
+    /// Returns an `Nfa` that matches (anywhere in the input) the strings in the language of `dfa`.
+    ///
+    /// The language of `dfa` is the set of strings that it accepts at the end of the input, when
+    /// starting from its `Look::Boundary` initial state. This is the inverse of
+    /// `whole_match_dfa`, except that the new `Nfa` doesn't have any look-around: a match may
+    /// start and end anywhere.
+    pub fn from_language<Ret: RetTrait>(dfa: &Dfa<Ret>) -> Nfa<u8, NoLooks> {
+        let mut ret = Nfa::with_capacity(dfa.num_states());
+        for idx in 0..dfa.num_states() {
+            if dfa.accept(idx).accepts_at_eoi() {
+                ret.add_state(Accept::Always);
+            } else {
+                ret.add_state(Accept::Never);
             }
         }
-
-        // Wire up the initial and loop states, but only if they aren't accepting. That's because
-        // if they are accepting then the accept should take priority over the transition (since
-        // making the transition means that we are searching for a match that starts later).
-        if init_accept != Accept::Always {
-            self.add_transition(init_state, loop_state, Range::full());
+        for idx in 0..dfa.num_states() {
+            for &(range, tgt) in dfa.transitions(idx).ranges_values() {
+                ret.add_transition(idx, tgt, range);
+            }
         }
-        if loop_accept != Accept::Always {
-            self.add_transition(loop_state, loop_state, Range::full());
+        if let Some(init) = dfa.init_at_start() {
+            ret.init.push((Look::Full, init));
         }
-
-        // The new Nfa is only allowed to start at the beginning of the input, and only at the new
-        // initial state.
-        self.init.push((Look::Boundary, init_state));
-        self.trim_unreachable();
-        Ok(self)
+        ret.trim_unreachable();
+        ret
     }
 
     // This does the same thing as add_utf8_sequences, but it gets the transitions from a dfa,
@@ -492,7 +831,12 @@ impl Nfa<u8, NoLooks> {
         if self.states[end_state].accept_tokens > 0 {
             let look = self.states[end_state].accept_look;
             let acc_state = self.states[end_state].accept_state;
-            end_state = self.add_look_ahead_state(look, seq.num_bytes(), acc_state);
+            let accept = self.states[end_state].accept;
+            // Only the last char of the look-ahead can take more than one byte.
+            let tokens = self.states[end_state].accept_tokens - 1 + seq.num_bytes();
+            end_state = self.add_look_ahead_state(look, tokens, acc_state);
+            // The look-ahead state for `\Z` only accepts at the end of the input.
+            self.states[end_state].accept = accept;
         }
         for range in &seq.last_byte {
             self.add_transition(last_state, end_state, Range::new(range.start, range.end));
@@ -517,33 +861,70 @@ impl Nfa<u8, NoLooks> {
         Ok(())
     }
 
-    // Finds the transitions out of the given set of states, as a RangeMap.
-    fn transition_map(&self, states: &[StateIdx]) -> RangeMap<u8, Vec<usize>> {
-        let mut transitions = states.into_iter()
-            .flat_map(|s| self.states[*s].consuming.ranges_values().cloned())
-            .collect::<RangeMultiMap<u8, StateIdx>>()
-            .group();
+    // Adds a one-byte transition from `start_state` to `end_state` for every Latin-1 char in
+    // `ranges`. Fails if there aren't any.
+    fn add_latin1_ranges<I>(&mut self, start_state: StateIdx, ranges: I, end_state: StateIdx)
+    -> ::Result<()>
+    where I: Iterator<Item=Range<u32>> {
+        let ranges: Vec<Range<u32>> = ranges.collect();
+        let mut found = false;
+        for r in ranges.iter().filter(|r| r.start <= u8::MAX as u32) {
+            let end = if r.end > u8::MAX as u32 { u8::MAX } else { r.end as u8 };
+            self.add_transition(start_state, end_state, Range::new(r.start as u8, end));
+            found = true;
+        }
 
-        // `scratch` is large enough to be indexed by anything in `elts`. It is full of `false`.
-        fn uniquify(elts: &mut Vec<StateIdx>, scratch: &mut Vec<bool>) {
-            elts.retain(|&e| {
-                let ret = !scratch[e];
-                scratch[e] = true;
-                ret
-            });
+        if found {
+            Ok(())
+        } else {
+            let ranges = ranges.iter()
+                .map(|r| format!("U+{:04X}-U+{:04X}", r.start, r.end))
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(Error::NotLatin1(format!("no chars in {} are in Latin-1", ranges)))
+        }
+    }
+}
 
-            // Clean up scratch, so that it is full of `false` again.
-            for e in elts {
-                scratch[*e] = false;
+impl Nfa<u16, NoLooks> {
+    /// Like `reverse`, but for an `Nfa` that was made by `utf16_me`.
+    ///
+    /// The returned `Nfa` needs a char before the match for some look-behind, but a lone surrogate
+    /// never counts as a char.
+    pub fn reverse_utf16(&self, max_states: usize) -> ::Result<Nfa<u16, NoLooks>> {
+        let ret = try!(self.reverse_by(|ret, i, look| {
+            if look.allows_eoi() {
+                ret.states[i].accept = max(ret.states[i].accept, Accept::AtEoi);
+                ret.states[i].accept_look = max(ret.states[i].accept_look, Look::Boundary);
             }
+            // `FinalNewLine` is only meant for looking ahead (see `reverse_with`).
+            let look = if look == Look::FinalNewLine { Look::NewLine } else { look };
+            let single = ret.add_look_ahead_state(look, 1, i);
+            let pair = ret.add_look_ahead_state(look, 2, i);
+            add_char_units(ret, i, look, single, pair, true);
+            Ok(())
+        }));
+        if ret.num_states() > max_states {
+            return Err(Error::TooManyStates);
         }
+        Ok(ret)
+    }
 
-        let mut scratch = vec![false; self.num_states()];
-        for pair in transitions.as_mut_slice() {
-            uniquify(&mut pair.1, &mut scratch);
+    /// Like `anchor_by_prev_char`, but for an `Nfa` that was made by `utf16_me`.
+    ///
+    /// The initial states are the ones that `init_by_prev_char` describes. In particular, the one
+    /// for `Look::Full` is for starting after a lone surrogate.
+    pub fn anchor_utf16_by_prev_char(self, max_states: usize) -> ::Result<Nfa<u16, NoLooks>> {
+        let contexts = [Look::Boundary, Look::NewLine, Look::AsciiWordChar,
+            Look::NonAsciiWordChar, Look::NotWordChar];
+        let ret = try!(self.anchor_by(&contexts, |nfa, state, look, target| {
+            add_char_units(nfa, state, look, target, target, false);
+            Ok(())
+        }));
+        if ret.num_states() > max_states {
+            return Err(Error::TooManyStates);
         }
-
-        transitions
+        Ok(ret)
     }
 }
 
@@ -555,33 +936,33 @@ enum MatchChoice {
 
 // This contains all the intermediate data structures that we need when turning an `Nfa` into a
 // `Dfa`.
-struct Determinizer<'a> {
-    nfa: &'a Nfa<u8, NoLooks>,
-    dfa: Dfa<(Look, u8)>,
+struct Determinizer<'a, Tok: 'a> {
+    nfa: &'a Nfa<Tok, NoLooks>,
+    dfa: Dfa<(Look, u8), Tok>,
     state_map: HashMap<StateSet, StateIdx>,
     active_states: Vec<StateSet>,
     max_states: usize,
     match_choice: MatchChoice,
 }
 
-impl<'a> Determinizer<'a> {
+impl<'a, Tok: TokTrait> Determinizer<'a, Tok> {
     // Turns an Nfa into an almost-equivalent (up to the difference between shortest and longest
     // matches) Dfa.
     //
     // `init` is a vector of length Look::num(). Each entry gives a set of initial states that
     // will be turned into the initial states of the dfa.
-    fn determinize(nfa: &Nfa<u8, NoLooks>,
+    fn determinize(nfa: &Nfa<Tok, NoLooks>,
                    max_states: usize,
                    match_choice: MatchChoice,
-                   init: Vec<(Look, StateIdx)>) -> ::Result<Dfa<(Look, u8)>> {
+                   init: Vec<(Look, StateIdx)>) -> ::Result<Dfa<(Look, u8), Tok>> {
         let mut det = Determinizer::new(nfa, max_states, match_choice);
         try!(det.run(init));
         Ok(det.dfa)
     }
 
-    fn new(nfa: &'a Nfa<u8, NoLooks>,
+    fn new(nfa: &'a Nfa<Tok, NoLooks>,
            max_states: usize,
-           match_choice: MatchChoice) -> Determinizer<'a> {
+           match_choice: MatchChoice) -> Determinizer<'a, Tok> {
         Determinizer {
             nfa: nfa,
             dfa: Dfa::new(),
@@ -603,7 +984,8 @@ impl<'a> Determinizer<'a> {
     // lower priority state says `Accept::Always`. In this case, we return `(AtEoi, look, bytes)`
     // where `look` and `bytes` come from the lower priority state. This doesn't lose any
     // information, since if a state says `Accept::AtEoi` then its `accept_look` and
-    // `accept_tokens` are guaranteed to be `Boundary` and `0`.
+    // `accept_tokens` are `Boundary` and `0`, unless it is the look-ahead state for `\Z`. That
+    // one needs to keep its own look-ahead, so it takes priority.
     fn accept(&self, s: &[StateIdx]) -> (Accept, Look, u8) {
         let mut accept_states = s.iter().cloned()
             .filter(|i| self.nfa.states[*i].accept != Accept::Never);
@@ -629,7 +1011,9 @@ impl<'a> Determinizer<'a> {
         if let Some(first_accept) = first_accept {
             let st = &self.nfa.states[first_accept];
 
-            if st.accept == Accept::AtEoi {
+            if st.accept == Accept::AtEoi && st.accept_tokens > 0 {
+                (Accept::AtEoi, st.accept_look, st.accept_tokens)
+            } else if st.accept == Accept::AtEoi {
                 // Check if there is a lower-priority Accept::Always.
                 if let Some(other_accept) = other_accept {
                     let other_st = &self.nfa.states[other_accept];
@@ -715,7 +1099,7 @@ impl<'a> Determinizer<'a> {
 mod tests {
     use look::Look;
     use dfa::Dfa;
-    use nfa::{Accept, Nfa, NoLooks};
+    use nfa::{Accept, Encoding, LineTerminator, Nfa, NoLooks, RegexOptions};
     use nfa::tests::{re_nfa, trans_nfa, trans_range_nfa};
     use range_map::Range;
     use std::usize;
@@ -768,11 +1152,67 @@ mod tests {
         assert_eq!(nfa, target);
     }
 
+    #[test]
+    fn anchor_by_prev_char() {
+        let nfa = re_nfa(r"\ba").byte_me(usize::MAX).unwrap()
+            .anchor_by_prev_char(Encoding::Utf8, LineTerminator::default(), usize::MAX)
+            .unwrap();
+        let looks: Vec<Look> = nfa.init.iter().map(|x| x.0).collect();
+        assert_eq!(looks, vec![Look::Boundary, Look::NewLine, Look::AsciiWordChar,
+                               Look::NonAsciiWordChar, Look::NotWordChar, Look::Full]);
+
+        // After a word char, the `a` has to wait for a non-word char.
+        let after_word = nfa.init[2].1;
+        assert!(!nfa.states[after_word].consuming.ranges_values().any(|x| x.0.start == b'a'));
+        let after_space = nfa.init[4].1;
+        assert!(nfa.states[after_space].consuming.ranges_values().any(|x| x.0.start == b'a'));
+    }
+
+    #[test]
+    fn whole_match() {
+        let whole = |re: &str| {
+            re_nfa(re).byte_me(usize::MAX).unwrap().whole_match_dfa(usize::MAX).unwrap()
+        };
+        assert_eq!(whole("a|ab"), whole("ab?"));
+        assert_eq!(whole(r"a\b"), whole("a$"));
+        assert_eq!(whole(r"\ba"), whole("^a"));
+    }
+
     #[test]
     fn determinize_pruning() {
         assert_eq!(re_dfa("a|aa"), re_dfa("a"));
     }
 
+    #[test]
+    fn latin1() {
+        let latin1 = |re: &str| re_nfa(re).byte_me_with(Encoding::Latin1, usize::MAX);
+
+        let nfa = latin1("é").unwrap();
+        let ranges: Vec<Range<u8>> = nfa.states.iter()
+            .flat_map(|s| s.consuming.ranges_values().map(|x| x.0).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(ranges, vec![Range::single(0xE9)]);
+        assert!(latin1("€").is_err());
+        assert!(latin1("[é€]").is_ok());
+
+        // Classes get cut off at the end of Latin-1, which makes the Dfa much smaller.
+        let utf8_dfa = re_nfa(r"\w+").byte_me(usize::MAX).unwrap().determinize(usize::MAX);
+        let latin1_dfa = latin1(r"\w+").unwrap().determinize(usize::MAX);
+        assert!(latin1_dfa.unwrap().optimize().num_states()
+                < utf8_dfa.unwrap().optimize().num_states());
+    }
+
+    #[test]
+    fn ascii_word_boundary() {
+        let rev_dfa_size = |ascii: bool| {
+            let opts = RegexOptions { ascii_word_boundary: ascii, ..RegexOptions::default() };
+            let nfa = Nfa::from_regex_with(r"\bab\b", &opts).unwrap().remove_looks();
+            let rev = nfa.byte_me(usize::MAX).unwrap().reverse(usize::MAX).unwrap();
+            rev.determinize(usize::MAX).unwrap().optimize().num_states()
+        };
+        assert!(rev_dfa_size(true) < rev_dfa_size(false));
+    }
+
     macro_rules! check_rev_inits {
         ($name:ident, $re:expr, $inits:expr) => {
             #[test]
@@ -797,5 +1237,6 @@ mod tests {
     check_rev_inits!(rev_init_new_line, "(?m)abc$", [Look::Boundary, Look::NewLine]);
     check_rev_inits!(rev_init_word, r"  \b", [Look::WordChar]);
     check_rev_inits!(rev_init_not_word, r"abc\b", [Look::Boundary, Look::NotWordChar]);
+    check_rev_inits!(rev_init_non_ascii_word, r"é\b", [Look::Boundary, Look::NotWordChar]);
     check_rev_inits!(rev_init_word_or_not_word, r".\b", [Look::Boundary, Look::NotWordChar, Look::WordChar]);
 }
@@ -0,0 +1,345 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Encoding `Nfa`s as UTF-16.
+//!
+//! This is the UTF-16 analogue of `byte_me`. It's a lot simpler than the UTF-8 version, because a
+//! char is encoded either as a single code unit (if it is in the Basic Multilingual Plane) or as a
+//! high surrogate followed by a low surrogate. The code points between `0xD800` and `0xDFFF` are
+//! not chars, so they are never encoded: a lone surrogate in the input never matches anything.
+
+use error::Error;
+use look::Look;
+use nfa::{Accept, Nfa, NoLooks, State, StateIdx};
+use range_map::{Range, RangeMultiMap};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+const HIGH_SURROGATE_START: u32 = 0xD800;
+const LOW_SURROGATE_START: u32 = 0xDC00;
+const LOW_SURROGATE_END: u32 = 0xDFFF;
+const BMP_END: u32 = 0xFFFF;
+const MAX_CHAR: u32 = 0x10FFFF;
+
+// The code units encoding a range of chars: either a range of single code units, or a range of
+// high surrogates followed by a range of low surrogates.
+//
+// In the second case, every combination of a high surrogate and a low surrogate belongs to the
+// range of chars.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Utf16Sequence {
+    high: Option<Range<u16>>,
+    last: Range<u16>,
+}
+
+impl Utf16Sequence {
+    fn single(start: u32, end: u32) -> Utf16Sequence {
+        Utf16Sequence {
+            high: None,
+            last: Range::new(start as u16, end as u16),
+        }
+    }
+
+    fn pair(high_start: u32, high_end: u32, low_start: u32, low_end: u32) -> Utf16Sequence {
+        Utf16Sequence {
+            high: Some(Range::new(high_start as u16, high_end as u16)),
+            last: Range::new(low_start as u16, low_end as u16),
+        }
+    }
+
+    fn num_units(&self) -> u8 {
+        if self.high.is_some() { 2 } else { 1 }
+    }
+}
+
+fn high_surrogate(c: u32) -> u32 {
+    HIGH_SURROGATE_START + ((c - 0x10000) >> 10)
+}
+
+fn low_surrogate(c: u32) -> u32 {
+    LOW_SURROGATE_START + ((c - 0x10000) & 0x3FF)
+}
+
+// Splits a range of code points into sequences of code units, ignoring anything that isn't a
+// char.
+fn utf16_sequences(r: Range<u32>) -> Vec<Utf16Sequence> {
+    let mut ret = Vec::new();
+    let end = if r.end > MAX_CHAR { MAX_CHAR } else { r.end };
+    if r.start > end {
+        return ret;
+    }
+
+    // The part in the Basic Multilingual Plane, minus the surrogates.
+    if r.start < HIGH_SURROGATE_START {
+        let bmp_end = if end < HIGH_SURROGATE_START { end } else { HIGH_SURROGATE_START - 1 };
+        ret.push(Utf16Sequence::single(r.start, bmp_end));
+    }
+    if r.start <= BMP_END && end > LOW_SURROGATE_END {
+        let bmp_start = if r.start > LOW_SURROGATE_END { r.start } else { LOW_SURROGATE_END + 1 };
+        let bmp_end = if end > BMP_END { BMP_END } else { end };
+        ret.push(Utf16Sequence::single(bmp_start, bmp_end));
+    }
+
+    // The part that needs surrogate pairs.
+    if end > BMP_END {
+        let start = if r.start > BMP_END { r.start } else { BMP_END + 1 };
+        let (high_start, low_start) = (high_surrogate(start), low_surrogate(start));
+        let (high_end, low_end) = (high_surrogate(end), low_surrogate(end));
+
+        if high_start == high_end {
+            ret.push(Utf16Sequence::pair(high_start, high_end, low_start, low_end));
+        } else {
+            let mut full_start = high_start;
+            let mut full_end = high_end;
+            if low_start > LOW_SURROGATE_START {
+                ret.push(Utf16Sequence::pair(high_start, high_start, low_start, LOW_SURROGATE_END));
+                full_start += 1;
+            }
+            if low_end < LOW_SURROGATE_END {
+                full_end -= 1;
+            }
+            if full_start <= full_end {
+                ret.push(Utf16Sequence::pair(full_start, full_end,
+                                             LOW_SURROGATE_START, LOW_SURROGATE_END));
+            }
+            if low_end < LOW_SURROGATE_END {
+                ret.push(Utf16Sequence::pair(high_end, high_end, LOW_SURROGATE_START, low_end));
+            }
+        }
+    }
+    ret
+}
+
+// Adds transitions from `from` that read a single char of `look`. A char that takes one code unit
+// leads to `single`, and a surrogate pair leads (through a new state in between) to `pair`. If
+// `reversed` is true, the surrogate pairs are read backwards, low surrogate first.
+pub fn add_char_units(nfa: &mut Nfa<u16, NoLooks>,
+                      from: StateIdx,
+                      look: Look,
+                      single: StateIdx,
+                      pair: StateIdx,
+                      reversed: bool) {
+    for seq in look.as_set().ranges().flat_map(utf16_sequences) {
+        match seq.high {
+            None => nfa.add_transition(from, single, seq.last),
+            Some(high) => {
+                let (first, second) = if reversed { (seq.last, high) } else { (high, seq.last) };
+                let mid = nfa.add_state(Accept::Never);
+                nfa.add_transition(from, mid, first);
+                nfa.add_transition(mid, pair, second);
+            },
+        }
+    }
+}
+
+impl Nfa<u32, NoLooks> {
+    /// Converts this `Nfa` into one that consumes UTF-16 code units.
+    ///
+    /// Like `byte_me`, this keeps the indices of the existing states, and look-ahead states
+    /// remember how many code units (rather than chars) they looked ahead. Lone surrogates are
+    /// never consumed, not even by transitions (like the one for `.`) whose range includes them.
+    pub fn utf16_me(self, max_states: usize) -> ::Result<Nfa<u16, NoLooks>> {
+        let mut ret = Nfa::<u16, NoLooks> {
+            states: self.states.iter().map(|s| State {
+                accept: s.accept,
+                accept_look: s.accept_look,
+                accept_state: s.accept_state,
+                accept_tokens: s.accept_tokens,
+                consuming: RangeMultiMap::new(),
+                looking: Vec::new(),
+            }).collect(),
+            init: self.init,
+            phantom: PhantomData,
+        };
+
+        // For each look-ahead state, the look-ahead state that we use instead of it when looking
+        // ahead by a surrogate pair.
+        let mut look_ahead_states = HashMap::new();
+        for (i, state) in self.states.into_iter().enumerate() {
+            let mut seqs: Vec<(StateIdx, Utf16Sequence)> = state.consuming.ranges_values()
+                .flat_map(|&(range, tgt)| utf16_sequences(range).into_iter().map(move |s| (tgt, s)))
+                .collect();
+
+            // Grouping the surrogate pairs by target and high surrogate means that we only need
+            // one intermediate state for each group.
+            seqs.sort_by_key(|&(tgt, s)| (tgt, s.high.map(|h| (h.start, h.end))));
+            let mut prev_group = None;
+            let mut mid_state = i;
+            for (tgt, seq) in seqs {
+                let tgt = ret.utf16_target(tgt, seq.num_units(), &mut look_ahead_states);
+                if let Some(high) = seq.high {
+                    if prev_group != Some((tgt, high)) {
+                        mid_state = ret.add_state(Accept::Never);
+                        ret.add_transition(i, mid_state, high);
+                        prev_group = Some((tgt, high));
+                    }
+                    ret.add_transition(mid_state, tgt, seq.last);
+                } else {
+                    ret.add_transition(i, tgt, seq.last);
+                }
+            }
+
+            if ret.states.len() > max_states {
+                return Err(Error::TooManyStates);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl Nfa<u16, NoLooks> {
+    // Returns the state that a sequence of `units` code units leading to `state` should end up in.
+    //
+    // If `state` is a look-ahead state, this might be a different look-ahead state (so that we
+    // know how many code units of look-ahead we used). Otherwise, it is just `state`.
+    fn utf16_target(&mut self,
+                    state: StateIdx,
+                    units: u8,
+                    look_ahead_states: &mut HashMap<StateIdx, StateIdx>)
+    -> StateIdx {
+        let (accept, look, acc_state, tokens) = {
+            let st = &self.states[state];
+            (st.accept, st.accept_look, st.accept_state, st.accept_tokens)
+        };
+        // Only the last char of the look-ahead can take more than one code unit.
+        let units = if tokens > 0 { tokens - 1 + units } else { units };
+        if tokens > 0 && tokens != units {
+            if let Some(&idx) = look_ahead_states.get(&state) {
+                return idx;
+            }
+            let idx = self.add_look_ahead_state(look, units, acc_state);
+            // This might be the look-ahead state for `\Z`, which only accepts at the end.
+            self.states[idx].accept = accept;
+            look_ahead_states.insert(state, idx);
+            idx
+        } else {
+            state
+        }
+    }
+
+    /// Changes the initial states so that they depend on the char before the start of the match,
+    /// instead of on the look-behind of the pattern.
+    ///
+    /// An initial state `(look, idx)` of this `Nfa` means that we can start in `idx` if the
+    /// previous char belongs to `look`. In the returned `Nfa`, the initial states with `look`
+    /// equal to
+    ///
+    /// - `Boundary` are the ones to use at the beginning of the input,
+    /// - `NewLine` are the ones to use after a `'\n'`,
+    /// - `AsciiWordChar` are the ones to use after an ASCII word char,
+    /// - `NonAsciiWordChar` are the ones to use after any other word char,
+    /// - `NotWordChar` are the ones to use after any other char, and
+    /// - `Full` are the ones to use after a lone surrogate.
+    ///
+    /// That is, a lone surrogate is treated as something that is neither a word char nor a
+    /// non-word char, so it satisfies only look-behinds that accept anything.
+    pub fn init_by_prev_char(mut self) -> Nfa<u16, NoLooks> {
+        let contexts = [Look::Boundary, Look::NewLine, Look::AsciiWordChar,
+            Look::NonAsciiWordChar, Look::NotWordChar, Look::Full];
+        let mut init = Vec::new();
+        for &context in &contexts {
+            init.extend(self.init.iter().filter(|&&(look, _)| context <= look).map(|&(_, idx)| {
+                (context, idx)
+            }));
+        }
+        self.init = init;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nfa::{Accept, Nfa, NoLooks};
+    use nfa::tests::re_nfa;
+    use range_map::Range;
+    use std::usize;
+    use super::{Utf16Sequence, utf16_sequences};
+
+    fn seqs(start: u32, end: u32) -> Vec<Utf16Sequence> {
+        utf16_sequences(Range::new(start, end))
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(seqs(0, 0x7F), vec![Utf16Sequence::single(0, 0x7F)]);
+        assert_eq!(seqs(0xD000, 0xE000),
+                   vec![Utf16Sequence::single(0xD000, 0xD7FF),
+                        Utf16Sequence::single(0xE000, 0xE000)]);
+        assert_eq!(seqs(0xD800, 0xDFFF), vec![]);
+        assert_eq!(seqs(0x10000, 0x10000),
+                   vec![Utf16Sequence::pair(0xD800, 0xD800, 0xDC00, 0xDC00)]);
+        assert_eq!(seqs(0x10000, 0x10FFFF),
+                   vec![Utf16Sequence::pair(0xD800, 0xDBFF, 0xDC00, 0xDFFF)]);
+        assert_eq!(seqs(0x10001, 0x10801),
+                   vec![Utf16Sequence::pair(0xD800, 0xD800, 0xDC01, 0xDFFF),
+                        Utf16Sequence::pair(0xD801, 0xD801, 0xDC00, 0xDFFF),
+                        Utf16Sequence::pair(0xD802, 0xD802, 0xDC00, 0xDC01)]);
+        assert_eq!(seqs(0x110000, 0x120000), vec![]);
+    }
+
+    fn utf16_nfa(re: &str) -> Nfa<u16, NoLooks> {
+        re_nfa(re).utf16_me(usize::MAX).unwrap()
+    }
+
+    // Returns true if the Nfa accepts the whole input.
+    fn accepts(nfa: &Nfa<u16, NoLooks>, input: &[u16]) -> bool {
+        let mut states: Vec<_> = nfa.init.iter().map(|&(_, idx)| idx).collect();
+        for &unit in input {
+            states = states.iter()
+                .flat_map(|&s| nfa.consuming(s).ranges_values()
+                          .filter(|&&(r, _)| r.start <= unit && unit <= r.end)
+                          .map(|&(_, tgt)| tgt)
+                          .collect::<Vec<_>>())
+                .collect();
+        }
+        states.iter().any(|&s| nfa.states[s].accept != Accept::Never)
+    }
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn encode() {
+        let nfa = utf16_nfa("a.c");
+        assert!(accepts(&nfa, &utf16("abc")));
+        assert!(accepts(&nfa, &utf16("aéc")));
+        assert!(accepts(&nfa, &utf16("a😀c")));
+        assert!(!accepts(&nfa, &[0x61, 0xD83D, 0x63]));
+        assert!(!accepts(&nfa, &[0x61, 0xDE00, 0x63]));
+
+        let nfa = utf16_nfa("[😀-😂]");
+        assert!(accepts(&nfa, &utf16("😁")));
+        assert!(!accepts(&nfa, &utf16("😃")));
+    }
+
+    #[test]
+    fn look_ahead() {
+        let nfa = re_nfa(r"a\b").utf16_me(usize::MAX).unwrap();
+        let units: Vec<u8> = nfa.states.iter()
+            .filter(|s| s.consuming.is_empty() && s.accept_tokens > 0)
+            .map(|s| s.accept_tokens)
+            .collect();
+        assert!(units.contains(&1));
+        assert!(units.contains(&2));
+    }
+
+    #[test]
+    fn init_by_prev_char() {
+        use look::Look;
+
+        let nfa = re_nfa(r"\ba").utf16_me(usize::MAX).unwrap().init_by_prev_char();
+        let looks: Vec<Look> = nfa.init.iter().map(|x| x.0).collect();
+        assert!(looks.contains(&Look::Boundary));
+        assert!(looks.contains(&Look::NotWordChar));
+        assert!(looks.contains(&Look::NewLine));
+        assert!(!looks.contains(&Look::AsciiWordChar));
+        assert!(!looks.contains(&Look::NonAsciiWordChar));
+        assert!(!looks.contains(&Look::Full));
+    }
+}
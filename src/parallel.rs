@@ -0,0 +1,205 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Searching for matches on several threads at once.
+//!
+//! We split the input into chunks and search each one on its own thread, starting from the
+//! beginning of the chunk and stopping once we find a match that starts in the next chunk. This
+//! gives us a list of matches for each chunk, but they aren't necessarily the right ones: if the
+//! last match in one chunk extends into the next one, then the sequential search would have
+//! started searching the next chunk from some position other than the beginning.
+//!
+//! To fix this, we stitch the chunks together sequentially. Whenever we enter a chunk at a position
+//! that the parallel search didn't start from, we search sequentially until we reach a position
+//! that the parallel search did start from. From then on, the two searches agree (because the
+//! result of a search depends only on where it starts). Usually this happens after at most one
+//! match, so almost all the work is done in parallel.
+//!
+//! Both kinds of search only look for matches that start in the current chunk. If the regex has
+//! a bound on the length of its matches, that means they only need to look a little way into the
+//! next chunk. Otherwise, a chunk without matches may have to be searched all the way to the end
+//! of the text, and the threads could do far more work between them than a single search would;
+//! so for those regexes, we just search sequentially.
+
+use rayon::prelude::*;
+use regex::{Regex, check_utf8, find_at_before, has_bounded_matches, next_search_pos};
+use std::cmp::max;
+use std::vec;
+
+// The number of bytes in each chunk. Chunks that are much smaller than this aren't worth sending
+// to another thread.
+const CHUNK_SIZE: usize = 1 << 20;
+
+// The matches that we found by searching one chunk.
+struct Chain {
+    // `starts[i]` is the position that we started searching from when we found `matches[i]`.
+    starts: Vec<usize>,
+    matches: Vec<(usize, usize)>,
+    // The position from which the search found nothing that starts in this chunk (or `None` if we
+    // got to the end of the input).
+    resume: Option<usize>,
+}
+
+impl Chain {
+    // Searches `text`, starting at `start` and stopping when we find a match that starts at or
+    // after `end`.
+    fn search(re: &Regex, text: &str, start: usize, end: usize) -> Chain {
+        let mut ret = Chain {
+            starts: Vec::new(),
+            matches: Vec::new(),
+            resume: Some(start),
+        };
+
+        while let Some(pos) = ret.resume {
+            match find_at_before(re, text, pos, end) {
+                Some(m) => {
+                    ret.starts.push(pos);
+                    ret.matches.push(m);
+                    ret.resume = next_search_pos(text, m);
+                },
+                None => break,
+            }
+        }
+        ret
+    }
+}
+
+// Splits `text` into chunks of roughly `CHUNK_SIZE` bytes, returning the boundaries of the chunks.
+fn chunk_boundaries(text: &str) -> Vec<usize> {
+    let mut ret = vec![0];
+    let mut pos = CHUNK_SIZE;
+    while pos < text.len() {
+        while !text.is_char_boundary(pos) {
+            pos += 1;
+        }
+        ret.push(pos);
+        pos += CHUNK_SIZE;
+    }
+    // A match can start at the very end of the text, so the last chunk includes that position.
+    ret.push(text.len() + 1);
+    ret
+}
+
+impl Regex {
+    /// Returns an iterator over the index ranges of all non-overlapping matches in `s`.
+    ///
+    /// The result is the same as that of `find_iter`, but the search is split over several
+    /// threads. This is only worthwhile for large inputs (at least several megabytes), and only
+    /// if the regex has a bound on the length of its matches; otherwise, the search is sequential.
+    ///
+    /// # Panics
+    /// - if this `Regex` was made by `new_latin1`.
+    pub fn par_find_iter(&self, s: &str) -> vec::IntoIter<(usize, usize)> {
+        check_utf8(self, "par_find_iter");
+        if !has_bounded_matches(self) {
+            return self.find_iter(s).collect::<Vec<_>>().into_iter();
+        }
+
+        let bounds = chunk_boundaries(s);
+        let chains: Vec<Chain> = (0..(bounds.len() - 1)).into_par_iter()
+            .map(|i| Chain::search(self, s, bounds[i], bounds[i + 1]))
+            .collect();
+
+        let mut ret = Vec::new();
+        let mut resume = Some(0);
+        for (chain, &end) in chains.iter().zip(&bounds[1..]) {
+            while let Some(pos) = resume {
+                if let Ok(i) = chain.starts.binary_search(&pos) {
+                    ret.extend_from_slice(&chain.matches[i..]);
+                    resume = chain.resume;
+                    break;
+                } else if resume == chain.resume {
+                    break;
+                }
+
+                match find_at_before(self, s, pos, end) {
+                    Some(m) => {
+                        ret.push(m);
+                        resume = next_search_pos(s, m);
+                    },
+                    None => break,
+                }
+            }
+
+            // No match starts between `resume` and `end`, so searching from `resume` finds the
+            // same thing as searching from `end`. That way, the next chunk doesn't have to search
+            // this one again.
+            resume = resume.map(|pos| max(pos, end));
+        }
+
+        ret.into_iter()
+    }
+
+    /// Returns the number of non-overlapping matches in `s`.
+    ///
+    /// This is the same as `self.find_iter(s).count()`, but the search is split over several
+    /// threads.
+    ///
+    /// # Panics
+    /// - if this `Regex` was made by `new_latin1`.
+    pub fn par_count(&self, s: &str) -> usize {
+        self.par_find_iter(s).len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use regex::Regex;
+    use std::iter::repeat;
+    use super::CHUNK_SIZE;
+
+    fn check(re: &str, text: &str) {
+        let re = Regex::new(re).unwrap();
+        let seq: Vec<_> = re.find_iter(text).collect();
+        let par: Vec<_> = re.par_find_iter(text).collect();
+        assert_eq!(seq, par);
+        assert_eq!(re.par_count(text), seq.len());
+    }
+
+    #[test]
+    fn small() {
+        check("a+", "baaab aab");
+        check("", "abc");
+        check(r"\bfoo", "foo xfoo foo");
+    }
+
+    #[test]
+    fn across_chunks() {
+        // Make a text where matches straddle the chunk boundaries.
+        let text: String = repeat("xaaaaaaaaay").take(3 * CHUNK_SIZE / 11 + 7).collect();
+        check("a+y", &text);
+        check("a{3}", &text);
+        check("y(xa)?", &text);
+
+        let text: String = repeat("ä").take(CHUNK_SIZE).collect();
+        check("ä{7}", &text);
+        check("ä*", &text);
+    }
+
+    #[test]
+    fn chunk_starts() {
+        // Nothing should match at the beginning of a chunk just because it's the beginning of a
+        // chunk.
+        let text: String = repeat("x").take(3 * CHUNK_SIZE).collect();
+        check(r"\bx", &text);
+        check(r"(?m)^x", &text);
+        check(r"^x", &text);
+        check(r"x\b", &text);
+    }
+
+    #[test]
+    fn sparse_matches() {
+        let mut text: String = repeat("xy").take(2 * CHUNK_SIZE).collect();
+        text.push('z');
+        check("z", &text);
+        check("y?z", &text);
+        check("yx{0,3}y", &text);
+        check("^xy", &text);
+        check("x.*z", &text);
+    }
+}
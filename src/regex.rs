@@ -6,17 +6,68 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use dfa::Dfa;
 use error::Error;
-use nfa::{Nfa, NoLooks};
-use runner::anchored::AnchoredEngine;
+use look::Look;
+use nfa::{Encoding, LineTerminator, Nfa, NoLooks, RegexOptions};
+use runner::anchored::AnchoredLookAheadEngine;
 use runner::forward_backward::{ForwardBackwardEngine, Prefix};
+use runner::program::Program;
 use runner::Engine;
 use std;
 use std::fmt::Debug;
+use std::mem::size_of;
 
 #[derive(Debug)]
 pub struct Regex {
-    engine: Box<Engine<u8>>,
+    // Finds matches, along with the number of edits that they needed (which is always zero unless
+    // this `Regex` was made by `fuzzy`).
+    engine: Box<Engine<u32>>,
+    // What this `Regex` was made from.
+    source: Source,
+    // How chars are encoded as bytes in the text that we search.
+    encoding: Encoding,
+    // The chars that `Look::NewLine` stands for in the `Nfa` that `engine` was built from.
+    line_terminator: LineTerminator,
+    // An upper bound on the length (in bytes) of a match, if there is one.
+    max_match_len: Option<usize>,
+}
+
+// What a `Regex` was made from.
+//
+// We need the byte-level `Nfa` of a `Regex` to find its language (for comparing and combining it
+// with other ones). Since most `Regex`es never need it, we rebuild the `Nfa` from this instead of
+// keeping it around.
+#[derive(Clone, Debug)]
+enum Source {
+    Regex(String, RegexOptions),
+    Latin1(String),
+    Fuzzy(String, u32),
+    // An `Nfa` that was given to `from_nfa`. We can't rebuild this one, so we have to keep it.
+    Nfa(Nfa<u8, NoLooks>),
+    // The language of a `Regex` that was made by combining other ones.
+    Language(Dfa<()>),
+}
+
+impl Source {
+    fn nfa(&self, max_states: usize) -> ::Result<Nfa<u8, NoLooks>> {
+        match *self {
+            Source::Regex(ref re, ref options) => {
+                let nfa = try!(Nfa::from_regex_with(re, options));
+                nfa.remove_looks_with(options.line_terminator).byte_me(max_states)
+            },
+            Source::Latin1(ref re) => {
+                let options = RegexOptions { latin1: true, ..RegexOptions::default() };
+                let nfa = try!(Nfa::from_regex_with(re, &options));
+                nfa.remove_looks().byte_me_with(Encoding::Latin1, max_states)
+            },
+            Source::Fuzzy(ref re, max_edits) => {
+                try!(Nfa::from_regex(re)).remove_looks().fuzzy(max_edits).byte_me(max_states)
+            },
+            Source::Nfa(ref nfa) => Ok(nfa.clone()),
+            Source::Language(ref dfa) => Ok(Nfa::from_language(dfa)),
+        }
+    }
 }
 
 // An engine that doesn't match anything.
@@ -24,14 +75,41 @@ pub struct Regex {
 struct EmptyEngine;
 
 impl<Ret: Debug> Engine<Ret> for EmptyEngine {
-    fn find(&self, _: &str) -> Option<(usize, usize, Ret)> { None }
+    fn find_at(&self, _: &[u8], _: usize, _: Look) -> Option<(usize, usize, Ret)> { None }
     fn clone_box(&self) -> Box<Engine<Ret>> { Box::new(EmptyEngine) }
 }
 
+// Checks whether `c` is one of the chars that `look` stands for.
+fn in_set(look: Look, c: char) -> bool {
+    let c = c as u32;
+    look.as_set().ranges().any(|r| r.start <= c && c <= r.end)
+}
+
+// We only use the compact program representation if it saves at least a third of the memory of
+// the table representation, since it's slower: over a few dozen regexes, searching mixed ASCII and
+// non-ASCII text with it usually took between 15% and 80% longer. Smaller savings aren't worth
+// that, and they are the usual case. For DFAs of Unicode classes like `\w+`, `\p{L}+` or
+// `\b\w{5}\b`, most states have their own non-ASCII row, and the compact program only saved 10%
+// to 20%. For small DFAs, it was up to three times bigger than the table. Much bigger savings are
+// rare: the table takes at most 1024 bytes per state, and the compact program takes at least 256.
+fn worth_compacting(compact_size: usize, table_size: usize) -> bool {
+    compact_size * 3 <= table_size * 2
+}
+
+// The smallest possible size of a compact program with `num_states` states: every state has its
+// own row of ASCII transitions. If even that isn't worth it, we don't bother compiling one.
+fn min_compact_size(num_states: usize) -> usize {
+    num_states * 128 * size_of::<u16>()
+}
+
 impl Clone for Regex {
     fn clone(&self) -> Regex {
         Regex {
             engine: self.engine.clone_box(),
+            source: self.source.clone(),
+            encoding: self.encoding,
+            line_terminator: self.line_terminator,
+            max_match_len: self.max_match_len,
         }
     }
 }
@@ -45,81 +123,586 @@ impl Regex {
     /// Creates a new `Regex` from a regular expression string, but only if it doesn't require too
     /// many states.
     pub fn new_bounded(re: &str, max_states: usize) -> ::Result<Regex> {
-        let nfa = try!(Nfa::from_regex(re));
-        let nfa = nfa.remove_looks();
+        Regex::new_with_options_bounded(re, &RegexOptions::default(), max_states)
+    }
+
+    /// Creates a new `Regex` from a regular expression string, with some options.
+    ///
+    /// For example, setting `ascii_word_boundary` can make regexes with `\b` much smaller, and
+    /// setting `line_terminator` to `LineTerminator::Crlf` makes `(?m)^` and `(?m)$` work with
+    /// `"\r\n"` line endings.
+    pub fn new_with_options(re: &str, options: &RegexOptions) -> ::Result<Regex> {
+        Regex::new_with_options_bounded(re, options, std::usize::MAX)
+    }
+
+    /// Creates a new `Regex` from a regular expression string, with some options, but only if it
+    /// doesn't require too many states.
+    pub fn new_with_options_bounded(re: &str, options: &RegexOptions, max_states: usize)
+    -> ::Result<Regex> {
+        let source = Source::Regex(re.to_owned(), options.clone());
+        let nfa = try!(source.nfa(max_states));
+        Regex::from_byte_nfa(nfa, source, Encoding::Utf8, options.line_terminator, max_states)
+    }
+
+    /// Creates a new `Regex` for searching in Latin-1 (ISO-8859-1) text.
+    ///
+    /// Every char in the text is a single byte, so search it using `find_bytes` and the other
+    /// methods that take `&[u8]`; the ones that take `&str` panic. Parts of `re` that can match
+    /// both Latin-1 chars and other chars (like `.`, `\w` or `[^a]`) only match the Latin-1 ones,
+    /// but it is an error for `re` to name a char outside of Latin-1, as in `€` or `[a€]`.
+    pub fn new_latin1(re: &str) -> ::Result<Regex> {
+        Regex::new_latin1_bounded(re, std::usize::MAX)
+    }
+
+    /// Creates a new `Regex` for searching in Latin-1 text, but only if it doesn't require too
+    /// many states.
+    pub fn new_latin1_bounded(re: &str, max_states: usize) -> ::Result<Regex> {
+        let source = Source::Latin1(re.to_owned());
+        let nfa = try!(source.nfa(max_states));
+        Regex::from_byte_nfa(nfa, source, Encoding::Latin1, LineTerminator::default(), max_states)
+    }
+
+    /// Creates a new `Regex` that matches anything within `max_edits` edits of a match of the
+    /// regular expression `re`.
+    ///
+    /// An edit is an insertion, deletion or substitution of a single char; look-around (like `^`
+    /// or `\b`) is never edited. Matches are found in the same way as for `Regex::new`, without
+    /// regard to the number of edits; use `find_with_edits` to find out how many edits a match
+    /// needed.
+    pub fn fuzzy(re: &str, max_edits: u32) -> ::Result<Regex> {
+        Regex::fuzzy_bounded(re, max_edits, std::usize::MAX)
+    }
+
+    /// Creates a new fuzzy `Regex`, but only if it doesn't require too many states.
+    pub fn fuzzy_bounded(re: &str, max_edits: u32, max_states: usize) -> ::Result<Regex> {
+        let source = Source::Fuzzy(re.to_owned(), max_edits);
+        let nfa = try!(Nfa::from_regex(re)).remove_looks();
+        let f_nfa = try!(nfa.fuzzy(max_edits).byte_me(max_states));
+        if f_nfa.is_empty() {
+            let terminator = LineTerminator::default();
+            return Regex::from_byte_nfa(f_nfa, source, Encoding::Utf8, terminator, max_states);
+        }
+
+        // Only the backward `Dfa` can tell how many edits a match needed, so we use the
+        // forward-backward engine even if the `Nfa` is anchored.
+        let layer_size = nfa.num_states();
+        let b_nfa = try!(try!(nfa.fuzzy_reversible(max_edits).byte_me(max_states))
+                         .reverse(max_states));
+        let b_dfa = try!(b_nfa.determinize_weighted(true, max_states, |i| {
+            max_edits - (i / layer_size) as u32
+        }));
+        let b_dfa = b_dfa.optimize().map_ret(|(_, bytes, edits)| (bytes, edits));
+
+        let anchored = f_nfa.is_anchored();
+        let f_dfa = if anchored {
+            try!(f_nfa.determinize(max_states))
+        } else {
+            let terminator = LineTerminator::default();
+            try!(try!(f_nfa.anchor_by_prev_char(Encoding::Utf8, terminator, max_states))
+                 .determinize(max_states))
+        };
+        let (eng, max_match_len) =
+            Regex::make_engine(f_dfa.optimize(), b_dfa, anchored, Encoding::Utf8);
 
-        let eng = if nfa.is_empty() {
-            Box::new(EmptyEngine) as Box<Engine<u8>>
+        Ok(Regex {
+            engine: eng,
+            source: source,
+            encoding: Encoding::Utf8,
+            line_terminator: LineTerminator::default(),
+            max_match_len: max_match_len,
+        })
+    }
+
+    /// Creates a new `Regex` from an `Nfa` that consumes bytes.
+    ///
+    /// The easiest way to get such an `Nfa` is to use `NfaBuilder`, either directly (with
+    /// `NfaBuilder<u8, NoLooks>`) or by calling `remove_looks()` and then `byte_me()` on an `Nfa`
+    /// built with `NfaBuilder<u32, HasLooks>`.
+    ///
+    /// The `Nfa` should only match valid UTF-8 (otherwise, the returned match positions might not
+    /// be at char boundaries). Returns an error if the `Nfa` doesn't satisfy its invariants.
+    pub fn from_nfa(nfa: Nfa<u8, NoLooks>) -> ::Result<Regex> {
+        Regex::from_nfa_bounded(nfa, std::usize::MAX)
+    }
+
+    /// Creates a new `Regex` from an `Nfa` that consumes bytes, but only if it doesn't require
+    /// too many states.
+    pub fn from_nfa_bounded(mut nfa: Nfa<u8, NoLooks>, max_states: usize) -> ::Result<Regex> {
+        try!(nfa.check_invariants());
+        nfa.trim_unreachable();
+        let source = Source::Nfa(nfa.clone());
+        Regex::from_byte_nfa(nfa, source, Encoding::Utf8, LineTerminator::default(), max_states)
+    }
+
+    fn from_byte_nfa(nfa: Nfa<u8, NoLooks>,
+                     source: Source,
+                     encoding: Encoding,
+                     line_terminator: LineTerminator,
+                     max_states: usize)
+    -> ::Result<Regex> {
+        let (eng, max_match_len) = if nfa.is_empty() {
+            (Box::new(EmptyEngine) as Box<Engine<u32>>, Some(0))
         } else if nfa.is_anchored() {
-            Box::new(try!(Regex::make_anchored(nfa, max_states))) as Box<Engine<u8>>
+            try!(Regex::make_anchored(nfa, encoding, max_states))
         } else {
-            Box::new(try!(Regex::make_forward_backward(nfa, max_states))) as Box<Engine<u8>>
+            try!(Regex::make_forward_backward(nfa, encoding, line_terminator, max_states))
         };
 
-        Ok(Regex { engine: eng })
+        Ok(Regex {
+            engine: eng,
+            source: source,
+            encoding: encoding,
+            line_terminator: line_terminator,
+            max_match_len: max_match_len,
+        })
     }
 
-    fn make_anchored(nfa: Nfa<u32, NoLooks>, max_states: usize)
-    -> ::Result<AnchoredEngine<u8>> {
-        let nfa = try!(nfa.byte_me(max_states));
+    // Makes an engine for an anchored `nfa`, and also returns an upper bound on the length of its
+    // matches.
+    fn make_anchored(nfa: Nfa<u8, NoLooks>, encoding: Encoding, max_states: usize)
+    -> ::Result<(Box<Engine<u32>>, Option<usize>)> {
         let dfa = try!(nfa.determinize(max_states))
             .optimize()
-            .map_ret(|(_, bytes)| bytes);
-        let prog = dfa.compile();
+            .map_ret(|(_, bytes)| (bytes, 0));
+        let prog = dfa.compile_with(encoding);
+        let max_len = dfa.max_match_len();
 
-        Ok(AnchoredEngine::new(prog))
+        let table_size = prog.memory_usage();
+        if worth_compacting(min_compact_size(dfa.num_states()), table_size) {
+            if let Some(compact) = dfa.compile_compact_with::<u16>(encoding) {
+                if worth_compacting(compact.memory_usage(), table_size) {
+                    return Ok((Box::new(AnchoredLookAheadEngine::new(compact)), max_len));
+                }
+            } else if let Some(compact) = dfa.compile_compact_with::<u32>(encoding) {
+                if worth_compacting(compact.memory_usage(), table_size) {
+                    return Ok((Box::new(AnchoredLookAheadEngine::new(compact)), max_len));
+                }
+            }
+        }
+        Ok((Box::new(AnchoredLookAheadEngine::new(prog)), max_len))
     }
 
-    fn make_forward_backward(nfa: Nfa<u32, NoLooks>, max_states: usize)
-    -> ::Result<ForwardBackwardEngine<u8>> {
+    // Like `make_anchored`, but for an `nfa` that isn't anchored.
+    fn make_forward_backward(nfa: Nfa<u8, NoLooks>,
+                             encoding: Encoding,
+                             line_terminator: LineTerminator,
+                             max_states: usize)
+    -> ::Result<(Box<Engine<u32>>, Option<usize>)> {
         if nfa.is_anchored() {
             return Err(Error::InvalidEngine("anchors rule out the forward-backward engine"));
         }
 
-        let f_nfa = try!(try!(nfa.clone().byte_me(max_states)).anchor(max_states));
-        let b_nfa = try!(try!(nfa.byte_me(max_states)).reverse(max_states));
+        let b_nfa = try!(nfa.reverse_with(encoding, line_terminator, max_states));
+        let f_nfa = try!(nfa.anchor_by_prev_char(encoding, line_terminator, max_states));
 
         let f_dfa = try!(f_nfa.determinize(max_states)).optimize();
         let b_dfa = try!(b_nfa.determinize_longest(max_states)).optimize();
-        let b_dfa = b_dfa.map_ret(|(_, bytes)| bytes);
+        let b_dfa = b_dfa.map_ret(|(_, bytes)| (bytes, 0));
+        Ok(Regex::make_engine(f_dfa, b_dfa, false, encoding))
+    }
+
+    // Makes a forward-backward engine out of a forward `Dfa` and a backward one (which returns
+    // the number of bytes of look-behind that it needed, and the number of edits). If `anchored`
+    // is true, `f_dfa` can only match at the beginning of the input.
+    fn make_engine(f_dfa: Dfa<(Look, u8)>,
+                   b_dfa: Dfa<(u8, u32)>,
+                   anchored: bool,
+                   encoding: Encoding)
+    -> (Box<Engine<u32>>, Option<usize>) {
+        // The backward `Dfa` consumes the match and possibly a char of look-behind.
+        let max_len = b_dfa.max_match_len();
 
-        let b_prog = b_dfa.compile();
+        let b_prog = b_dfa.compile_with(encoding);
         let f_dfa = f_dfa.map_ret(|(look, bytes)| {
             let b_dfa_state = b_dfa.init[look.as_usize()].expect("BUG: back dfa must have this init");
             (b_dfa_state, bytes)
         });
 
-        let mut f_prog = f_dfa.compile();
-        let prefix = Prefix::from_parts(f_dfa.prefix_strings());
-        match prefix {
-            Prefix::Empty => {},
+        let prefix = if anchored {
+            Prefix::Start
+        } else {
+            Prefix::from_parts(f_dfa.prefix_strings())
+        };
+        let f_dfa = match prefix {
+            Prefix::Empty | Prefix::Start => f_dfa,
             _ => {
                 // If there is a non-trivial prefix, we can usually speed up matching by deleting
                 // transitions that return to the start state. That way, instead of returning to
                 // the start state, we will just fail to match. Then we get to search for the
                 // prefix before trying to match again.
-                let f_dfa = f_dfa.cut_loop_to_init().optimize();
-                f_prog = f_dfa.compile();
+                f_dfa.cut_loop_to_init().optimize()
             },
-        }
+        };
+        let f_prog = f_dfa.compile_with(encoding);
+        let init = f_dfa.init.clone();
 
-        Ok(ForwardBackwardEngine::new(f_prog, prefix, b_prog))
+        let table_size = f_prog.memory_usage() + b_prog.memory_usage();
+        let num_states = f_dfa.num_states() + b_dfa.num_states();
+        if worth_compacting(min_compact_size(num_states), table_size) {
+            if let (Some(f), Some(b)) = (f_dfa.compile_compact_with::<u16>(encoding),
+                                         b_dfa.compile_compact_with::<u16>(encoding)) {
+                if worth_compacting(f.memory_usage() + b.memory_usage(), table_size) {
+                    return (Box::new(ForwardBackwardEngine::new(f, init, prefix, b)), max_len);
+                }
+            } else if let (Some(f), Some(b)) = (f_dfa.compile_compact_with::<u32>(encoding),
+                                                b_dfa.compile_compact_with::<u32>(encoding)) {
+                if worth_compacting(f.memory_usage() + b.memory_usage(), table_size) {
+                    return (Box::new(ForwardBackwardEngine::new(f, init, prefix, b)), max_len);
+                }
+            }
+        }
+        (Box::new(ForwardBackwardEngine::new(f_prog, init, prefix, b_prog)), max_len)
     }
 
     /// Returns the index range of the first match, if there is a match. The indices returned are
     /// byte indices of the string. The first index is inclusive; the second is exclusive.
+    ///
+    /// # Panics
+    /// - if this `Regex` was made by `new_latin1`.
     pub fn find(&self, s: &str) -> Option<(usize, usize)> {
-        if let Some((start, end, look_behind)) = self.engine.find(s) {
-            Some((start + look_behind as usize, end))
+        check_utf8(self, "find");
+        self.find_bytes(s.as_bytes())
+    }
+
+    /// Returns the index range of the first match in some bytes, if there is a match.
+    ///
+    /// If this `Regex` was made by `new_latin1`, `s` is Latin-1 text. Otherwise, `s` should be
+    /// valid UTF-8 (and then this is the same as `find`).
+    pub fn find_bytes(&self, s: &[u8]) -> Option<(usize, usize)> {
+        self.find_bytes_at(s, 0)
+    }
+
+    /// Like `find`, but also returns the smallest number of edits needed for the match.
+    ///
+    /// The number of edits is always zero unless this `Regex` was made by `fuzzy`.
+    ///
+    /// # Panics
+    /// - if this `Regex` was made by `new_latin1`.
+    pub fn find_with_edits(&self, s: &str) -> Option<(usize, usize, u32)> {
+        check_utf8(self, "find_with_edits");
+        self.engine.find_at(s.as_bytes(), 0, Look::Boundary)
+    }
+
+    /// Returns the index range of the first match that starts at or after byte index `pos`.
+    ///
+    /// The text before `pos` isn't searched, but look-around still sees it: for example, `^`
+    /// doesn't match at `pos` unless `pos` is zero, and `\b` only matches at `pos` if `pos` is
+    /// really on a word boundary.
+    ///
+    /// # Panics
+    /// - if `pos` is not a character boundary of `s`.
+    /// - if this `Regex` was made by `new_latin1`.
+    pub fn find_at(&self, s: &str, pos: usize) -> Option<(usize, usize)> {
+        check_utf8(self, "find_at");
+        if !s.is_char_boundary(pos) {
+            panic!("find_at: {} is not a char boundary", pos);
+        }
+        self.find_bytes_at(s.as_bytes(), pos)
+    }
+
+    /// Like `find_at`, but searches in some bytes (which are Latin-1 text if this `Regex` was
+    /// made by `new_latin1`, and UTF-8 otherwise).
+    ///
+    /// # Panics
+    /// - if `pos` is greater than the length of `s`.
+    pub fn find_bytes_at(&self, s: &[u8], pos: usize) -> Option<(usize, usize)> {
+        if pos > s.len() {
+            panic!("find_bytes_at: {} is past the end of the input", pos);
+        }
+        let prev = self.prev_char_look(s, pos);
+        self.engine.find_at(s, pos, prev).map(|(start, end, _)| (start, end))
+    }
+
+    // Says what kind of char comes just before position `pos` (in the sense of
+    // `Nfa::anchor_by_prev_char`).
+    fn prev_char_look(&self, s: &[u8], pos: usize) -> Look {
+        if pos == 0 {
+            return Look::Boundary;
+        }
+
+        let c = match self.encoding {
+            Encoding::Latin1 => s[pos - 1] as char,
+            Encoding::Utf8 => {
+                // Back up over at most three continuation bytes to find the start of the char.
+                let min_start = pos.saturating_sub(4);
+                let start = (min_start..pos).rev()
+                    .find(|&i| (s[i] & 0xC0) != 0x80)
+                    .unwrap_or(min_start);
+                match std::str::from_utf8(&s[start..pos]).ok().and_then(|c| c.chars().next()) {
+                    Some(c) => c,
+                    None => return Look::Full,
+                }
+            },
+        };
+
+        let crlf = self.line_terminator == LineTerminator::Crlf;
+        if crlf && c == '\r' {
+            Look::CarriageReturn
+        } else if self.line_terminator.bytes().into_iter().any(|b| b as u32 == c as u32) {
+            Look::NewLine
+        } else if in_set(Look::AsciiWordChar, c) {
+            Look::AsciiWordChar
+        } else if in_set(Look::NonAsciiWordChar, c) {
+            Look::NonAsciiWordChar
+        } else if crlf {
+            Look::NotWordCharNotCr
         } else {
-            None
+            Look::NotWordChar
         }
     }
 
+    /// Returns an iterator over the index ranges of all non-overlapping matches in `s`.
+    ///
+    /// # Panics
+    /// - if this `Regex` was made by `new_latin1`.
+    pub fn find_iter<'a>(&'a self, s: &'a str) -> FindIter<'a> {
+        check_utf8(self, "find_iter");
+        self.find_iter_bytes(s.as_bytes())
+    }
+
+    /// Returns an iterator over the index ranges of all non-overlapping matches in some bytes.
+    ///
+    /// If this `Regex` was made by `new_latin1`, `s` is Latin-1 text. Otherwise, `s` should be
+    /// valid UTF-8 (and then this is the same as `find_iter`).
+    pub fn find_iter_bytes<'a>(&'a self, s: &'a [u8]) -> FindIter<'a> {
+        FindIter {
+            re: self,
+            text: s,
+            pos: Some(0),
+        }
+    }
+
+    /// Returns true if there is a match anywhere in `s`.
+    ///
+    /// # Panics
+    /// - if this `Regex` was made by `new_latin1`.
     pub fn is_match(&self, s: &str) -> bool {
+        check_utf8(self, "is_match");
+        self.is_match_bytes(s.as_bytes())
+    }
+
+    /// Returns true if there is a match anywhere in some bytes.
+    ///
+    /// If this `Regex` was made by `new_latin1`, `s` is Latin-1 text. Otherwise, `s` should be
+    /// valid UTF-8 (and then this is the same as `is_match`).
+    pub fn is_match_bytes(&self, s: &[u8]) -> bool {
         // TODO: for the forward-backward engine, this could be faster because we don't need
         // to run backward.
-        self.find(s).is_some()
+        self.find_bytes(s).is_some()
+    }
+
+    // Having found the match `m` in `s`, returns the position at which to search for the next
+    // match (like `next_search_pos`, but for the encoding of this `Regex`).
+    fn next_bytes_search_pos(&self, s: &[u8], m: (usize, usize)) -> Option<usize> {
+        let (start, end) = m;
+        if end > start {
+            Some(end)
+        } else if end == s.len() {
+            None
+        } else if self.encoding == Encoding::Latin1 {
+            Some(end + 1)
+        } else {
+            // Skip the continuation bytes of the char at `end`.
+            Some((end + 1..s.len()).find(|&i| (s[i] & 0xC0) != 0x80).unwrap_or(s.len()))
+        }
+    }
+
+    // Returns a `Dfa` whose language is the set of strings that this `Regex` matches in their
+    // entirety.
+    fn language(&self) -> Dfa<()> {
+        // This can't fail: building the `Nfa` succeeded when we made this `Regex`, and this time
+        // we don't bound the number of states.
+        let nfa = self.source.nfa(std::usize::MAX).unwrap();
+        nfa.whole_match_dfa(std::usize::MAX).unwrap()
+    }
+
+    // Like `language`, but fails if this `Regex` has any look-around: the language doesn't say
+    // anything about the text around a match, so it's only the whole story without look-around.
+    fn language_without_looks(&self) -> ::Result<Dfa<()>> {
+        let nfa = try!(self.source.nfa(std::usize::MAX));
+        if nfa.has_look_around() {
+            return Err(Error::Unsupported("combining regexes that have look-around".to_owned()));
+        }
+        nfa.whole_match_dfa(std::usize::MAX)
+    }
+
+    // Makes a new `Regex` that matches the language of `dfa`.
+    fn from_language(dfa: Dfa<()>, encoding: Encoding) -> ::Result<Regex> {
+        // The language doesn't have any look-around, so it doesn't matter how lines end.
+        let nfa = Nfa::from_language(&dfa);
+        let source = Source::Language(dfa);
+        Regex::from_byte_nfa(nfa, source, encoding, LineTerminator::default(), std::usize::MAX)
+    }
+
+    // Like `language`, but only with the strings that can be turned back into chars by `decode`.
+    fn string_language(&self) -> Dfa<()> {
+        match self.encoding {
+            // A `Regex` made by `from_nfa` might also match invalid UTF-8.
+            Encoding::Utf8 => self.language().intersect(&Dfa::valid_utf8(None)),
+            Encoding::Latin1 => self.language(),
+        }
+    }
+
+    // Turns a string in `string_language` back into chars.
+    fn decode(&self, s: Vec<u8>) -> String {
+        match self.encoding {
+            Encoding::Utf8 => String::from_utf8(s).expect("BUG: example should be UTF-8"),
+            Encoding::Latin1 => s.into_iter().map(|b| b as char).collect(),
+        }
+    }
+
+    // Checks that `self` and `other` search the same kind of text, so that it makes sense to
+    // compare their languages.
+    fn check_same_encoding(&self, other: &Regex) {
+        if self.encoding != other.encoding {
+            panic!("can't combine a {:?} regex with a {:?} one", self.encoding, other.encoding);
+        }
+    }
+
+    /// Checks whether `self` and `other` match exactly the same strings in their entirety.
+    ///
+    /// If they don't, returns a shortest string that is matched in its entirety by one of them but
+    /// not by the other. Only strings are compared: if `self` or `other` was made by `from_nfa`,
+    /// any invalid UTF-8 that it matches is ignored.
+    ///
+    /// # Panics
+    /// - if one of `self` and `other` was made by `new_latin1` and the other one wasn't.
+    pub fn matches_same_language_as(&self, other: &Regex) -> Result<(), String> {
+        self.check_same_encoding(other);
+        self.string_language()
+            .is_equivalent(&other.string_language())
+            .map_err(|s| self.decode(s))
+    }
+
+    /// Returns a shortest string that this `Regex` matches in its entirety, or `None` if it can
+    /// never match any string. (A `Regex` made by `from_nfa` might match invalid UTF-8, which
+    /// isn't a string.)
+    pub fn shortest_match_example(&self) -> Option<String> {
+        self.string_language().shortest_match_example().map(|s| self.decode(s))
+    }
+
+    /// Returns a `Regex` matching the strings that are matched by both `self` and `other`.
+    ///
+    /// This (and also `or`, `and_not` and `complement`) combines the sets of strings that `self`
+    /// and `other` match in their entirety. The combined `Regex` only knows about that set, and not
+    /// about which alternatives `self` and `other` prefer, so it searches for leftmost-longest
+    /// matches (unlike `Regex::new`, which finds leftmost-first ones).
+    ///
+    /// Returns an error if `self` or `other` has any look-around (like `\b` or `$`), since whether
+    /// they match then depends on more than the matched string.
+    ///
+    /// # Panics
+    /// - if one of `self` and `other` was made by `new_latin1` and the other one wasn't.
+    pub fn and(&self, other: &Regex) -> ::Result<Regex> {
+        self.check_same_encoding(other);
+        let lang = try!(self.language_without_looks());
+        let other_lang = try!(other.language_without_looks());
+        Regex::from_language(lang.intersect(&other_lang), self.encoding)
+    }
+
+    /// Returns a `Regex` matching the strings that are matched by either `self` or `other`.
+    ///
+    /// See `and` for the meaning of "matched" here, and for when this fails or panics.
+    pub fn or(&self, other: &Regex) -> ::Result<Regex> {
+        self.check_same_encoding(other);
+        let lang = try!(self.language_without_looks());
+        let other_lang = try!(other.language_without_looks());
+        Regex::from_language(lang.union(&other_lang), self.encoding)
+    }
+
+    /// Returns a `Regex` matching the strings that are matched by `self` but not by `other`.
+    ///
+    /// See `and` for the meaning of "matched" here, and for when this fails or panics.
+    pub fn and_not(&self, other: &Regex) -> ::Result<Regex> {
+        self.check_same_encoding(other);
+        let lang = try!(self.language_without_looks());
+        let other_lang = try!(other.language_without_looks());
+        Regex::from_language(lang.difference(&other_lang), self.encoding)
+    }
+
+    /// Returns a `Regex` matching the strings that are not matched by `self`.
+    ///
+    /// The strings are those that `find` can search (or, if this `Regex` was made by
+    /// `new_latin1`, any bytes). See `and` for the meaning of "matched" here, and for when this
+    /// fails.
+    pub fn complement(&self) -> ::Result<Regex> {
+        let lang = try!(self.language_without_looks());
+        Regex::from_language(lang.complement_with(self.encoding, ()), self.encoding)
+    }
+}
+
+
+/// An iterator over all non-overlapping matches of a `Regex` in a string.
+///
+/// This is created by `Regex::find_iter` or `Regex::find_iter_bytes`.
+#[derive(Clone, Debug)]
+pub struct FindIter<'a> {
+    re: &'a Regex,
+    text: &'a [u8],
+    // The position to start the next search from, or `None` if we've finished.
+    pos: Option<usize>,
+}
+
+impl<'a> Iterator for FindIter<'a> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        if let Some(pos) = self.pos {
+            let ret = self.re.find_bytes_at(self.text, pos);
+            self.pos = ret.and_then(|m| self.re.next_bytes_search_pos(self.text, m));
+            ret
+        } else {
+            None
+        }
+    }
+}
+
+// Look-ahead never needs more than one char, which is at most four bytes long.
+const MAX_LOOK_AHEAD: usize = 4;
+
+/// Like `re.find_at(text, pos)`, but only returns a match if it starts before `end`.
+///
+/// If `re` has a bound on the length of its matches, this doesn't search much further than `end`.
+pub fn find_at_before(re: &Regex, text: &str, pos: usize, end: usize) -> Option<(usize, usize)> {
+    if pos >= end {
+        return None;
+    }
+
+    // A match that starts before `end` is over by `end + max_len`, after which it needs at most
+    // one more char of look-ahead. Cutting off the text there can only make the `Dfa` accept at
+    // the new end of the input, but such a match would be too long to start before `end`.
+    let text = text.as_bytes();
+    let limit = re.max_match_len
+        .map_or(text.len(), |len| end.saturating_add(len).saturating_add(MAX_LOOK_AHEAD));
+    let text = if limit < text.len() { &text[..limit] } else { text };
+    match re.find_bytes_at(text, pos) {
+        Some(m) if m.0 < end => Some(m),
+        _ => None,
+    }
+}
+
+/// Returns true if `re` has a bound on the length of its matches.
+pub fn has_bounded_matches(re: &Regex) -> bool {
+    re.max_match_len.is_some()
+}
+
+/// Panics if `re` searches Latin-1 text, which can't be given to `method` as a `&str`.
+pub fn check_utf8(re: &Regex, method: &str) {
+    if re.encoding == Encoding::Latin1 {
+        panic!("{}: a Latin-1 Regex can only search bytes", method);
     }
 }
 
+/// Having found the match `m` in `text`, returns the position at which to search for the next
+/// match (or `None` if there can't be any more matches).
+///
+/// Usually, this is just the end of `m`. But if `m` is empty, we need to skip ahead by a character
+/// in order to avoid finding it again.
+pub fn next_search_pos(text: &str, m: (usize, usize)) -> Option<usize> {
+    let (start, end) = m;
+    if end > start {
+        Some(end)
+    } else {
+        text[end..].chars().next().map(|c| end + c.len_utf8())
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use look::Look;
+use nfa::{Nfa, NoLooks};
+use runner::tokens::TokenInsts;
+use std;
+
+// Look-behind only ever needs to see one char, which is at most two code units long.
+const MAX_LOOK_BEHIND: usize = 2;
+
+/// A regular expression that searches in UTF-16 text.
+///
+/// This is like `Regex`, except that the text is a slice of UTF-16 code units (as you might get
+/// from Windows APIs or JavaScript strings), and match positions are code unit offsets.
+///
+/// # Lone surrogates
+///
+/// Since `&[u16]` doesn't have to be valid UTF-16, the text might contain lone surrogates (a high
+/// surrogate that isn't followed by a low surrogate, or a low surrogate that isn't preceded by a
+/// high surrogate). These are treated as code units that aren't chars at all, so:
+///
+/// - a match never contains a lone surrogate, not even if the pattern contains something (like
+///   `.` or `[^a]`) that would match any char;
+/// - a match may start or end next to a lone surrogate, but look-around (like `\b`, `^` or `$`
+///   in multi-line mode) is never satisfied by one. For example, `\ba` doesn't match the `a` in
+///   `[0xD800, 0x61]`, but `a` does.
+///
+/// Matches never start or end between the two halves of a surrogate pair.
+///
+/// Like `Regex`, this searches in linear time: it runs one program forward to find the end of a
+/// match, and then another one backward from there to find the start.
+#[derive(Clone, Debug)]
+pub struct Regex16 {
+    // Returns the initial state of `backward`, and the number of code units of look-ahead.
+    forward: TokenInsts<u16, (usize, u8)>,
+    // The initial state of `forward` for each kind of previous char; see
+    // `Nfa::anchor_utf16_by_prev_char`.
+    init: Vec<Option<usize>>,
+    // Returns the number of code units of look-behind.
+    backward: TokenInsts<u16, u8>,
+}
+
+fn is_high_surrogate(u: u16) -> bool {
+    0xD800 <= u && u <= 0xDBFF
+}
+
+fn is_low_surrogate(u: u16) -> bool {
+    0xDC00 <= u && u <= 0xDFFF
+}
+
+// Says what kind of char comes just before position `pos` (in the sense of
+// `Nfa::init_by_prev_char`), or returns `None` if `pos` is in the middle of a surrogate pair.
+fn prev_char_look(s: &[u16], pos: usize) -> Option<Look> {
+    if pos == 0 {
+        return Some(Look::Boundary);
+    }
+
+    let unit = s[pos - 1];
+    let c = if is_high_surrogate(unit) {
+        if pos < s.len() && is_low_surrogate(s[pos]) {
+            return None;
+        }
+        return Some(Look::Full);
+    } else if is_low_surrogate(unit) {
+        if pos < 2 || !is_high_surrogate(s[pos - 2]) {
+            return Some(Look::Full);
+        }
+        0x10000 + (((s[pos - 2] as u32) - 0xD800) << 10) + ((unit as u32) - 0xDC00)
+    } else {
+        unit as u32
+    };
+
+    if c == '\n' as u32 {
+        Some(Look::NewLine)
+    } else if Look::AsciiWordChar.as_set().ranges().any(|r| r.start <= c && c <= r.end) {
+        Some(Look::AsciiWordChar)
+    } else if Look::NonAsciiWordChar.as_set().ranges().any(|r| r.start <= c && c <= r.end) {
+        Some(Look::NonAsciiWordChar)
+    } else {
+        Some(Look::NotWordChar)
+    }
+}
+
+impl Regex16 {
+    /// Creates a new `Regex16` from a regular expression string.
+    pub fn new(re: &str) -> ::Result<Regex16> {
+        Regex16::new_bounded(re, std::usize::MAX)
+    }
+
+    /// Creates a new `Regex16` from a regular expression string, but only if it doesn't require
+    /// too many states.
+    pub fn new_bounded(re: &str, max_states: usize) -> ::Result<Regex16> {
+        let nfa = try!(Nfa::from_regex(re));
+        Regex16::from_char_nfa(nfa.remove_looks(), max_states)
+    }
+
+    /// Creates a new `Regex16` from an `Nfa` that consumes chars.
+    ///
+    /// The easiest way to get such an `Nfa` is to build one with `NfaBuilder<u32, HasLooks>` and
+    /// then call `remove_looks()`. Returns an error if the `Nfa` doesn't satisfy its invariants.
+    pub fn from_nfa(nfa: Nfa<u32, NoLooks>) -> ::Result<Regex16> {
+        Regex16::from_nfa_bounded(nfa, std::usize::MAX)
+    }
+
+    /// Creates a new `Regex16` from an `Nfa` that consumes chars, but only if it doesn't require
+    /// too many states.
+    pub fn from_nfa_bounded(mut nfa: Nfa<u32, NoLooks>, max_states: usize) -> ::Result<Regex16> {
+        try!(nfa.check_invariants());
+        nfa.trim_unreachable();
+        Regex16::from_char_nfa(nfa, max_states)
+    }
+
+    fn from_char_nfa(nfa: Nfa<u32, NoLooks>, max_states: usize) -> ::Result<Regex16> {
+        let nfa = try!(nfa.utf16_me(max_states));
+        let b_nfa = try!(nfa.reverse_utf16(max_states));
+        let f_nfa = try!(nfa.anchor_utf16_by_prev_char(max_states));
+
+        let b_dfa = try!(b_nfa.determinize_longest(max_states))
+            .optimize()
+            .map_ret(|(_, units)| units);
+        let f_dfa = try!(f_nfa.determinize(max_states))
+            .optimize()
+            .map_ret(|(look, units)| {
+                let b_state = b_dfa.init[look.as_usize()]
+                    .expect("BUG: back dfa must have this init");
+                (b_state, units)
+            });
+
+        Ok(Regex16 {
+            forward: f_dfa.compile_tokens(),
+            init: f_dfa.init.clone(),
+            backward: b_dfa.compile_tokens(),
+        })
+    }
+
+    /// Returns the code unit index range of the first match, if there is a match. The first index
+    /// is inclusive; the second is exclusive.
+    pub fn find(&self, s: &[u16]) -> Option<(usize, usize)> {
+        self.find_at(s, 0)
+    }
+
+    /// Returns the index range of the first match that starts at or after code unit index `pos`.
+    ///
+    /// The text before `pos` isn't searched, but look-around still sees it: for example, `^`
+    /// doesn't match at `pos` unless `pos` is zero. If `pos` is between the two halves of a
+    /// surrogate pair, the search starts after the pair.
+    ///
+    /// # Panics
+    /// - if `pos` is larger than the length of `s`.
+    pub fn find_at(&self, s: &[u16], pos: usize) -> Option<(usize, usize)> {
+        assert!(pos <= s.len(), "the position is past the end of the text");
+        let (pos, prev) = match prev_char_look(s, pos) {
+            Some(look) => (pos, look),
+            // The char before `pos + 1` is a whole surrogate pair.
+            None => (pos + 1, prev_char_look(s, pos + 1).unwrap()),
+        };
+        let init = match self.init[prev.as_usize()] {
+            Some(init) => init,
+            None => return None,
+        };
+
+        // The forward program can always skip ahead to a later start, so it only fails if there
+        // is no match at all.
+        let (end, (b_state, look_ahead)) = match self.forward.find_from(s, pos, init) {
+            Ok(x) => x,
+            Err(_) => return None,
+        };
+        let end = end - look_ahead as usize;
+        let (start, look_behind) = self.backward
+            .longest_backward_find_from(s,
+                                        pos.saturating_sub(MAX_LOOK_BEHIND),
+                                        end,
+                                        b_state,
+                                        |p, look_behind| p + look_behind as usize >= pos)
+            .expect("BUG: matched forward but failed to match backward");
+        Some((start + look_behind as usize, end))
+    }
+
+    /// Returns true if there is a match somewhere in `s`.
+    pub fn is_match(&self, s: &[u16]) -> bool {
+        self.find(s).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    fn find(re: &str, s: &[u16]) -> Option<(usize, usize)> {
+        Regex16::new(re).unwrap().find(s)
+    }
+
+    #[test]
+    fn basic() {
+        assert_eq!(find("b+", &utf16("abbbc")), Some((1, 4)));
+        assert_eq!(find("^b", &utf16("abbbc")), None);
+        assert_eq!(find("c$", &utf16("abbbc")), Some((4, 5)));
+        assert_eq!(find("x", &utf16("abbbc")), None);
+        assert_eq!(find("", &utf16("")), Some((0, 0)));
+    }
+
+    #[test]
+    fn code_unit_offsets() {
+        // The emoji takes two code units.
+        assert_eq!(find("b", &utf16("😀b")), Some((2, 3)));
+        assert_eq!(find(".b", &utf16("a😀b")), Some((1, 4)));
+        assert_eq!(find("é+", &utf16("caféé")), Some((3, 5)));
+    }
+
+    #[test]
+    fn look_around() {
+        assert_eq!(find(r"\bfoo\b", &utf16("a foo b")), Some((2, 5)));
+        assert_eq!(find(r"\bfoo\b", &utf16("afoo b")), None);
+        assert_eq!(find(r"foo\b", &utf16("foo😀")), Some((0, 3)));
+        assert_eq!(find(r"(?m)^b", &utf16("a\nb")), Some((2, 3)));
+    }
+
+    #[test]
+    fn lone_surrogates() {
+        assert_eq!(find("a.b", &[0x61, 0xD800, 0x62]), None);
+        assert_eq!(find("a.b", &[0x61, 0xDC00, 0x62]), None);
+        assert_eq!(find("a", &[0xD800, 0x61]), Some((1, 2)));
+        assert_eq!(find(r"\ba", &[0xD800, 0x61]), None);
+        assert_eq!(find(r"a\b", &[0x61, 0xDC00]), None);
+        assert_eq!(find(r"a\b", &[0x61]), Some((0, 1)));
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        let s = utf16("😀😀");
+        assert_eq!(find("[^a]", &s), Some((0, 2)));
+        assert_eq!(find("😀$", &s), Some((2, 4)));
+        assert_eq!(Regex16::new("😀").unwrap().find_at(&s, 1), Some((2, 4)));
+        assert_eq!(Regex16::new("").unwrap().find_at(&s, 1), Some((2, 2)));
+    }
+
+    #[test]
+    fn find_at() {
+        let s = utf16("ab ab");
+        let re = Regex16::new("^a").unwrap();
+        assert_eq!(re.find_at(&s, 0), Some((0, 1)));
+        assert_eq!(re.find_at(&s, 3), None);
+        let re = Regex16::new(r"b").unwrap();
+        assert_eq!(re.find_at(&s, 1), None);
+        assert_eq!(Regex16::new(r"\Bb").unwrap().find_at(&s, 1), Some((1, 2)));
+    }
+
+    #[test]
+    fn long_input() {
+        // Trying every start position would take quadratic time here.
+        let s = vec![0x61; 100000];
+        assert_eq!(find("a.*b", &s), None);
+        assert_eq!(find("a+", &s), Some((0, 100000)));
+    }
+}
@@ -6,27 +6,28 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fmt::Debug;
+use look::Look;
 use runner::Engine;
-use runner::program::TableInsts;
+use runner::program::Program;
+use std::fmt::Debug;
 
 #[derive(Clone, Debug)]
-pub struct AnchoredEngine<Ret> {
-    prog: TableInsts<Ret>,
+pub struct AnchoredEngine<Prog> {
+    prog: Prog,
 }
 
-impl<Ret: Copy + Debug> AnchoredEngine<Ret> {
-    pub fn new(prog: TableInsts<Ret>) -> AnchoredEngine<Ret> {
+impl<Prog: Program> AnchoredEngine<Prog> {
+    pub fn new(prog: Prog) -> AnchoredEngine<Prog> {
         AnchoredEngine {
             prog: prog,
         }
     }
 }
 
-impl<Ret: Copy + Debug + 'static> Engine<Ret> for AnchoredEngine<Ret> {
-    fn find(&self, s: &str) -> Option<(usize, usize, Ret)> {
-        let input = s.as_bytes();
-        if self.prog.is_empty() {
+impl<Prog: Program + Send + Sync + 'static> Engine<Prog::Ret> for AnchoredEngine<Prog> {
+    fn find_at(&self, input: &[u8], pos: usize, _: Look) -> Option<(usize, usize, Prog::Ret)> {
+        // An anchored program can only match at the beginning of the input.
+        if self.prog.is_empty() || pos > 0 {
             None
         } else if let Ok(end) = self.prog.find_from(input, 0, 0) {
             Some((0, end.0, end.1))
@@ -35,6 +36,39 @@ impl<Ret: Copy + Debug + 'static> Engine<Ret> for AnchoredEngine<Ret> {
         }
     }
 
+    fn clone_box(&self) -> Box<Engine<Prog::Ret>> {
+        Box::new(self.clone())
+    }
+}
+
+/// Like `AnchoredEngine`, but for a program that returns the number of bytes of look-ahead that
+/// it needed before accepting (along with a value that the engine passes on). The match ends that
+/// many bytes before the place where the program accepted.
+#[derive(Clone, Debug)]
+pub struct AnchoredLookAheadEngine<Prog> {
+    prog: Prog,
+}
+
+impl<Prog, Ret> AnchoredLookAheadEngine<Prog> where Prog: Program<Ret=(u8, Ret)> {
+    pub fn new(prog: Prog) -> AnchoredLookAheadEngine<Prog> {
+        AnchoredLookAheadEngine {
+            prog: prog,
+        }
+    }
+}
+
+impl<Prog, Ret> Engine<Ret> for AnchoredLookAheadEngine<Prog>
+where Prog: Program<Ret=(u8, Ret)> + Send + Sync + 'static, Ret: Debug {
+    fn find_at(&self, input: &[u8], pos: usize, _: Look) -> Option<(usize, usize, Ret)> {
+        if self.prog.is_empty() || pos > 0 {
+            None
+        } else if let Ok((end, (look_ahead, ret))) = self.prog.find_from(input, 0, 0) {
+            Some((0, end - look_ahead as usize, ret))
+        } else {
+            None
+        }
+    }
+
     fn clone_box(&self) -> Box<Engine<Ret>> {
         Box::new(self.clone())
     }
@@ -0,0 +1,237 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use num_traits::PrimInt;
+use runner::program::{Accel, Program};
+use std::fmt::{Debug, Formatter, Error as FmtError};
+use std::hash::Hash;
+use std::mem::size_of;
+
+/// The types that can be used for storing state indices in a `CompactInsts`.
+pub trait CompactIdx: Debug + Hash + PrimInt {}
+impl<T: Debug + Hash + PrimInt> CompactIdx for T {}
+
+/// A DFA program that stores its ASCII and non-ASCII transitions separately.
+///
+/// Every state gets a dense table of its 128 ASCII transitions. The transitions on the remaining
+/// bytes are compressed into equivalence classes and stored in rows that are shared between
+/// states. Typically, many states have exactly the same non-ASCII transitions (for example,
+/// because they all fail on non-ASCII input), and so there are only a few distinct rows.
+///
+/// `Idx` is the type used for storing state indices. With `u16`, the tables are half the size that
+/// they would be with `u32`, but we can only have up to 65535 states.
+#[derive(Clone)]
+pub struct CompactInsts<Ret, Idx> {
+    /// A `128 x num_states`-long table of transitions on ASCII bytes.
+    ///
+    /// `Idx::max_value()` represents the dead state.
+    pub ascii: Vec<Idx>,
+    /// A vec of length 128 mapping the bytes `0x80` through `0xFF` to their class indices.
+    pub high_class: Vec<u8>,
+    /// The deduplicated rows of non-ASCII transitions. Each row has one entry per class in
+    /// `high_class`.
+    pub high: Vec<Idx>,
+    /// For each state, the offset in `high` of the row containing its non-ASCII transitions.
+    pub high_row: Vec<u32>,
+    /// If `accept[st]` is not `None` then `st` is accepting, and `accept[st]` is the data
+    /// to return.
+    pub accept: Vec<Option<Ret>>,
+    /// Same as `accept`, but applies only at the end of the input.
+    pub accept_at_eoi: Vec<Option<Ret>>,
+    /// See `TableInsts::accel`.
+    pub accel: Vec<Accel>,
+    /// See `TableInsts::utf8`.
+    pub utf8: bool,
+}
+
+impl<Ret: Debug, Idx: CompactIdx> Debug for CompactInsts<Ret, Idx> {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        try!(f.write_fmt(format_args!("CompactInsts ({} states, {} non-ASCII rows):\n",
+                                      self.accept.len(),
+                                      self.high.len() / self.num_classes())));
+        try!(f.write_str("Non-ASCII byte classes: "));
+        try!(f.debug_map()
+            .entries((0..128).map(|b| (b + 128, self.high_class[b])))
+            .finish());
+
+        for idx in 0..self.accept.len() {
+            try!(f.write_fmt(format_args!("State {} (non-ASCII row at {}):\n",
+                                          idx,
+                                          self.high_row[idx])));
+            try!(f.debug_map()
+                .entries((0usize..128)
+                    .map(|b| (b, self.ascii[(idx << 7) + b]))
+                    .filter(|x| x.1 != Idx::max_value()))
+                .finish());
+            try!(f.write_str("\n"));
+        }
+
+        try!(f.write_str("Accept: "));
+        for idx in 0..self.accept.len() {
+            if let Some(ref ret) = self.accept[idx] {
+                try!(f.write_fmt(format_args!("{} -> {:?}, ", idx, ret)));
+            }
+        }
+
+        try!(f.write_str("Accept_at_eoi: "));
+        for idx in 0..self.accept_at_eoi.len() {
+            if let Some(ref ret) = self.accept_at_eoi[idx] {
+                try!(f.write_fmt(format_args!("{} -> {:?}, ", idx, ret)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<Ret, Idx: CompactIdx> CompactInsts<Ret, Idx> {
+    fn num_classes(&self) -> usize {
+        self.high_class.iter().cloned().max().map_or(1, |c| c as usize + 1)
+    }
+
+    fn next_state(&self, state: usize, input: u8) -> Option<usize> {
+        let next_state = if input < 0x80 {
+            self.ascii[(state << 7) + input as usize]
+        } else {
+            let class = self.high_class[(input - 0x80) as usize];
+            self.high[self.high_row[state] as usize + class as usize]
+        };
+
+        if next_state != Idx::max_value() {
+            next_state.to_usize()
+        } else {
+            None
+        }
+    }
+}
+
+impl<Ret: Copy + Debug, Idx: CompactIdx> Program for CompactInsts<Ret, Idx> {
+    type Ret = Ret;
+
+    fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    fn find_from(&self, input: &[u8], pos: usize, state: usize)
+    -> Result<(usize, Ret), usize> {
+        let mut state = state;
+        let mut ret = Err(input.len());
+        let mut pos = pos;
+
+        while pos < input.len() {
+            if let Some(accept_ret) = self.accept[state] {
+                ret = Ok((pos, accept_ret));
+            }
+
+            let accel = self.accel[state];
+            if accel != Accel::None && (!self.utf8 || (input[pos] & 0xC0) != 0x80) {
+                match accel.find(&input[pos..]) {
+                    Some(skip) => {
+                        pos += skip;
+                        if let Some(accept_ret) = self.accept[state] {
+                            ret = Ok((pos, accept_ret));
+                        }
+                    },
+                    None => break,
+                }
+            }
+
+            match self.next_state(state, input[pos]) {
+                Some(next_state) => state = next_state,
+                None => return ret.or(Err(pos)),
+            }
+            pos += 1;
+        }
+
+        if let Some(accept_ret) = self.accept_at_eoi[state] {
+            Ok((input.len(), accept_ret))
+        } else {
+            ret
+        }
+    }
+
+    fn longest_backward_find_from<Keep>(&self,
+                                        input: &[u8],
+                                        min_pos: usize,
+                                        pos: usize,
+                                        mut state: usize,
+                                        keep: Keep)
+    -> Option<(usize, Ret)>
+    where Keep: Fn(usize, Ret) -> bool {
+        let mut ret = None;
+        for pos in (min_pos..pos).rev() {
+            if let Some(next_ret) = self.accept[state] {
+                if keep(pos + 1, next_ret) {
+                    ret = Some((pos + 1, next_ret));
+                }
+            }
+            if let Some(next_state) = self.next_state(state, input[pos]) {
+                state = next_state;
+            } else {
+                return ret;
+            }
+        }
+
+        let end_ret = if min_pos == 0 { self.accept_at_eoi[state] } else { self.accept[state] };
+        match end_ret {
+            Some(end_ret) if keep(min_pos, end_ret) => Some((min_pos, end_ret)),
+            _ => ret,
+        }
+    }
+
+    fn memory_usage(&self) -> usize {
+        (self.ascii.len() + self.high.len()) * size_of::<Idx>()
+            + self.high_class.len()
+            + self.high_row.len() * size_of::<u32>()
+            + (self.accept.len() + self.accept_at_eoi.len()) * size_of::<Option<Ret>>()
+            + self.accel.len() * size_of::<Accel>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa::tests::{make_anchored, make_dfa};
+    use runner::program::Program;
+
+    // Checks that the compact and table programs give the same results on every suffix of the
+    // input.
+    fn check_same(re: &str, input: &str) {
+        let dfa = make_anchored(re);
+        let table = dfa.compile();
+        let compact = dfa.compile_compact::<u16>().unwrap();
+        let input = input.as_bytes();
+
+        for pos in 0..input.len() {
+            assert_eq!(table.find_from(input, pos, 0), compact.find_from(input, pos, 0));
+        }
+    }
+
+    #[test]
+    fn same_results() {
+        check_same("a+b", "cabaaab");
+        check_same(r"\bx+", "x xx yx");
+        check_same("[αβγ]+δ", "αβδ γγγ δαα");
+        check_same(r#""[^"]*""#, r#"a "bc" "dé"#);
+        check_same("a$", "baaa");
+    }
+
+    #[test]
+    fn shared_rows() {
+        // None of the states in this DFA have any non-ASCII transitions, so they can all share
+        // one row.
+        let dfa = make_dfa("abcdefgh").unwrap();
+        let compact = dfa.compile_compact::<u16>().unwrap();
+        assert_eq!(compact.high.len(), 1);
+    }
+
+    #[test]
+    fn too_many_states() {
+        let dfa = make_dfa("a{300}").unwrap();
+        assert!(dfa.compile_compact::<u8>().is_none());
+        assert!(dfa.compile_compact::<u16>().is_some());
+    }
+}
@@ -6,42 +6,74 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::fmt::Debug;
 //use dfa::{Dfa, PrefixPart, RetTrait};
 use dfa::PrefixPart;
 use itertools::Itertools;
+use look::Look;
 use memchr::memchr;
 use runner::Engine;
-use runner::program::TableInsts;
+use runner::program::Program;
+use std::fmt::Debug;
 
+// Look-behind only ever needs to see one char, which is at most four bytes long.
+const MAX_LOOK_BEHIND: usize = 4;
+
+/// An engine that runs a DFA forward to find the end of a match, and then runs another DFA
+/// backward from there to find the start.
+///
+/// The forward program returns the initial state of the backward program, and the number of bytes
+/// of look-ahead that it needed before accepting. The backward program returns the number of
+/// bytes of look-behind that it needed, along with a value that the engine passes on.
 #[derive(Clone, Debug)]
-pub struct ForwardBackwardEngine<Ret> {
-    forward: TableInsts<(usize, u8)>,
-    backward: TableInsts<Ret>,
+pub struct ForwardBackwardEngine<FProg, BProg> {
+    forward: FProg,
+    // The initial state of `forward` for each kind of previous char (indexed by `Look::as_usize`).
+    init: Vec<Option<usize>>,
+    backward: BProg,
     prefix: Prefix,
 }
 
-impl<Ret: Copy + Debug> ForwardBackwardEngine<Ret> {
-    pub fn new(forward: TableInsts<(usize, u8)>, prefix: Prefix, backward: TableInsts<Ret>) -> Self {
+impl<FProg, BProg, Ret> ForwardBackwardEngine<FProg, BProg>
+where FProg: Program<Ret=(usize, u8)>, BProg: Program<Ret=(u8, Ret)> {
+    pub fn new(forward: FProg, init: Vec<Option<usize>>, prefix: Prefix, backward: BProg) -> Self {
         ForwardBackwardEngine {
             forward: forward,
+            init: init,
             backward: backward,
             prefix: prefix,
         }
     }
 
-    fn find_with_searcher<SearchFn>(&self, input: &[u8], search: SearchFn)
+    fn find_with_searcher<SearchFn>(&self, input: &[u8], pos: usize, prev: Look, search: SearchFn)
     -> Option<(usize, usize, Ret)>
     where SearchFn: Fn(&[u8], usize) -> Option<usize> {
-        let mut pos = 0;
+        let min_start = pos;
+        let mut pos = pos;
         while let Some(start) = search(input, pos) {
-            match self.forward.find_from(input, start, 0) {
+            // Only the first position has a known previous char; the prefix only takes us to
+            // places where it doesn't matter.
+            let look = if start == min_start { prev } else { Look::Boundary };
+            let init = match self.init[look.as_usize()] {
+                Some(init) => init,
+                None => {
+                    pos = start + 1;
+                    continue;
+                },
+            };
+
+            match self.forward.find_from(input, start, init) {
                 Ok((end, (rev_state, look_ahead))) => {
+                    // The backward program may need to look at the char before `min_start`, but
+                    // the match itself has to start at or after `min_start`.
                     let rev_pos = end.saturating_sub(look_ahead as usize);
-                    let (start_pos, ret) = self.backward
-                        .longest_backward_find_from(input, rev_pos, rev_state)
+                    let (start_pos, (look_behind, ret)) = self.backward
+                        .longest_backward_find_from(input,
+                                                    min_start.saturating_sub(MAX_LOOK_BEHIND),
+                                                    rev_pos,
+                                                    rev_state,
+                                                    |p, ret| p + ret.0 as usize >= min_start)
                         .expect("BUG: matched forward but failed to match backward");
-                    return Some((start_pos, rev_pos, ret));
+                    return Some((start_pos + look_behind as usize, rev_pos, ret));
 
                 },
                 Err(end) => {
@@ -55,9 +87,11 @@ impl<Ret: Copy + Debug> ForwardBackwardEngine<Ret> {
 
 }
 
-impl<Ret: Copy + Debug + 'static> Engine<Ret> for ForwardBackwardEngine<Ret> {
-    fn find(&self, s: &str) -> Option<(usize, usize, Ret)> {
-        let input = s.as_bytes();
+impl<FProg, BProg, Ret> Engine<Ret> for ForwardBackwardEngine<FProg, BProg>
+where FProg: Program<Ret=(usize, u8)> + Send + Sync + 'static,
+      BProg: Program<Ret=(u8, Ret)> + Send + Sync + 'static,
+      Ret: Debug {
+    fn find_at(&self, input: &[u8], pos: usize, prev: Look) -> Option<(usize, usize, Ret)> {
         if self.forward.is_empty() {
             return None;
         }
@@ -65,10 +99,20 @@ impl<Ret: Copy + Debug + 'static> Engine<Ret> for ForwardBackwardEngine<Ret> {
         match self.prefix {
             Prefix::Empty => self.find_with_searcher(
                 input,
+                pos,
+                prev,
                 |s, pos| if pos <= s.len() { Some(pos) } else { None }
             ),
+            Prefix::Start => self.find_with_searcher(
+                input,
+                pos,
+                prev,
+                |_, pos| if pos == 0 { Some(0) } else { None }
+            ),
             Prefix::ByteSet { ref bytes, offset } => self.find_with_searcher(
                 input,
+                pos,
+                prev,
                 |s, pos| if pos + offset <= s.len() {
                         s[(pos + offset)..].iter().position(|c| bytes[*c as usize]).map(|x| x + pos)
                     } else {
@@ -77,6 +121,8 @@ impl<Ret: Copy + Debug + 'static> Engine<Ret> for ForwardBackwardEngine<Ret> {
             ),
             Prefix::Byte { byte, offset } => self.find_with_searcher(
                 input,
+                pos,
+                prev,
                 |s, pos| if pos + offset <= s.len() {
                     memchr(byte, &input[(pos + offset)..]).map(|x| x + pos)
                 } else {
@@ -101,6 +147,8 @@ impl<Ret: Copy + Debug + 'static> Engine<Ret> for ForwardBackwardEngine<Ret> {
 pub enum Prefix {
     // Matches every position.
     Empty,
+    // Only matches at the beginning of the input (for a DFA that is anchored there).
+    Start,
     // Matches a single byte in a particular set and then rewinds some number of bytes.
     ByteSet { bytes: Vec<bool>, offset: usize },
     // Matches a specific byte and then rewinds some number of bytes.
@@ -1,10 +1,22 @@
+use look::Look;
 use std::fmt::Debug;
 
-pub trait Engine<Ret: Debug>: Debug {
-    fn find(&self, s: &str) -> Option<(usize, usize, Ret)>;
+pub trait Engine<Ret: Debug>: Debug + Send + Sync {
+    fn find(&self, s: &[u8]) -> Option<(usize, usize, Ret)> {
+        self.find_at(s, 0, Look::Boundary)
+    }
+
+    /// Finds the first match that starts at or after position `pos`.
+    ///
+    /// `prev` describes the char just before `pos` (as in `Nfa::anchor_by_prev_char`), so that
+    /// look-behind at `pos` works; it is `Look::Boundary` if `pos` is zero.
+    fn find_at(&self, s: &[u8], pos: usize, prev: Look) -> Option<(usize, usize, Ret)>;
+
     fn clone_box(&self) -> Box<Engine<Ret>>;
 }
 
 pub mod anchored;
+pub mod compact;
 pub mod forward_backward;
 pub mod program;
+pub mod tokens;
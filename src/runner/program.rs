@@ -6,12 +6,103 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use memchr::{memchr, memchr2, memchr3};
 use std::fmt::{Debug, Formatter, Error as FmtError};
+use std::mem::size_of;
 use std::u32;
 
 pub type TableStateIdx = u32;
 
+/// A compiled DFA, ready for running on some input.
+///
+/// There are several ways to lay out a DFA in memory, trading off speed against size. The
+/// engines are generic over this trait so that they can run on any of them.
+pub trait Program: Clone + Debug {
+    type Ret: Copy + Debug;
+
+    /// Returns the number of states.
+    fn num_states(&self) -> usize;
+
+    /// Runs the program forward from `state`, starting at position `pos` of the input.
+    ///
+    /// Returns the position and return value of the last accepting state that we saw before
+    /// either failing or getting to the end of the input. If we never accept, returns the
+    /// position at which we failed.
+    fn find_from(&self, input: &[u8], pos: usize, state: usize)
+    -> Result<(usize, Self::Ret), usize>;
+
+    /// Runs the program backward from `state`, starting just before position `pos` of the input
+    /// and going no further back than position `min_pos`.
+    ///
+    /// Returns the position and return value of the longest match that `keep` allows, if there is
+    /// one. Position `min_pos` only counts as the beginning of the input (for states that only
+    /// accept there) if it is zero.
+    fn longest_backward_find_from<Keep>(&self,
+                                        input: &[u8],
+                                        min_pos: usize,
+                                        pos: usize,
+                                        state: usize,
+                                        keep: Keep)
+    -> Option<(usize, Self::Ret)>
+    where Keep: Fn(usize, Self::Ret) -> bool;
+
+    /// Returns (approximately) the number of bytes of memory used by this program.
+    fn memory_usage(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.num_states() == 0
+    }
+}
+
+/// Describes how to skip quickly through a state that loops back to itself on almost every byte.
+///
+/// If a state has `Accel::Two(a, b)`, for example, then every byte other than `a` and `b` leads
+/// straight back to the same state. So instead of looking up the transition table for each byte,
+/// we can use `memchr2` to jump straight to the next `a` or `b`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Accel {
+    None,
+    One(u8),
+    Two(u8, u8),
+    Three(u8, u8, u8),
+}
+
+impl Accel {
+    /// Creates an `Accel` that skips everything except the given bytes.
+    ///
+    /// Returns `Accel::None` if there are too many bytes to search for (or none at all).
+    pub fn from_escapes(bytes: &[u8]) -> Accel {
+        match bytes.len() {
+            1 => Accel::One(bytes[0]),
+            2 => Accel::Two(bytes[0], bytes[1]),
+            3 => Accel::Three(bytes[0], bytes[1], bytes[2]),
+            _ => Accel::None,
+        }
+    }
+
+    /// Returns the position of the first escape byte in `input`, or `None` if there isn't one.
+    ///
+    /// This should not be called on `Accel::None`.
+    pub fn find(&self, input: &[u8]) -> Option<usize> {
+        match *self {
+            Accel::One(a) => memchr(a, input),
+            Accel::Two(a, b) => memchr2(a, b, input),
+            Accel::Three(a, b, c) => memchr3(a, b, c, input),
+            Accel::None => Some(0),
+        }
+    }
+}
+
+/// The value in `TableInsts::table` representing the dead state.
+pub const DEAD_STATE: TableStateIdx = u32::MAX;
+
 /// A DFA program implemented as a lookup table.
+///
+/// States are referred to by "premultiplied" indices: the state with index `i` is represented by
+/// `i << log_num_classes`, which is the offset of its row in `table`. Moreover, the states are
+/// ordered so that all the "special" ones (those that are accepting or accelerated) come after
+/// the others. That way, a single comparison with `special_min` tells us whether we need to do
+/// anything more than a table lookup.
 #[derive(Clone)]
 pub struct TableInsts<Ret> {
     /// The log (rounded up) of the number of different equivalence classes of bytes.
@@ -20,28 +111,45 @@ pub struct TableInsts<Ret> {
     // multiply (instead of just shifting) to look up the next state, and that slows us down by
     // 10-20%.
     //
-    // TODO: we can probably save more memory by splitting classes into ASCII/non-ASCII. Often,
-    // many states share the same non-ASCII transitions, so those tables can be merged.
+    // `CompactInsts` saves more memory by splitting classes into ASCII/non-ASCII, since many states
+    // often share the same non-ASCII transitions.
     pub log_num_classes: u32,
     /// A vec of length 256 mapping from bytes to their class indices.
     pub byte_class: Vec<u8>,
     /// A `(1 << log_num_classes) x num_instructions`-long table.
     ///
-    /// For a given input byte `b` in state `state`, we look up the next state using
-    /// `table[state << log_num_classes + b]`.
+    /// For a given input byte `b` in the state with premultiplied index `state`, we look up the
+    /// (premultiplied) next state using `table[state + byte_class[b]]`.
     pub table: Vec<TableStateIdx>,
+    /// Every premultiplied state index that is at least `special_min` refers to a special state
+    /// (or to `DEAD_STATE`).
+    pub special_min: TableStateIdx,
+    /// Maps the indices of states in the original `Dfa` to premultiplied indices.
+    pub state_map: Vec<TableStateIdx>,
     /// If `accept[st]` is not `None` then `st` is accepting, and `accept[st]` is the data
     /// to return.
+    ///
+    /// This (and also `accept_at_eoi` and `accel`) is indexed by state indices that are *not*
+    /// premultiplied.
     pub accept: Vec<Option<Ret>>,
     /// Same as `accept`, but applies only at the end of the input.
     pub accept_at_eoi: Vec<Option<Ret>>,
+    /// For each state, a description of the bytes that take us out of that state (if there are
+    /// few enough of them that it's worth searching for them with `memchr`).
+    pub accel: Vec<Accel>,
+    /// If true, the input is valid UTF-8. Then some states in `accel` only look for their ASCII
+    /// escapes, because they loop on every non-ASCII codepoint; we only skip ahead from those
+    /// states at the start of a codepoint.
+    pub utf8: bool,
 }
 
 impl<Ret: Debug> Debug for TableInsts<Ret> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
-        try!(f.write_fmt(format_args!("TableInsts ({} log_classes, {} instructions):\n",
+        let num_special = self.accept.len() - self.state_idx(self.special_min);
+        try!(f.write_fmt(format_args!("TableInsts ({} log_classes, {} instructions, {} special):\n",
                                       self.log_num_classes,
-                                      self.accept.len())));
+                                      self.accept.len(),
+                                      num_special)));
         try!(f.write_str("Byte classes: "));
         try!(f.debug_map()
             .entries((0..256).map(|b| (b, self.byte_class[b])))
@@ -53,11 +161,19 @@ impl<Ret: Debug> Debug for TableInsts<Ret> {
             try!(f.debug_map()
                 .entries((0usize..num_classes)
                     .map(|c| (c, self.table[(idx << self.log_num_classes) + c]))
-                    .filter(|x| x.1 != u32::MAX))
+                    .filter(|x| x.1 != DEAD_STATE)
+                    .map(|(c, tgt)| (c, tgt >> self.log_num_classes)))
                 .finish());
             try!(f.write_str("\n"));
         }
 
+        try!(f.write_str("Accelerated: "));
+        for idx in 0..self.accel.len() {
+            if self.accel[idx] != Accel::None {
+                try!(f.write_fmt(format_args!("{} -> {:?}, ", idx, self.accel[idx])));
+            }
+        }
+
         try!(f.write_str("Accept: "));
         for idx in 0..self.accept.len() {
             if let Some(ref ret) = self.accept[idx] {
@@ -75,85 +191,160 @@ impl<Ret: Debug> Debug for TableInsts<Ret> {
     }
 }
 
-impl<Ret: Copy + Debug> TableInsts<Ret> {
-    fn next_state(&self, state: usize, input: u8) -> Option<usize> {
+impl<Ret> TableInsts<Ret> {
+    // Takes a premultiplied state index and returns the premultiplied index of the next state.
+    #[inline(always)]
+    fn next_state(&self, state: TableStateIdx, input: u8) -> TableStateIdx {
         let class = self.byte_class[input as usize];
-        let next_state = self.table[(state << self.log_num_classes) + class as usize];
-        if next_state != u32::MAX {
-            Some(next_state as usize)
-        } else {
-            None
-        }
+        self.table[state as usize + class as usize]
     }
 
-    pub fn num_states(&self) -> usize {
+    // Converts a premultiplied state index into an ordinary one.
+    #[inline(always)]
+    fn state_idx(&self, state: TableStateIdx) -> usize {
+        (state >> self.log_num_classes) as usize
+    }
+}
+
+impl<Ret: Copy + Debug> Program for TableInsts<Ret> {
+    type Ret = Ret;
+
+    fn num_states(&self) -> usize {
         self.accept.len()
     }
 
-    pub fn find_from(&self, input: &[u8], pos: usize, state: usize)
+    fn find_from(&self, input: &[u8], pos: usize, state: usize)
     -> Result<(usize, Ret), usize> {
-        let mut state = state as u32;
+        let mut state = self.state_map[state];
         let mut ret = Err(input.len());
+        let mut pos = pos;
+        let special_min = self.special_min;
 
-        if state as usize >= self.accept.len() {
-            panic!("BUG");
-        }
-        for pos in pos..input.len() {
-            if let Some(accept_ret) = self.accept[state as usize] {
-                ret = Ok((pos, accept_ret));
+        // At the top of this loop, `state` is the state we're in just before reading
+        // `input[pos]`.
+        loop {
+            if state >= special_min {
+                if state == DEAD_STATE {
+                    // We always get here right after a transition, so `pos` is at least 1.
+                    return ret.or(Err(pos - 1));
+                }
+
+                if pos >= input.len() {
+                    break;
+                }
+                let idx = self.state_idx(state);
+                if let Some(accept_ret) = self.accept[idx] {
+                    ret = Ok((pos, accept_ret));
+                }
+
+                // If we're in a state that only leaves on a few bytes, skip straight to the next
+                // one of those bytes. The state doesn't change while we skip, so the only
+                // accepting position that we need to record is the one we land on. Some states
+                // only promise to loop on whole codepoints, so we don't skip if we're in the
+                // middle of one.
+                let accel = self.accel[idx];
+                if accel != Accel::None && (!self.utf8 || (input[pos] & 0xC0) != 0x80) {
+                    match accel.find(&input[pos..]) {
+                        Some(skip) => {
+                            pos += skip;
+                            if let Some(accept_ret) = self.accept[idx] {
+                                ret = Ok((pos, accept_ret));
+                            }
+                        },
+                        // None of the escape bytes appear, so we stay in this state until the end.
+                        None => break,
+                    }
+                }
+
+                state = self.next_state(state, input[pos]);
+                pos += 1;
+                continue;
             }
 
-            // We've manually inlined next_state here, for better performance (measurably better
-            // than using #[inline(always)]).
-            // For some reason, these bounds checks (even though LLVM leaves them in) don't seem to
-            // hurt performance.
-            let class = self.byte_class[input[pos] as usize];
-            state = self.table[((state as usize) << self.log_num_classes) + class as usize];
-
-            // Since everything in `self.table` is either a valid state or u32::MAX, this is the
-            // same as checking if state == u32::MAX. We write it this way in the hope that
-            // rustc/LLVM will be able to elide the bounds check at the top of the loop.
-            if state as usize >= self.accept.len() {
-                if ret.is_err() {
-                    return Err(pos);
+            // We're in a boring state, so we can go through the input as fast as possible until
+            // we get to a special one. We've unrolled this loop by hand, because it's measurably
+            // faster than letting LLVM do it.
+            while pos + 4 <= input.len() {
+                let s0 = self.next_state(state, input[pos]);
+                if s0 >= special_min {
+                    state = s0;
+                    pos += 1;
+                    break;
+                }
+                let s1 = self.next_state(s0, input[pos + 1]);
+                if s1 >= special_min {
+                    state = s1;
+                    pos += 2;
+                    break;
+                }
+                let s2 = self.next_state(s1, input[pos + 2]);
+                if s2 >= special_min {
+                    state = s2;
+                    pos += 3;
+                    break;
+                }
+                state = self.next_state(s2, input[pos + 3]);
+                pos += 4;
+                if state >= special_min {
+                    break;
                 }
-                break;
+            }
+
+            if state < special_min {
+                if pos >= input.len() {
+                    break;
+                }
+                state = self.next_state(state, input[pos]);
+                pos += 1;
             }
         }
 
         // If we made it to the end of the input, prefer a return value that is specific to EOI
         // over one that can occur anywhere.
-        if (state as usize) < self.accept.len() {
-            if let Some(accept_ret) = self.accept_at_eoi[state as usize] {
-                return Ok((input.len(), accept_ret))
-            }
+        if let Some(accept_ret) = self.accept_at_eoi[self.state_idx(state)] {
+            Ok((input.len(), accept_ret))
+        } else {
+            ret
         }
-        ret
     }
 
-    pub fn longest_backward_find_from(&self, input: &[u8], pos: usize, mut state: usize)
-    -> Option<(usize, Ret)> {
+    fn longest_backward_find_from<Keep>(&self,
+                                        input: &[u8],
+                                        min_pos: usize,
+                                        pos: usize,
+                                        state: usize,
+                                        keep: Keep)
+    -> Option<(usize, Ret)>
+    where Keep: Fn(usize, Ret) -> bool {
+        let mut state = self.state_map[state];
         let mut ret = None;
-        for pos in (0..pos).rev() {
-            if let Some(next_ret) = self.accept[state] {
-                ret = Some((pos + 1, next_ret));
+        for pos in (min_pos..pos).rev() {
+            if let Some(next_ret) = self.accept[self.state_idx(state)] {
+                if keep(pos + 1, next_ret) {
+                    ret = Some((pos + 1, next_ret));
+                }
             }
-            if let Some(next_state) = self.next_state(state, input[pos]) {
-                state = next_state;
-            } else {
+            state = self.next_state(state, input[pos]);
+            if state == DEAD_STATE {
                 return ret;
             }
         }
 
-        if let Some(end_ret) = self.accept_at_eoi[state] {
-            Some((0, end_ret))
+        let end_ret = if min_pos == 0 {
+            self.accept_at_eoi[self.state_idx(state)]
         } else {
-            ret
+            self.accept[self.state_idx(state)]
+        };
+        match end_ret {
+            Some(end_ret) if keep(min_pos, end_ret) => Some((min_pos, end_ret)),
+            _ => ret,
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.num_states() == 0
+    fn memory_usage(&self) -> usize {
+        (self.table.len() + self.state_map.len()) * size_of::<TableStateIdx>()
+            + self.byte_class.len()
+            + (self.accept.len() + self.accept_at_eoi.len()) * size_of::<Option<Ret>>()
+            + self.accel.len() * size_of::<Accel>()
     }
 }
-
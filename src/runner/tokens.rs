@@ -0,0 +1,349 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use dfa::TokTrait;
+use runner::program::{DEAD_STATE, TableStateIdx};
+use std::fmt::Debug;
+
+/// A DFA program that runs on tokens of any integer type, implemented as a lookup table.
+///
+/// This is made by `Dfa::compile_tokens`. It is simpler (and slower) than `TableInsts`, because
+/// we can't assume that the input is UTF-8, and there might be too many different tokens to
+/// have a table entry for each one.
+///
+/// Instead, the tokens are split into intervals, where every token in an interval behaves in the
+/// same way. The intervals are then grouped into equivalence classes, and the table has one
+/// column for each class.
+#[derive(Clone, Debug)]
+pub struct TokenInsts<Tok, Ret> {
+    /// The first token of every interval, in increasing order. The first interval always starts
+    /// at the smallest possible token.
+    pub interval_starts: Vec<Tok>,
+    /// The equivalence class of every interval.
+    pub interval_class: Vec<u32>,
+    /// The number of equivalence classes.
+    pub num_classes: usize,
+    /// A `num_classes x num_states`-long table.
+    ///
+    /// For an input token in class `c` in state `st`, the next state is
+    /// `table[st * num_classes + c]`.
+    pub table: Vec<TableStateIdx>,
+    /// If `accept[st]` is not `None` then `st` is accepting, and `accept[st]` is the data
+    /// to return.
+    pub accept: Vec<Option<Ret>>,
+    /// Same as `accept`, but applies only at the end of the input.
+    pub accept_at_eoi: Vec<Option<Ret>>,
+    /// The state to start in at the beginning of the input.
+    pub init_at_start: Option<usize>,
+    /// The state to start in anywhere else.
+    pub init_otherwise: Option<usize>,
+}
+
+impl<Tok: TokTrait, Ret: Copy + Debug> TokenInsts<Tok, Ret> {
+    /// Returns the number of states.
+    pub fn num_states(&self) -> usize {
+        self.accept.len()
+    }
+
+    fn next_state(&self, state: usize, tok: Tok) -> TableStateIdx {
+        let interval = match self.interval_starts.binary_search(&tok) {
+            Ok(i) => i,
+            // This doesn't underflow, because the first interval starts at the smallest token.
+            Err(i) => i - 1,
+        };
+        self.table[state * self.num_classes + self.interval_class[interval] as usize]
+    }
+
+    /// Runs the program forward from `state`, starting at position `pos` of the input.
+    ///
+    /// Returns the position and return value of the last accepting state that we saw before
+    /// either failing or getting to the end of the input. If we never accept, returns the
+    /// position at which we failed.
+    pub fn find_from(&self, input: &[Tok], pos: usize, state: usize)
+    -> Result<(usize, Ret), usize> {
+        let mut state = state;
+        let mut ret = Err(input.len());
+        for pos in pos..input.len() {
+            if let Some(accept_ret) = self.accept[state] {
+                ret = Ok((pos, accept_ret));
+            }
+            let next = self.next_state(state, input[pos]);
+            if next == DEAD_STATE {
+                return ret.or(Err(pos));
+            }
+            state = next as usize;
+        }
+
+        // If we made it to the end of the input, prefer a return value that is specific to EOI
+        // over one that can occur anywhere.
+        if let Some(accept_ret) = self.accept_at_eoi[state] {
+            Ok((input.len(), accept_ret))
+        } else {
+            ret
+        }
+    }
+
+    /// Runs the program backward from `state`, starting just before position `pos` of the input
+    /// and going no further back than position `min_pos`.
+    ///
+    /// Returns the position and return value of the longest match that `keep` allows, if there is
+    /// one. Position `min_pos` only counts as the beginning of the input (for states that only
+    /// accept there) if it is zero.
+    pub fn longest_backward_find_from<Keep>(&self,
+                                            input: &[Tok],
+                                            min_pos: usize,
+                                            pos: usize,
+                                            state: usize,
+                                            keep: Keep)
+    -> Option<(usize, Ret)>
+    where Keep: Fn(usize, Ret) -> bool {
+        let mut state = state;
+        let mut ret = None;
+        for pos in (min_pos..pos).rev() {
+            if let Some(next_ret) = self.accept[state] {
+                if keep(pos + 1, next_ret) {
+                    ret = Some((pos + 1, next_ret));
+                }
+            }
+            let next = self.next_state(state, input[pos]);
+            if next == DEAD_STATE {
+                return ret;
+            }
+            state = next as usize;
+        }
+
+        let end_ret = if min_pos == 0 { self.accept_at_eoi[state] } else { self.accept[state] };
+        match end_ret {
+            Some(end_ret) if keep(min_pos, end_ret) => Some((min_pos, end_ret)),
+            _ => ret,
+        }
+    }
+}
+
+/// A DFA program that finds matches in a slice of tokens of any integer type.
+///
+/// This is made by `Dfa::compile_token_searcher`. Since a `Dfa` can't say which of its matches
+/// the caller wants, we prefer matches that start earlier, and then ones that end later. So unlike
+/// the forward-backward engine for `Regex`, this first runs backward over the input to find where
+/// the first match starts, and then runs forward from there to find where it ends.
+#[derive(Clone, Debug)]
+pub struct TokenSearcher<Tok, Ret> {
+    forward: TokenInsts<Tok, Ret>,
+    // Runs backward from the end of the input, and accepts wherever a match could start from
+    // `forward.init_otherwise`. This is `None` if there is no such initial state.
+    backward: Option<TokenInsts<Tok, ()>>,
+}
+
+impl<Tok: TokTrait, Ret: Copy + Debug> TokenSearcher<Tok, Ret> {
+    pub fn new(forward: TokenInsts<Tok, Ret>, backward: Option<TokenInsts<Tok, ()>>)
+    -> TokenSearcher<Tok, Ret> {
+        TokenSearcher {
+            forward: forward,
+            backward: backward,
+        }
+    }
+
+    /// Returns the first match in `input`: the start and end positions of the match, and the
+    /// value that the `Dfa` returned.
+    ///
+    /// We prefer matches that start earlier, and then ones that end later. This takes time linear
+    /// in the length of `input`.
+    pub fn find(&self, input: &[Tok]) -> Option<(usize, usize, Ret)> {
+        let fwd = &self.forward;
+        if let Some(init) = fwd.init_at_start.or(fwd.init_otherwise) {
+            if let Ok((end, ret)) = fwd.find_from(input, 0, init) {
+                return Some((0, end, ret));
+            }
+        }
+        if input.is_empty() {
+            return None;
+        }
+
+        // Any other match starts from `init_otherwise`, at the earliest position where the
+        // backward program accepts.
+        let (init, backward) = match (fwd.init_otherwise, self.backward.as_ref()) {
+            (Some(init), Some(backward)) => (init, backward),
+            _ => return None,
+        };
+        let b_init = match backward.init_at_start {
+            Some(b_init) => b_init,
+            None => return None,
+        };
+        let len = input.len();
+        backward.longest_backward_find_from(input, 1, len, b_init, |_, _| true)
+            .and_then(|(start, _)| {
+                fwd.find_from(input, start, init).ok().map(|(end, ret)| (start, end, ret))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dfa::Dfa;
+    use look::Look;
+    use nfa::{Accept, NfaBuilder, NoLooks};
+    use range_map::Range;
+    use runner::tokens::{TokenInsts, TokenSearcher};
+    use std::usize;
+
+    fn searcher(b: NfaBuilder<u16, NoLooks>) -> TokenSearcher<u16, (Look, u8)> {
+        dfa(b).compile_token_searcher(usize::MAX).unwrap()
+    }
+
+    fn dfa(b: NfaBuilder<u16, NoLooks>) -> Dfa<(Look, u8), u16> {
+        b.build().unwrap().determinize(usize::MAX).unwrap().optimize()
+    }
+
+    // Matches a word of 1 (as token 1) followed by some 2s and then a 3, so that "1 2* 3" is the
+    // "regex". The tokens are u16 code units.
+    fn one_twos_three(anchored: bool) -> NfaBuilder<u16, NoLooks> {
+        let mut b = NfaBuilder::<u16, NoLooks>::new();
+        let s0 = b.add_state(Accept::Never);
+        let s1 = b.add_state(Accept::Never);
+        let s2 = b.add_state(Accept::Always);
+        b.add_init(Look::Boundary, s0);
+        if !anchored {
+            b.add_init(Look::Full, s0);
+        }
+        b.add_transition(s0, s1, Range::single(1));
+        b.add_transition(s1, s1, Range::single(2));
+        b.add_transition(s1, s2, Range::single(3));
+        b
+    }
+
+    // Matches "4" at the beginning of the input, or "1 2* 3" anywhere.
+    fn four_at_start() -> NfaBuilder<u16, NoLooks> {
+        let mut b = one_twos_three(false);
+        let s3 = b.add_state(Accept::Never);
+        let s4 = b.add_state(Accept::Always);
+        b.add_init(Look::Boundary, s3);
+        b.add_transition(s3, s4, Range::single(4));
+        b
+    }
+
+    // Matches "(1 2)*" anywhere, or "(1 2)* 3" at the end of the input.
+    fn pairs_then_three() -> NfaBuilder<u16, NoLooks> {
+        let mut b = NfaBuilder::<u16, NoLooks>::new();
+        let s0 = b.add_state(Accept::Always);
+        let s1 = b.add_state(Accept::Never);
+        let s2 = b.add_state(Accept::AtEoi);
+        b.add_init(Look::Full, s0);
+        b.add_transition(s0, s1, Range::single(1));
+        b.add_transition(s1, s0, Range::single(2));
+        b.add_transition(s0, s2, Range::single(3));
+        b
+    }
+
+    // Finds the first match by trying every starting position in turn.
+    fn naive_find(prog: &TokenInsts<u16, (Look, u8)>, input: &[u16]) -> Option<(usize, usize)> {
+        if let Some(init) = prog.init_at_start.or(prog.init_otherwise) {
+            if let Ok((end, _)) = prog.find_from(input, 0, init) {
+                return Some((0, end));
+            }
+        }
+        if let Some(init) = prog.init_otherwise {
+            for start in 1..(input.len() + 1) {
+                if let Ok((end, _)) = prog.find_from(input, start, init) {
+                    return Some((start, end));
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn find() {
+        let prog = searcher(one_twos_three(false));
+        assert_eq!(prog.find(&[1, 2, 2, 3]).map(|x| (x.0, x.1)), Some((0, 4)));
+        assert_eq!(prog.find(&[7, 1, 3, 1]).map(|x| (x.0, x.1)), Some((1, 3)));
+        assert_eq!(prog.find(&[1, 2, 4, 3]), None);
+        assert_eq!(prog.find(&[]), None);
+    }
+
+    #[test]
+    fn find_anchored() {
+        let prog = searcher(one_twos_three(true));
+        assert_eq!(prog.find(&[1, 3]).map(|x| (x.0, x.1)), Some((0, 2)));
+        assert_eq!(prog.find(&[0, 1, 3]), None);
+    }
+
+    #[test]
+    fn find_at_start() {
+        let prog = searcher(four_at_start());
+        assert_eq!(prog.find(&[4, 1, 3]).map(|x| (x.0, x.1)), Some((0, 1)));
+        assert_eq!(prog.find(&[1, 4, 1, 3]).map(|x| (x.0, x.1)), Some((2, 4)));
+        assert_eq!(prog.find(&[1, 4]), None);
+    }
+
+    #[test]
+    fn find_like_naive() {
+        let builders = vec![one_twos_three(false), one_twos_three(true), four_at_start(),
+                            pairs_then_three()];
+        for b in builders {
+            let dfa = dfa(b);
+            let prog = dfa.compile_tokens();
+            let searcher = dfa.compile_token_searcher(usize::MAX).unwrap();
+
+            // Try every input of length at most 6 with tokens from 1 to 4.
+            for len in 0..7 {
+                for n in 0..4usize.pow(len) {
+                    let input: Vec<u16> = (0..len).map(|i| (n / 4usize.pow(i) % 4 + 1) as u16)
+                        .collect();
+                    let found = searcher.find(&input).map(|x| (x.0, x.1));
+                    assert_eq!(found, naive_find(&prog, &input), "input {:?}", input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_input() {
+        // Matches "2* 3". Trying every starting position would take quadratic time here, since
+        // the search from each one gets to the end of the input.
+        let mut b = NfaBuilder::<u16, NoLooks>::new();
+        let s0 = b.add_state(Accept::Never);
+        let s1 = b.add_state(Accept::Always);
+        b.add_init(Look::Full, s0);
+        b.add_transition(s0, s0, Range::single(2));
+        b.add_transition(s0, s1, Range::single(3));
+        let prog = searcher(b);
+
+        let mut input = vec![1];
+        input.extend(vec![2; 1000000]);
+        assert_eq!(prog.find(&input), None);
+        input.push(3);
+        assert_eq!(prog.find(&input).map(|x| (x.0, x.1)), Some((1, 1000002)));
+    }
+
+    #[test]
+    fn classes() {
+        // Tokens 0 and 4..=u16::MAX all behave the same (they lead to the dead state), so they
+        // should share a class.
+        let prog = dfa(one_twos_three(false)).compile_tokens();
+        assert_eq!(prog.num_classes, 4);
+        assert_eq!(prog.interval_starts, vec![0, 1, 2, 3, 4]);
+        assert_eq!(prog.interval_class[0], prog.interval_class[4]);
+    }
+
+    #[test]
+    fn wide_tokens() {
+        let mut b = NfaBuilder::<u32, NoLooks>::new();
+        let s0 = b.add_state(Accept::Never);
+        let s1 = b.add_state(Accept::AtEoi);
+        b.add_init(Look::Boundary, s0);
+        b.add_transition(s0, s1, Range::new(100000, 200000));
+        b.add_transition(s1, s1, Range::new(100000, 200000));
+        let dfa = b.build().unwrap().determinize(usize::MAX).unwrap().optimize();
+        let prog = dfa.compile_token_searcher(usize::MAX).unwrap();
+        assert_eq!(prog.find(&[100000, 150000]).map(|x| (x.0, x.1)), Some((0, 2)));
+        assert_eq!(prog.find(&[100000, 250000]), None);
+
+        let insts = dfa.compile_tokens();
+        assert!(insts.table.len() <= insts.num_states() * 2);
+    }
+}
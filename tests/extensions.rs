@@ -0,0 +1,465 @@
+// Copyright 2015-2016 Joe Neeman.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Tests for the parts of the crate that the generated examples in `matches.rs` don't cover.
+
+extern crate range_map;
+extern crate regex_dfa;
+
+use range_map::Range;
+use regex_dfa::{Error, Look, Regex, Regex16};
+use regex_dfa::nfa::{Accept, LineTerminator, NfaBuilder, NoLooks, RegexOptions};
+
+macro_rules! mat(
+    ($name:ident, $re:expr, $text:expr, $($loc:tt)+) => (
+        #[test]
+        fn $name() {
+            let text = $text;
+            let expected: Vec<Option<_>> = vec!($($loc)+);
+            let r = Regex::new($re).unwrap();
+            let pos = r.find(text);
+
+            // We don't support capture groups, so just check the whole match.
+            if expected[0] != pos {
+                panic!("For RE '{}' against '{:?}', expected '{:?}' but got '{:?}'",
+                       $re, text, expected, pos);
+            }
+        }
+    );
+);
+
+macro_rules! no_mat(
+    ($name:ident, $re:expr, $text:expr) => (
+        #[test]
+        fn $name() {
+            let text = $text;
+            let r = Regex::new($re).unwrap();
+            println!("{:?}", r);
+            let result = r.find(text);
+            if result.is_some() {
+                panic!("For RE '{}' against '{:?}', expected None but got '{:?}'",
+                       $re, text, result);
+            }
+        }
+    );
+);
+
+// Test patterns that can skip ahead using memchr.
+mat!(match_accel_1, r#""[^"]*""#, r#"say "héllo" twice"#, Some((4, 12)));
+mat!(match_accel_2, r#""[^"\\]*""#, r#"a "b\"c" "d""#, Some((5, 8)));
+mat!(match_accel_3, r"x[^y]*y", "xaaaaaaaaaa☃aaaaay", Some((0, 20)));
+mat!(match_accel_4, r"x[^yz]*$", "xaaa\naaa☃", Some((0, 11)));
+no_mat!(no_match_accel_1, r#""[^"]*""#, r#"say "hello twice"#);
+no_mat!(no_match_accel_2, r"x[^yzw]*y", "xaaaaaaaaaaaaaaaaaaaaw");
+
+#[test]
+fn latin1_accel() {
+    // In Latin-1, `\xC0` is a char of its own, and not the start of a multi-byte one.
+    let re = Regex::new_latin1("x[^yÀ]*y").unwrap();
+    assert_eq!(re.find_bytes(b"x\xC0y"), None);
+    assert_eq!(re.find_bytes(b"x\xC1\x80y"), Some((0, 4)));
+    assert_eq!(re.find_bytes(b"x\xC0xaaaaaaaaaaaaay"), Some((2, 17)));
+}
+
+// Test patterns with large unicode classes, which may use the compact program representation.
+mat!(match_compact_1, r"\w+\s+\w+", "  héllo wörld", Some((2, 15)));
+mat!(match_compact_2, r"\bπ\w*", "aπ πρ", Some((4, 8)));
+
+#[test]
+fn find_iter() {
+    let re = Regex::new("a+").unwrap();
+    assert_eq!(re.find_iter("baaab aab").collect::<Vec<_>>(), vec![(1, 4), (6, 8)]);
+
+    // Empty matches shouldn't get stuck, and should skip whole characters.
+    let re = Regex::new("x*").unwrap();
+    assert_eq!(re.find_iter("éxx").collect::<Vec<_>>(), vec![(0, 0), (2, 4), (4, 4)]);
+
+    // Look-behind sees the text before the place where the search resumes.
+    let re = Regex::new("^a").unwrap();
+    assert_eq!(re.find_iter("aaa").collect::<Vec<_>>(), vec![(0, 1)]);
+    let re = Regex::new(r"\bfoo").unwrap();
+    assert_eq!(re.find_iter("foofoo foo").collect::<Vec<_>>(), vec![(0, 3), (7, 10)]);
+    let re = Regex::new(r"(?m)^a").unwrap();
+    assert_eq!(re.find_iter("aa\na").collect::<Vec<_>>(), vec![(0, 1), (3, 4)]);
+    let re = Regex::new(r"\bé").unwrap();
+    assert_eq!(re.find_iter("éé é").collect::<Vec<_>>(), vec![(0, 2), (5, 7)]);
+}
+
+#[test]
+fn find_at() {
+    let re = Regex::new("a+").unwrap();
+    assert_eq!(re.find_at("aaa", 1), Some((1, 3)));
+    let re = Regex::new(r"\ba").unwrap();
+    assert_eq!(re.find_at("aa a", 1), Some((3, 4)));
+    assert_eq!(re.find_at("a a", 2), Some((2, 3)));
+    let re = Regex::new(r"a\b").unwrap();
+    assert_eq!(re.find_at("aa", 1), Some((1, 2)));
+}
+
+#[test]
+fn compact_program() {
+    // With Unicode word boundaries and case folding, lots of states share the same non-ASCII
+    // transitions, which the compact program stores only once.
+    let re = Regex::new(r"(?i)straße|\bfoo\b").unwrap();
+    assert!(format!("{:?}", re).contains("CompactInsts"));
+    assert_eq!(re.find("Die STRAẞE"), Some((4, 12)));
+    assert_eq!(re.find("xfoo foo"), Some((5, 8)));
+
+    // A small table is better.
+    assert!(!format!("{:?}", Regex::new("a.*b").unwrap()).contains("CompactInsts"));
+}
+
+#[test]
+fn boolean_ops() {
+    let word = Regex::new(r"[a-z]+").unwrap();
+    let kw = Regex::new(r"if|else|while").unwrap();
+
+    let ident = word.and_not(&kw).unwrap();
+    assert_eq!(ident.find("iff"), Some((0, 3)));
+    assert_eq!(ident.find("while"), Some((0, 4)));
+    assert_eq!(ident.find(" if"), Some((1, 2)));
+
+    let both = word.and(&Regex::new(r"\w*q\w*").unwrap()).unwrap();
+    assert_eq!(both.find("abc Quq"), Some((5, 7)));
+    assert_eq!(both.find("abc"), None);
+
+    let either = kw.or(&Regex::new(r"[0-9]+").unwrap()).unwrap();
+    assert_eq!(either.find("x = 12 if"), Some((4, 6)));
+    assert_eq!(either.find("elsewhere"), Some((0, 4)));
+
+    // The languages don't say what look-around needs to see, so it can't be combined.
+    assert!(word.and(&Regex::new(r"foo\b").unwrap()).is_err());
+    assert!(Regex::new(r"^foo").unwrap().or(&word).is_err());
+    assert!(word.and_not(&Regex::new(r"(?m)foo$").unwrap()).is_err());
+    assert!(Regex::new(r"\bfoo").unwrap().complement().is_err());
+}
+
+#[test]
+fn complement() {
+    let lower = Regex::new(r"[a-z]*").unwrap();
+    let not_lower = lower.complement().unwrap();
+    assert_eq!(not_lower.find("ab1c"), Some((0, 4)));
+    assert_eq!(not_lower.find("abc"), None);
+    assert_eq!(not_lower.find("é"), Some((0, 2)));
+    assert_eq!(not_lower.complement().unwrap().matches_same_language_as(&lower), Ok(()));
+
+    // In Latin-1, the complement has all the other bytes.
+    let not_lower = Regex::new_latin1(r"[a-z]*").unwrap().complement().unwrap();
+    assert_eq!(not_lower.find_bytes(b"ab\xFF"), Some((0, 3)));
+    assert_eq!(not_lower.find_bytes(b"ab"), None);
+}
+
+#[test]
+fn same_language() {
+    let re = |s| Regex::new(s).unwrap();
+    let num = re(r"[0-9]+(\.[0-9]+)?");
+    assert_eq!(num.matches_same_language_as(&re(r"[0-9]+|[0-9]+\.[0-9]+")), Ok(()));
+    assert_eq!(re("a|ab").matches_same_language_as(&re("ab?")), Ok(()));
+    assert_eq!(re("ab*").matches_same_language_as(&re("a")), Err("ab".to_owned()));
+
+    // Combined regexes have languages too.
+    let lower = re("[a-z]+");
+    let combined = lower.and_not(&re("x")).unwrap().or(&re("x")).unwrap();
+    assert_eq!(combined.matches_same_language_as(&lower), Ok(()));
+}
+
+#[test]
+fn shortest_match_example() {
+    let example = |s| Regex::new(s).unwrap().shortest_match_example();
+    assert_eq!(example(r"\d{4}-\d{2}"), Some("0000-00".to_owned()));
+    assert_eq!(example(r"[^a-z]+ing"), Some(" ing".to_owned()));
+    assert_eq!(example(r"\btest\B\b"), None);
+}
+
+#[test]
+fn languages_with_invalid_utf8() {
+    // An `Nfa` that matches "ab" and the (invalid UTF-8) byte 0xFF.
+    let bytes_re = |with_ab: bool| {
+        let mut b: NfaBuilder<u8, NoLooks> = NfaBuilder::new();
+        let init = b.add_state(Accept::Never);
+        let a = b.add_state(Accept::Never);
+        let end = b.add_state(Accept::Always);
+        b.add_init(Look::Full, init);
+        b.add_transition(init, end, Range::single(0xFF));
+        if with_ab {
+            b.add_transition(init, a, Range::single(b'a'));
+            b.add_transition(a, end, Range::single(b'b'));
+        }
+        Regex::from_nfa(b.build().unwrap()).unwrap()
+    };
+
+    assert_eq!(bytes_re(true).shortest_match_example(), Some("ab".to_owned()));
+    assert_eq!(bytes_re(false).shortest_match_example(), None);
+    let ab = Regex::new("^ab$").unwrap();
+    assert_eq!(bytes_re(true).matches_same_language_as(&ab), Ok(()));
+    assert_eq!(bytes_re(false).matches_same_language_as(&ab), Err("ab".to_owned()));
+}
+
+#[test]
+fn fuzzy() {
+    let re = Regex::fuzzy("invoice", 1).unwrap();
+    assert_eq!(re.find_with_edits("invoice"), Some((0, 7, 0)));
+    assert_eq!(re.find_with_edits("invoise"), Some((0, 7, 1)));
+    assert_eq!(re.find_with_edits("invice"), Some((0, 6, 1)));
+    assert_eq!(re.find_with_edits("imvoise"), None);
+    assert_eq!(re.find_with_edits("invoic"), Some((0, 6, 1)));
+    assert_eq!(re.find_with_edits("an invoise"), Some((3, 10, 1)));
+
+    // The edits are counted over the whole match, even if it ends with an exact match.
+    let re = Regex::fuzzy("abc", 1).unwrap();
+    assert_eq!(re.find_with_edits("xabc"), Some((0, 4, 1)));
+
+    let re = Regex::fuzzy("^invoice", 1).unwrap();
+    assert_eq!(re.find_with_edits("invoice"), Some((0, 7, 0)));
+    assert_eq!(re.find_with_edits("invoise x"), Some((0, 7, 1)));
+    assert_eq!(re.find_with_edits("xinvoice"), Some((0, 8, 1)));
+
+    let re = Regex::fuzzy(r"\binvoice\b", 2).unwrap();
+    assert_eq!(re.find_with_edits("the imvoise"), Some((4, 11, 2)));
+    assert_eq!(re.find_with_edits("theinvoice"), None);
+
+    assert_eq!(Regex::new("abc").unwrap().find_with_edits("abc"), Some((0, 3, 0)));
+}
+
+#[test]
+fn regex16() {
+    // Regex16 should find the same matches as Regex, but with offsets in UTF-16 code units.
+    let cases = [("a+", "bcaaad"), (r"\w+", "  grüße 😀"), (r"😀\b", "x😀 "), ("(?i)É", "café"),
+                 (r"(?m)^\d+$", "ab\n123\n")];
+    for &(re, text) in &cases {
+        let to_units = |i: usize| text[..i].encode_utf16().count();
+        let expected = Regex::new(re).unwrap().find(text)
+            .map(|(s, e)| (to_units(s), to_units(e)));
+        let text16: Vec<u16> = text.encode_utf16().collect();
+        assert_eq!(Regex16::new(re).unwrap().find(&text16), expected);
+    }
+}
+
+#[test]
+fn latin1() {
+    let re = Regex::new_latin1(r"café\b").unwrap();
+    assert_eq!(re.find_bytes(b"un caf\xe9 noir"), Some((3, 7)));
+    assert_eq!(re.find_bytes(b"caf\xe9s"), None);
+
+    let re = Regex::new_latin1(r"\bfoo").unwrap();
+    assert_eq!(re.find_bytes(b" foo"), Some((1, 4)));
+    assert_eq!(re.find_bytes(b"\xe9foo"), None);
+
+    let re = Regex::new_latin1(r"\w+").unwrap();
+    assert_eq!(re.find_bytes(b" na\xefve!"), Some((1, 6)));
+    assert_eq!(Regex::new_latin1(r"é+").unwrap().shortest_match_example(), Some("é".to_owned()));
+
+    assert!(Regex::new_latin1("€").is_err());
+    assert!(Regex::new_latin1("[a€]").is_err());
+    assert!(Regex::new_latin1(r"[a-\x{100}]").is_err());
+    assert!(Regex::new_latin1(r"[\w&&[a€]]").is_err());
+    assert!(Regex::new_latin1(r"\x{FF}").is_ok());
+    assert!(Regex::new_latin1(r"[^€]").is_ok());
+    assert!(Regex::new_latin1(r"[\w--€]").is_ok());
+    assert!(Regex::new_latin1(r".\pL").is_ok());
+
+    let re = Regex::new_latin1(r"\w*").unwrap();
+    let text = b"\xe9t\xe9 \xa0\xff";
+    let matches: Vec<_> = re.find_iter_bytes(text).collect();
+    assert_eq!(matches, vec![(0, 3), (3, 3), (4, 4), (5, 6), (6, 6)]);
+    assert_eq!(re.find_bytes_at(text, 1), Some((1, 3)));
+    assert!(Regex::new_latin1(r"\xa0").unwrap().is_match_bytes(text));
+    assert!(!Regex::new_latin1(r"\xa0\xe9").unwrap().is_match_bytes(text));
+}
+
+#[test]
+#[should_panic]
+fn latin1_str() {
+    Regex::new_latin1("é").unwrap().find("é");
+}
+
+#[test]
+fn find_iter_bytes() {
+    let re = Regex::new("").unwrap();
+    let text = "aé€".as_bytes();
+    assert_eq!(re.find_iter_bytes(text).collect::<Vec<_>>(), vec![(0, 0), (1, 1), (3, 3), (6, 6)]);
+    assert!(re.is_match_bytes(b""));
+}
+
+#[test]
+fn ascii_word_boundary() {
+    let opts = RegexOptions { ascii_word_boundary: true, ..RegexOptions::default() };
+    let re = Regex::new_with_options(r"\bfoo\b", &opts).unwrap();
+    assert_eq!(re.find("a foo b"), Some((2, 5)));
+    assert_eq!(re.find("afoo"), None);
+    // Non-ASCII letters aren't word chars, so they count as boundaries.
+    assert_eq!(re.find("éfooé"), Some((2, 5)));
+    assert_eq!(Regex::new(r"\bfoo\b").unwrap().find("éfooé"), None);
+
+    let re = Regex::new_with_options(r"\Bfoo", &opts).unwrap();
+    assert_eq!(re.find("éfoo xfoo"), Some((7, 10)));
+}
+
+#[test]
+fn modern_syntax() {
+    let find = |re: &str, text: &str| Regex::new(re).unwrap().find(text);
+    assert_eq!(find(r"\p{Greek}+", "abc αβγ"), Some((4, 10)));
+    assert_eq!(find(r"\p{Script=Greek}", "abc αβγ"), Some((4, 6)));
+    assert_eq!(find(r"[\w&&[^\d]]+", "12ab3"), Some((2, 4)));
+    assert_eq!(find(r"[a-z--[aeiou]]+", "aexyz"), Some((2, 5)));
+    assert_eq!(find("(?x) a b  # a comment", "xab"), Some((1, 3)));
+    assert_eq!(find(r"(?-u:\b)foo", "éfoo"), Some((2, 5)));
+    assert_eq!(find(r"\bfoo", "éfoo"), None);
+    assert_eq!(find(r"(?m)\Aa", "b\na"), None);
+    assert_eq!(find(r"(?m)^a", "b\na"), Some((2, 3)));
+    assert_eq!(find(r"(?m)a\z", "a\nb"), None);
+
+    // Outside of a class, `[:name:]` is still an ASCII class, as it was in the old syntax.
+    assert_eq!(find(r"[:alpha:]+", ":ab1"), Some((1, 3)));
+    assert_eq!(find(r"[:^digit:]", "1a"), Some((1, 2)));
+    assert_eq!(find(r"(?:x|[:space:])+", "a x\t"), Some((1, 4)));
+    assert_eq!(find(r"[:foo:]+", "a:of"), Some((1, 4)));
+    assert_eq!(find(r"[[:word:]]+", "a:_"), Some((0, 1)));
+}
+
+#[test]
+fn syntax_error_span() {
+    match Regex::new("ab)") {
+        Err(Error::RegexSyntax { span, .. }) => assert_eq!(span, 2..3),
+        x => panic!("expected a syntax error, got {:?}", x),
+    }
+}
+
+#[test]
+fn case_folding() {
+    let find = |re: &str, text: &str| Regex::new(re).unwrap().find(text);
+    assert_eq!(find("(?i)k", "\u{212A}"), Some((0, 3)));
+    assert_eq!(find("(?i)s+", "Sſs"), Some((0, 4)));
+    assert_eq!(find("(?i)σ+", "ΣσςX"), Some((0, 6)));
+    assert_eq!(find("(?i)[r-t]", "\u{17F}"), Some((0, 2)));
+    assert_eq!(find("(?i)ß", "\u{1E9E}"), Some((0, 3)));
+    assert_eq!(find("(?i)i", "\u{130}\u{131}"), None);
+}
+
+#[test]
+fn line_terminators() {
+    let crlf = RegexOptions { line_terminator: LineTerminator::Crlf, ..RegexOptions::default() };
+    let find = |re: &str, text: &str| Regex::new_with_options(re, &crlf).unwrap().find(text);
+    assert_eq!(find(r"(?m)^b$", "a\r\nb\r\nc"), Some((3, 4)));
+    assert_eq!(find(r"(?m)a$", "a\r\n"), Some((0, 1)));
+    assert_eq!(find(r"(?m)^c", "a\r\nc"), Some((3, 4)));
+    assert_eq!(find(r".+", "ab\r\n"), Some((0, 2)));
+    assert_eq!(Regex::new(r"(?m)a$").unwrap().find("a\r\n"), None);
+
+    // A "\r\n" is a single line terminator, so no line starts or ends inside it.
+    let find_all = |re: &str, text: &str| -> Vec<(usize, usize)> {
+        Regex::new_with_options(re, &crlf).unwrap().find_iter(text).collect()
+    };
+    assert_eq!(find(r"(?m)^$", "a\r\nb"), None);
+    assert_eq!(find_all(r"(?m)^$", "a\r\nb"), vec![]);
+    assert_eq!(find_all(r"(?m)^", "a\r\nb"), vec![(0, 0), (3, 3)]);
+    assert_eq!(find_all(r"(?m)$", "a\r\nb"), vec![(1, 1), (4, 4)]);
+    assert_eq!(find_all(r"(?m)^$", "a\r\rb\n\r\n"), vec![(2, 2), (5, 5), (7, 7)]);
+    assert_eq!(find_all(r"(?m)^\n", "\r\n\n"), vec![(2, 3)]);
+
+    let nul = RegexOptions { line_terminator: LineTerminator::Byte(0), ..RegexOptions::default() };
+    let find = |re: &str, text: &str| Regex::new_with_options(re, &nul).unwrap().find(text);
+    assert_eq!(find(r"(?m)^b.*$", "a\0bc\0d"), Some((2, 4)));
+    assert_eq!(find(r"(?m)^c", "a\nc"), None);
+    assert_eq!(find(r".+", "a\nb\0"), Some((0, 3)));
+
+    assert!(Regex::new(r"(?mR)^a").is_err());
+    let word = RegexOptions {
+        line_terminator: LineTerminator::Byte(b'a'),
+        ..RegexOptions::default()
+    };
+    assert!(Regex::new_with_options("b", &word).is_err());
+}
+
+#[test]
+fn text_anchors() {
+    let find = |re: &str, text: &str| Regex::new(re).unwrap().find(text);
+    // `\A` and `\z` only match at the edges of the text, even in multi-line mode.
+    assert_eq!(find(r"\Aa", "a\na"), Some((0, 1)));
+    assert_eq!(find(r"(?m)\Aa", "b\na"), None);
+    assert_eq!(find(r"(?m)a\z", "a\nb"), None);
+    assert_eq!(find(r"(?m)a\z", "a\na"), Some((2, 3)));
+    assert_eq!(find(r"a\z", "a\n"), None);
+    assert_eq!(find(r"a$", "a\n"), None);
+    assert_eq!(find(r"(?m)a$", "a\n"), Some((0, 1)));
+
+    // `\Z` also matches just before a line terminator that ends the text.
+    assert_eq!(find(r"a\Z", "a"), Some((0, 1)));
+    assert_eq!(find(r"a\Z", "a\n"), Some((0, 1)));
+    assert_eq!(find(r"a\Z", "a\n\n"), None);
+    assert_eq!(find(r"a\Z", "a\nb"), None);
+    assert_eq!(find(r"(?m)a\Z", "a\nba\n"), Some((3, 4)));
+    assert_eq!(find(r"(?m)a\Z", "a\nb"), None);
+    assert_eq!(find(r"\Z", "ab\n"), Some((2, 2)));
+    assert_eq!(find(r"\Z", "ab"), Some((2, 2)));
+    assert_eq!(find(r"\Aab\Z", "ab\n"), Some((0, 2)));
+    assert_eq!(find(r"\Aab\Z", "ab\nc"), None);
+
+    // Escaped backslashes and char classes don't make `\Z`s.
+    assert_eq!(find(r"\\Z", "a\\Z"), Some((1, 3)));
+    assert_eq!(find(r"[\\]Z", "a\\Z"), Some((1, 3)));
+    match Regex::new(r"a\Z)") {
+        Err(Error::RegexSyntax { span, .. }) => assert_eq!(span, 3..4),
+        x => panic!("expected a syntax error, got {:?}", x),
+    }
+    match Regex::new(r"[\Z]") {
+        Err(Error::RegexSyntax { span, .. }) => assert_eq!(span, 1..3),
+        x => panic!("expected a syntax error, got {:?}", x),
+    }
+    assert_eq!(find(r"[]\\]\Z", "a\\"), Some((1, 2)));
+    assert_eq!(find(r"[^]]\Z", "ab\n"), Some((1, 2)));
+
+    // Groups around a `\Z`, or next to it, are just groups.
+    assert_eq!(find(r"(a)(\Z)", "ba\n"), Some((1, 2)));
+    assert_eq!(find(r"(?P<x>a|\Z)b", "ab"), Some((0, 2)));
+    assert_eq!(find(r"(?:b|\Z)", "a\n"), Some((1, 1)));
+    assert_eq!(find(r"a(b|\Z)", "ab"), Some((0, 2)));
+    assert_eq!(find(r"a(b|\Z)", "a\n"), Some((0, 1)));
+
+    // Nothing that consumes input can come after a `\Z`.
+    assert_eq!(find(r"a\Z\n", "a\n"), None);
+
+    // In CRLF mode, `\Z` can also match before a final "\r\n", but not inside it.
+    let crlf = RegexOptions { line_terminator: LineTerminator::Crlf, ..RegexOptions::default() };
+    let find = |re: &str, text: &str| Regex::new_with_options(re, &crlf).unwrap().find(text);
+    assert_eq!(find(r"a\Z", "a\r\n"), Some((0, 1)));
+    assert_eq!(find(r"a\Z", "a\n"), Some((0, 1)));
+    assert_eq!(find(r"a\Z", "a\r"), None);
+    assert_eq!(find(r"a\Z", "a\r\n\r\n"), None);
+    assert_eq!(find(r"a\r\Z", "a\r\n"), None);
+    assert_eq!(find(r"a\r\Z", "a\r"), Some((0, 2)));
+    assert_eq!(find(r"\Z", "ab\r\n"), Some((2, 2)));
+}
+
+#[test]
+fn half_word_boundaries() {
+    let find = |re: &str, text: &str| Regex::new(re).unwrap().find(text);
+    assert_eq!(find(r"\b{start}\w+", "  foo bar"), Some((2, 5)));
+    assert_eq!(find(r"\b{start}oo", "foo"), None);
+    assert_eq!(find(r"\w+\b{end}", "foo bar"), Some((0, 3)));
+    assert_eq!(find(r"fo\b{end}", "foo"), None);
+    assert_eq!(find(r"\<bar\>", "foobar bar"), Some((7, 10)));
+    // The edges of the text count as non-word chars.
+    assert_eq!(find(r"\b{start}foo\b{end}", "foo"), Some((0, 3)));
+    assert_eq!(find(r"\b{end}", ""), None);
+    assert_eq!(find(r"\b{start-half}", ""), Some((0, 0)));
+    assert_eq!(find(r"\b{end-half}", "é"), Some((2, 2)));
+    assert_eq!(find(r"\b{start-half}a", "ba a"), Some((3, 4)));
+    assert_eq!(find(r"a\b{end-half}", "ab a"), Some((3, 4)));
+    assert_eq!(find(r"(?-u:\b{start})b", "éb"), Some((2, 3)));
+    assert_eq!(find(r"\b{start}b", "éb"), None);
+}
+
+#[test]
+fn anchored_look_ahead() {
+    // Anchored regexes that look ahead to accept must end the match before the look-ahead.
+    let find = |re: &str, text: &str| Regex::new(re).unwrap().find(text);
+    assert_eq!(find(r"^a\b", "a b"), Some((0, 1)));
+    assert_eq!(find(r"^a\b", "ab"), None);
+    assert_eq!(find(r"\Aab\B", "abc"), Some((0, 2)));
+}